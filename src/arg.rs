@@ -1,18 +1,440 @@
-use crate::settings;
-use clap::{crate_authors, crate_version, Args as ClapArgs, Command, FromArgMatches, Parser};
+use aspotify::CountryCode;
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use clap::parser::ValueSource;
+use clap::{
+	crate_authors, crate_version, ArgAction, Args as ClapArgs, Command, FromArgMatches, Parser,
+	ValueEnum,
+};
+use down_on_spot::settings::{self, AuthMethod, ConfigFile};
+use down_on_spot::{
+	AlbumGroup, Bitrate, CoverSize, DateFormat, LinkMode, MetadataSource, OutputFormat, Quality,
+	SearchType,
+};
+use log::LevelFilter;
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 pub struct Args {
 	#[arg(
-		long_help = "Track / Album / Playlist / Artist / Podcast / Episode / Show / User URL, ID or search term\nFor example, \'Ariana Grande\', \'spotify:track:0KjAxsrYSvN0xGuh3cKPxD\', or \'https://open.spotify.com/playlist/37i9dQZF1DXcxvFzl58uP7\'"
+		long_help = "Track / Album / Playlist / Artist / Podcast / Episode / Show / User URL, ID or search term\nFor example, \'Ariana Grande\', \'spotify:track:0KjAxsrYSvN0xGuh3cKPxD\', or \'https://open.spotify.com/playlist/37i9dQZF1DXcxvFzl58uP7\'",
+		required_unless_present = "input_file"
 	)]
-	pub input: String,
+	pub input: Option<String>,
+	#[arg(
+		long,
+		conflicts_with_all = ["input", "stdout"],
+		help = "File with a URL, URI or search term per line, - for stdin, lines starting with # are skipped"
+	)]
+	pub input_file: Option<PathBuf>,
+	#[arg(
+		long,
+		requires = "input_file",
+		help = "Stop at the first line of the input file which can not be added, cancelling the downloads"
+	)]
+	pub fail_fast: bool,
+	#[arg(
+		long,
+		value_enum,
+		default_value_t = SearchType::Track,
+		help = "Kind of item to search for when the input is a search term"
+	)]
+	pub search_type: SearchType,
+	#[arg(
+		long,
+		alias = "quiet",
+		help = "Print a line per state change instead of redrawing the progress"
+	)]
+	pub no_progress: bool,
+	#[arg(
+		short,
+		long,
+		value_enum,
+		help = "Format of the downloaded files, overrides the settings"
+	)]
+	pub format: Option<OutputFormat>,
+	#[arg(
+		short,
+		long,
+		help = "Output folder, may contain template variables, overrides the settings"
+	)]
+	pub output: Option<String>,
+	#[arg(long, help = "Template of the file names, overrides the settings")]
+	pub filename_template: Option<String>,
+	#[arg(
+		long,
+		value_enum,
+		help = "Quality of the downloaded tracks, overrides the settings"
+	)]
+	pub quality: Option<Quality>,
+	#[arg(long, help = "Tracks downloaded at once, overrides the settings")]
+	pub concurrent_downloads: Option<usize>,
+	#[arg(
+		long,
+		conflicts_with = "format",
+		help = "Keep the files as served by Spotify without converting, same as --format original"
+	)]
+	pub no_transcode: bool,
+	#[arg(
+		long,
+		value_enum,
+		help = "Bitrate of converted MP3 and Opus files in kbit/s, overrides the settings"
+	)]
+	pub bitrate: Option<Bitrate>,
+	#[arg(
+		long,
+		value_parser = clap::value_parser!(u8).range(0..=9),
+		help = "Algorithm quality of converted MP3 files from 0, the best, to 9, the fastest, overrides the settings"
+	)]
+	pub mp3_quality: Option<u8>,
+	#[arg(
+		long,
+		conflicts_with = "bitrate",
+		value_parser = clap::value_parser!(u8).range(0..=9),
+		help = "Encode converted MP3 files with variable bitrate of this quality from 0, the best, to 9 instead of a fixed bitrate"
+	)]
+	pub vbr: Option<u8>,
+	#[arg(
+		long,
+		requires = "normalize",
+		help = "Dither the samples when applying the normalization gain, to reduce quantization noise"
+	)]
+	pub dither: bool,
+	#[arg(
+		long,
+		help = "Retries of downloads failing with a transient error, overrides the settings"
+	)]
+	pub retries: Option<usize>,
+	#[arg(
+		long,
+		value_enum,
+		help = "How to authenticate with Spotify, overrides the settings"
+	)]
+	pub auth_method: Option<AuthMethod>,
+	#[arg(
+		long,
+		help = "Write an M3U file of downloaded playlists into the output directory"
+	)]
+	pub write_m3u: bool,
+	#[arg(
+		long,
+		help = "List the tracks with their format and path without downloading anything"
+	)]
+	pub dry_run: bool,
+	#[arg(
+		long,
+		conflicts_with_all = ["dry_run", "stdout"],
+		help = "List the available formats of the tracks and what each quality would pick without downloading anything"
+	)]
+	pub simulate_quality: bool,
+	#[arg(
+		long,
+		help = "Write the audio of a single track to stdout, for example to pipe it into a player"
+	)]
+	pub stdout: bool,
+	#[arg(
+		long,
+		value_enum,
+		default_value_t = OutputMode::Text,
+		help = "Format of the progress output, json prints one event object per line"
+	)]
+	pub output_format: OutputMode,
+	#[arg(
+		long,
+		value_enum,
+		default_value_t = ColorMode::Auto,
+		help = "When to color the output and the log, auto colors terminals unless NO_COLOR is set"
+	)]
+	pub color: ColorMode,
+	#[arg(
+		long,
+		value_enum,
+		value_delimiter = ',',
+		help = "Kinds of releases to download for artists, overrides the settings"
+	)]
+	pub include: Option<Vec<AlbumGroup>>,
+	#[arg(
+		long,
+		default_value_t = 0,
+		help = "Tracks to skip at the start of albums, playlists and artists"
+	)]
+	pub offset: usize,
+	#[arg(
+		long,
+		conflicts_with = "offset",
+		value_parser = clap::value_parser!(u64).range(1..),
+		help = "Position to start at in albums, playlists and artists from 1, same as --offset one less"
+	)]
+	pub start_at: Option<u64>,
+	#[arg(
+		long,
+		help = "Maximum of tracks to download of albums, playlists and artists"
+	)]
+	pub limit: Option<usize>,
+	#[arg(
+		long,
+		value_name = "DATE",
+		value_parser = parse_added_after,
+		help = "Only download tracks added to playlists after this UTC date (YYYY-MM-DD) or RFC 3339 time, for syncing them"
+	)]
+	pub added_after: Option<DateTime<Utc>>,
+	#[arg(
+		long,
+		help = "Playlist index of the first track of playlists for %playlist_index%, overrides the settings"
+	)]
+	pub number_from: Option<usize>,
+	#[arg(
+		long,
+		help = "Digits %playlist_index% is padded to, overrides the digits of the playlist length"
+	)]
+	pub playlist_index_width: Option<usize>,
+	#[arg(
+		long,
+		help = "Number the downloaded tracks of playlists without gaps for skipped ones"
+	)]
+	pub renumber_playlist: bool,
+	#[arg(
+		long,
+		conflicts_with_all = ["number_from", "playlist_index_width", "renumber_playlist"],
+		help = "Leave %playlist_index% empty, overrides the settings"
+	)]
+	pub no_playlist_numbering: bool,
+	#[arg(
+		long,
+		conflicts_with = "no_playlist_numbering",
+		help = "Name files only by their position like 001, in the folder of path without subfolders, overrides the templates"
+	)]
+	pub flat_numbered: bool,
+	#[arg(long, help = "Write the lyrics of tracks into LRC files next to them")]
+	pub lyrics: bool,
+	#[arg(
+		long,
+		help = "Write the metadata of tracks into JSON files next to them"
+	)]
+	pub write_metadata_json: bool,
+	#[arg(
+		long,
+		value_name = "FILE",
+		help = "Write the result of every input and track into a JSON manifest when finished"
+	)]
+	pub json_manifest: Option<PathBuf>,
+	#[arg(
+		long,
+		help = "Download tracks in several of the inputs, playlists or albums only once"
+	)]
+	pub dedupe: bool,
+	#[arg(
+		long,
+		value_enum,
+		help = "Link duplicates to the file of their first download instead of leaving them out, implies --dedupe"
+	)]
+	pub dedupe_link: Option<LinkMode>,
+	#[arg(
+		long,
+		help = "Name files by all artists of tracks in their order for %artist%, not only the primary one"
+	)]
+	pub artists_in_order: bool,
+	#[arg(
+		long,
+		help = "Stop all downloads and exit with an error as soon as one track fails or is unavailable"
+	)]
+	pub strict: bool,
+	#[arg(
+		long,
+		alias = "no-skip-header",
+		help = "Keep the Spotify header in front of Ogg files, only when not converting, for debugging"
+	)]
+	pub raw: bool,
+	#[arg(
+		long,
+		value_enum,
+		help = "Size of the embedded cover, the closest available is used, overrides the settings"
+	)]
+	pub cover_size: Option<CoverSize>,
+	#[arg(
+		long,
+		value_enum,
+		help = "Precision of the release date in tags, full uses what Spotify knows, overrides the settings"
+	)]
+	pub date_format: Option<DateFormat>,
+	#[arg(
+		long,
+		value_enum,
+		help = "Source of the track, album and artist names in tags and paths, spotify being the Web API, overrides the settings"
+	)]
+	pub metadata_source: Option<MetadataSource>,
+	#[arg(
+		long,
+		help = "Write the cover as cover.jpg into the folder of the tracks"
+	)]
+	pub save_cover: bool,
+	#[arg(
+		long,
+		help = "Fail a download receiving no audio for this many seconds, retried like network errors"
+	)]
+	pub timeout: Option<u64>,
+	#[arg(
+		long,
+		help = "Fail a download taking longer than this many seconds altogether, retried like network errors"
+	)]
+	pub track_timeout: Option<u64>,
+	#[arg(long, help = "Skip explicit tracks, overrides the settings")]
+	pub skip_explicit: bool,
+	#[arg(
+		long,
+		conflicts_with = "skip_explicit",
+		help = "Only download explicit tracks, overrides the settings"
+	)]
+	pub only_explicit: bool,
+	#[arg(
+		long,
+		help = "Apply the normalization gain of tracks, only when converting with --format"
+	)]
+	pub normalize: bool,
+	#[arg(
+		long,
+		help = "Download tracks again and replace existing files, overrides skip_existing"
+	)]
+	pub overwrite: bool,
+	#[arg(
+		long,
+		conflicts_with = "overwrite",
+		help = "Recognize existing files by the Spotify track id in their tags instead of their path"
+	)]
+	pub match_by_id: bool,
+	#[arg(
+		long,
+		help = "Maximum combined download rate in bytes per second, overrides the settings"
+	)]
+	pub max_rate: Option<usize>,
+	#[arg(
+		long,
+		value_enum,
+		help = "Skip tracks not available in at least this quality, overrides the settings"
+	)]
+	pub min_quality: Option<Quality>,
+	#[arg(
+		long,
+		help = "HTTP proxy for all connections, e.g. http://localhost:8080, overrides the settings and HTTP_PROXY"
+	)]
+	pub proxy: Option<String>,
+	#[arg(
+		long,
+		help = "Device ID the session registers with, overrides the settings"
+	)]
+	pub device_id: Option<String>,
+	#[arg(
+		long,
+		env = "DOWN_ON_SPOT_CLIENT_ID",
+		help = "Client ID of the Web API application, overrides the settings"
+	)]
+	pub client_id: Option<String>,
+	#[arg(
+		long,
+		env = "DOWN_ON_SPOT_CLIENT_SECRET",
+		hide_env_values = true,
+		help = "Client secret of the Web API application, overrides the settings"
+	)]
+	pub client_secret: Option<String>,
+	#[arg(
+		long,
+		env = "DOWN_ON_SPOT_CACHE_DIR",
+		help = "Folder of the cached credentials, overrides the settings"
+	)]
+	pub cache_dir: Option<String>,
+	#[arg(
+		long,
+		help = "Keep downloaded audio files in this folder to reuse them in later runs, overrides the settings"
+	)]
+	pub audio_cache_dir: Option<String>,
+	#[arg(
+		long,
+		conflicts_with = "audio_cache_dir",
+		help = "Do not use the audio cache of the settings"
+	)]
+	pub no_audio_cache: bool,
+	#[arg(
+		long,
+		value_parser = parse_market,
+		help = "Country code of the market to resolve items in, e.g. DE, overrides market_country_code"
+	)]
+	pub market: Option<CountryCode>,
+	#[arg(
+		short,
+		long,
+		action = ArgAction::Count,
+		help = "Log more, repeat for more details (-v debug, -vv trace)"
+	)]
+	pub verbose: u8,
+	#[arg(
+		long,
+		help = "Level of the log (off, error, warn, info, debug, trace), overrides --verbose and RUST_LOG"
+	)]
+	pub log_level: Option<LevelFilter>,
+	/// Options which got their value from an environment variable
+	#[arg(skip)]
+	pub from_env: Vec<&'static str>,
+}
+
+/// Parse an ISO 3166-1 alpha-2 country code
+fn parse_market(code: &str) -> Result<CountryCode, String> {
+	CountryCode::for_alpha2(&code.to_uppercase())
+		.map_err(|_| format!("Unknown country code: {}", code))
+}
+
+/// Parse a date as its start in UTC or an RFC 3339 time
+fn parse_added_after(value: &str) -> Result<DateTime<Utc>, String> {
+	if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+		return Ok(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap()));
+	}
+	DateTime::parse_from_rfc3339(value)
+		.map(|time| time.with_timezone(&Utc))
+		.map_err(|_| format!("Invalid date, expected YYYY-MM-DD or RFC 3339: {}", value))
+}
+
+/// Format of the progress printed to stdout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputMode {
+	Text,
+	Json,
+}
+
+/// When to color the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorMode {
+	Auto,
+	Always,
+	Never,
 }
 
 impl Args {
 	pub fn from_cli() -> Self {
 		let cli = get_command();
-		Self::from_arg_matches(&cli.get_matches()).unwrap()
+		let matches = cli.get_matches();
+		let mut args = Self::from_arg_matches(&matches).unwrap();
+		args.from_env = ["client_id", "client_secret"]
+			.into_iter()
+			.filter(|id| matches.value_source(id) == Some(ValueSource::EnvVariable))
+			.collect();
+		args
+	}
+
+	/// Drop the values of environment variables which the config file sets, it takes precedence
+	pub fn merge_config(&mut self, config: &ConfigFile) {
+		if config.client_id.is_some() && self.from_env.contains(&"client_id") {
+			self.client_id = None;
+		}
+		if config.client_secret.is_some() && self.from_env.contains(&"client_secret") {
+			self.client_secret = None;
+		}
+	}
+
+	/// Level of the log chosen with --log-level or --verbose
+	pub fn log_filter(&self) -> Option<LevelFilter> {
+		self.log_level.or(match self.verbose {
+			0 => None,
+			1 => Some(LevelFilter::Debug),
+			_ => Some(LevelFilter::Trace),
+		})
 	}
 }
 
@@ -21,9 +443,9 @@ fn get_command() -> Command {
 		.author(crate_authors!())
 		.version(crate_version!())
 		.about(format!(
-			"Settings file located at: {}",
-			settings::get_config_settings_path().to_string_lossy()
+			"Settings file located at: {}\nOptions override down_on_spot.toml, which overrides environment variables, which override the settings file",
+			settings::get_settings_path().to_string_lossy()
 		));
-	
+
 	Args::augment_args(cli)
 }
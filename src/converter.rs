@@ -1,17 +1,87 @@
 use lewton::inside_ogg::OggStreamReader;
-use std::io::{Error, ErrorKind, Read, Seek};
+use std::borrow::Cow;
+use std::fs::OpenOptions;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
 
-use crate::downloader::{AudioFormat, Quality};
+use crate::downloader::{AudioFormat, Bitrate, OutputFormat};
 use crate::error::SpotifyError;
 use crate::error::SpotifyError::{InvalidFormat, LameConverterError};
+use crate::lame::Lame;
+#[cfg(feature = "opus")]
+use crate::opus::Opus;
 
-/// Converts audio to MP3
-pub enum AudioConverter {
-	Ogg {
-		decoder: OggStreamReader<ReadWrap>,
-		lame: lame::Lame,
-		lame_end: bool,
-	},
+/// Size of the blocks written by the FLAC encoder
+const FLAC_BLOCK_SIZE: usize = 4096;
+
+/// End of the header Spotify puts in front of Ogg files
+const SPOTIFY_OGG_HEADER_END: usize = 0xa7;
+
+/// Bytes searched for the first Ogg page if it is not right after the header
+const OGG_SEARCH_SIZE: u64 = 4096;
+
+/// Ogg page flags
+pub(crate) const OGG_CONTINUED: u8 = 0x01;
+pub(crate) const OGG_FIRST: u8 = 0x02;
+pub(crate) const OGG_LAST: u8 = 0x04;
+
+/// Sample rate of all Opus streams written
+#[cfg(feature = "opus")]
+const OPUS_SAMPLE_RATE: u32 = 48000;
+
+/// Samples per channel of an Opus packet, 20 ms
+#[cfg(feature = "opus")]
+const OPUS_FRAME_SIZE: usize = 960;
+
+/// Packets written to an Ogg page at most, about a second of Opus audio
+#[cfg(feature = "opus")]
+const OPUS_PAGE_PACKETS: usize = 50;
+
+/// Serial number of the only stream of written Ogg files
+#[cfg(feature = "opus")]
+const OGG_SERIAL: u32 = 0x444f5350;
+
+/// Output settings of a conversion
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ConvertOptions {
+	pub output: OutputFormat,
+	pub bitrate: Bitrate,
+	/// Algorithm quality of lame, 0 is best and 9 is worst
+	pub mp3_quality: u8,
+	/// Variable bitrate quality of MP3 files instead of the bitrate, 0 is best and 9 is worst
+	pub vbr: Option<u8>,
+	/// Scale the samples by the normalization gain from the Spotify header
+	pub normalize: bool,
+	/// Dither the samples scaled by the normalization gain
+	pub dither: bool,
+}
+
+/// Converts audio to the output format
+pub(crate) struct AudioConverter {
+	decoder: OggStreamReader<ReadWrap>,
+	encoder: Box<dyn Encoder>,
+	/// Encoded data not yet read
+	buffer: Vec<u8>,
+	finished: bool,
+	/// Factor applied to the decoded samples
+	gain: Option<f32>,
+	dither: Option<Dither>,
+	/// Decoded samples per channel, for the progress
+	samples: u64,
+	sample_rate: u32,
+}
+
+/// Encoder of the output format, fed with the decoded packets
+trait Encoder {
+	/// Encode a packet of samples per channel and append the output to buffer
+	fn encode(&mut self, pcm: &[Vec<i16>], buffer: &mut Vec<u8>) -> std::io::Result<()>;
+	/// Append the remaining data at the end of stream to buffer
+	fn flush(&mut self, buffer: &mut Vec<u8>) -> std::io::Result<()>;
+	/// Data to write over the start of the output once it is complete
+	fn header(&self) -> Option<Vec<u8>> {
+		None
+	}
 }
 
 unsafe impl Send for AudioConverter {}
@@ -19,122 +89,1137 @@ unsafe impl Send for AudioConverter {}
 impl AudioConverter {
 	/// Wrap reader
 	pub fn new(
-		read: Box<(dyn Read + Send + 'static)>,
+		read: Box<dyn Read + Send + 'static>,
 		format: AudioFormat,
-		quality: Quality,
+		options: ConvertOptions,
+		gain: Option<f32>,
 	) -> Result<AudioConverter, SpotifyError> {
-		// Create encoder
-		let bitrate = match quality {
-			Quality::Q320 => 320,
-			Quality::Q256 => 256,
-			Quality::Q160 => 160,
-			Quality::Q96 => 96,
+		let ConvertOptions {
+			output, bitrate, ..
+		} = options;
+		let decoder = match format {
+			// Lewton decoder
+			AudioFormat::Ogg => OggStreamReader::new(ReadWrap::new(Box::new(read)))?,
+			// Only Vorbis can be decoded
+			_ => return Err(InvalidFormat),
 		};
+		let sample_rate = decoder.ident_hdr.audio_sample_rate;
+
+		let encoder: Box<dyn Encoder> = match output {
+			OutputFormat::Mp3 => Box::new(AudioConverter::init_lame(
+				sample_rate,
+				bitrate,
+				options.mp3_quality,
+				options.vbr,
+			)?),
+			OutputFormat::Flac => Box::new(FlacEncoder::new(
+				sample_rate,
+				decoder.ident_hdr.audio_channels as usize,
+			)),
+			OutputFormat::Wav => Box::new(WavEncoder::new(
+				sample_rate,
+				decoder.ident_hdr.audio_channels as usize,
+			)),
+			#[cfg(feature = "opus")]
+			OutputFormat::Opus => Box::new(OpusEncoder::new(
+				sample_rate,
+				decoder.ident_hdr.audio_channels.min(2),
+				bitrate,
+			)?),
+			#[cfg(not(feature = "opus"))]
+			OutputFormat::Opus => {
+				return Err(SpotifyError::Error(
+					"Opus output needs a build with the opus feature".to_string(),
+				))
+			}
+			OutputFormat::Original => return Err(InvalidFormat),
+		};
+
+		Ok(AudioConverter {
+			decoder,
+			encoder,
+			buffer: vec![],
+			finished: false,
+			gain,
+			dither: options.dither.then(Dither::new),
+			samples: 0,
+			sample_rate,
+		})
+	}
 
-		let mut lame = lame::Lame::new().unwrap();
+	/// Create MP3 encoder
+	fn init_lame(
+		sample_rate: u32,
+		bitrate: Bitrate,
+		quality: u8,
+		vbr: Option<u8>,
+	) -> Result<Lame, SpotifyError> {
+		let mut lame = Lame::new().ok_or_else(|| LameConverterError("Init".to_string()))?;
 
 		match lame.set_channels(2) {
 			Ok(_) => {}
 			Err(_) => return Err(LameConverterError("Channels".to_string())),
 		};
 
-		match lame.set_quality(0) {
+		match lame.set_quality(quality) {
 			Ok(_) => {}
 			Err(_) => return Err(LameConverterError("Quality".to_string())),
 		};
-		match lame.set_kilobitrate(bitrate) {
+		let mode = match vbr {
+			Some(vbr) => lame.set_vbr_quality(vbr),
+			None => lame.set_kilobitrate(bitrate.kbps()),
+		};
+		match mode {
 			Ok(_) => {}
 			Err(_) => return Err(LameConverterError("Bitrate".to_string())),
 		};
+		match lame.set_sample_rate(sample_rate) {
+			Ok(_) => {}
+			Err(_) => return Err(LameConverterError("Sample rate".to_string())),
+		};
+		match lame.init_params() {
+			Ok(_) => {}
+			Err(_) => return Err(LameConverterError("Init".to_string())),
+		};
 
-		match format {
-			AudioFormat::Aac => todo!(),
-			// Lewton decoder
-			AudioFormat::Ogg => {
-				let decoder = OggStreamReader::new(ReadWrap::new(Box::new(read)))?;
-				let sample_rate = decoder.ident_hdr.audio_sample_rate;
-				// Init lame
-				match lame.set_sample_rate(sample_rate) {
-					Ok(_) => {}
-					Err(_) => return Err(LameConverterError("Sample rate".to_string())),
-				};
-				match lame.init_params() {
-					Ok(_) => {}
-					Err(_) => return Err(LameConverterError("Init".to_string())),
-				};
+		Ok(lame)
+	}
 
-				Ok(AudioConverter::Ogg {
-					lame,
-					decoder,
-					lame_end: false,
-				})
+	/// Data to write over the start of the output, once everything was read
+	pub fn header(&self) -> Option<Vec<u8>> {
+		self.encoder.header()
+	}
+
+	/// Position in the audio up to which it was decoded
+	pub fn position(&self) -> Duration {
+		Duration::from_secs_f64(self.samples as f64 / self.sample_rate.max(1) as f64)
+	}
+}
+
+impl Encoder for Lame {
+	fn encode(&mut self, pcm: &[Vec<i16>], buffer: &mut Vec<u8>) -> std::io::Result<()> {
+		let pcm = stereo(pcm);
+		// Worst case buffer size recommended by lame
+		let mut out = vec![0; pcm[0].len() * 5 / 4 + 7200];
+		match Lame::encode(self, &pcm[0], &pcm[1], &mut out) {
+			Ok(size) => buffer.extend_from_slice(&out[..size]),
+			Err(e) => {
+				return Err(Error::new(
+					ErrorKind::InvalidData,
+					format!("Lame error: {:?}", e),
+				));
 			}
-			AudioFormat::Mp3 => panic!("No reencoding allowd!"),
-			_ => Err(InvalidFormat),
-		}
+		};
+		Ok(())
+	}
+
+	fn flush(&mut self, buffer: &mut Vec<u8>) -> std::io::Result<()> {
+		let mut out = vec![0; 7200];
+		match Lame::flush(self, &mut out) {
+			Ok(size) => buffer.extend_from_slice(&out[..size]),
+			Err(e) => {
+				return Err(Error::new(
+					ErrorKind::InvalidData,
+					format!("Lame error: {:?}", e),
+				));
+			}
+		};
+		Ok(())
+	}
+
+	/// Xing/Info frame for the duration and seeking, mostly needed for variable bitrate
+	fn header(&self) -> Option<Vec<u8>> {
+		Some(self.lametag_frame()).filter(|frame| !frame.is_empty())
 	}
 }
 
 impl Read for AudioConverter {
 	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-		match self {
-			AudioConverter::Ogg {
-				decoder,
-				lame,
-				lame_end,
-			} => {
-				match decoder.read_dec_packet() {
-					Ok(packet) => match packet {
-						Some(data) => {
-							// 0 sized packets aren't EOF
-							if data[0].is_empty() {
-								return self.read(buf);
-							}
-
-							let result = match lame.encode(&data[0], &data[1], buf) {
-								Ok(size) => {
-									if size == 0 {
-										return self.read(buf);
-									}
-									size
-								}
-								Err(e) => {
-									return Err(Error::new(
-										ErrorKind::InvalidData,
-										format!("Lame error: {:?}", e),
-									));
-								}
+		while self.buffer.is_empty() {
+			if self.finished {
+				return Ok(0);
+			}
+
+			match self.decoder.read_dec_packet() {
+				Ok(Some(mut data)) => {
+					// 0 sized packets aren't EOF
+					if data.is_empty() || data[0].is_empty() {
+						continue;
+					}
+					self.samples += data[0].len() as u64;
+					if let Some(gain) = self.gain {
+						for sample in data.iter_mut().flatten() {
+							let scaled = match &mut self.dither {
+								Some(dither) => (*sample as f32 * gain + dither.noise()).round(),
+								None => *sample as f32 * gain,
 							};
-							Ok(result as usize)
-						}
-						None => {
-							if *lame_end {
-								return Ok(0);
-							}
-							*lame_end = true;
-							Ok(0)
+							*sample = scaled.clamp(i16::MIN as f32, i16::MAX as f32) as i16;
 						}
+					}
+					self.encoder.encode(&data, &mut self.buffer)?;
+				}
+				// Drain the encoder before EOF
+				Ok(None) => {
+					self.finished = true;
+					self.encoder.flush(&mut self.buffer)?;
+				}
+				// Don't end the stream early, the file would be truncated
+				Err(e) => {
+					return Err(Error::new(
+						ErrorKind::InvalidData,
+						format!("Lewton error: {}", e),
+					));
+				}
+			}
+		}
+
+		let size = buf.len().min(self.buffer.len());
+		buf[..size].copy_from_slice(&self.buffer[..size]);
+		self.buffer.drain(..size);
+		Ok(size)
+	}
+}
+
+/// Triangular dither of one least significant bit, from a xorshift generator
+struct Dither {
+	state: u32,
+}
+
+impl Dither {
+	fn new() -> Dither {
+		Dither { state: 0x2545_f491 }
+	}
+
+	/// Uniform value between -0.5 and 0.5
+	fn uniform(&mut self) -> f32 {
+		self.state ^= self.state << 13;
+		self.state ^= self.state >> 17;
+		self.state ^= self.state << 5;
+		self.state as f32 / u32::MAX as f32 - 0.5
+	}
+
+	/// Noise to add before rounding a sample
+	fn noise(&mut self) -> f32 {
+		self.uniform() + self.uniform()
+	}
+}
+
+/// Samples of two channels, duplicating mono and mixing down more channels
+fn stereo(pcm: &[Vec<i16>]) -> Cow<'_, [Vec<i16>]> {
+	match pcm.len() {
+		2 => return Cow::Borrowed(pcm),
+		1 => return Cow::Owned(vec![pcm[0].clone(), pcm[0].clone()]),
+		_ => {}
+	}
+
+	// Weights of the channels in the left and right output, in the Vorbis channel order
+	let center = std::f32::consts::FRAC_1_SQRT_2;
+	let weights: Vec<(f32, f32)> = match pcm.len() {
+		// Left, center, right
+		3 => vec![(1.0, 0.0), (center, center), (0.0, 1.0)],
+		// Front left, front right, rear left, rear right
+		4 => vec![(1.0, 0.0), (0.0, 1.0), (1.0, 0.0), (0.0, 1.0)],
+		// Front left, center, front right, rear left, rear right, LFE
+		5 | 6 => vec![
+			(1.0, 0.0),
+			(center, center),
+			(0.0, 1.0),
+			(1.0, 0.0),
+			(0.0, 1.0),
+			(0.0, 0.0),
+		],
+		// Front left, center, front right, side left, side right, rear center, LFE
+		7 => vec![
+			(1.0, 0.0),
+			(center, center),
+			(0.0, 1.0),
+			(1.0, 0.0),
+			(0.0, 1.0),
+			(center, center),
+			(0.0, 0.0),
+		],
+		// Front left, center, front right, side left, side right, rear left, rear right, LFE
+		8 => vec![
+			(1.0, 0.0),
+			(center, center),
+			(0.0, 1.0),
+			(1.0, 0.0),
+			(0.0, 1.0),
+			(1.0, 0.0),
+			(0.0, 1.0),
+			(0.0, 0.0),
+		],
+		// Undefined order, alternate between left and right
+		n => (0..n)
+			.map(|i| if i % 2 == 0 { (1.0, 0.0) } else { (0.0, 1.0) })
+			.collect(),
+	};
+	let left_total: f32 = weights.iter().map(|w| w.0).sum();
+	let right_total: f32 = weights.iter().map(|w| w.1).sum();
+
+	let mut left = Vec::with_capacity(pcm[0].len());
+	let mut right = Vec::with_capacity(pcm[0].len());
+	for i in 0..pcm[0].len() {
+		let (mut l, mut r) = (0.0, 0.0);
+		for (channel, (wl, wr)) in pcm.iter().zip(&weights) {
+			l += channel[i] as f32 * wl;
+			r += channel[i] as f32 * wr;
+		}
+		// Scaled by the total weight so the mix does not clip
+		left.push((l / left_total) as i16);
+		right.push((r / right_total) as i16);
+	}
+	Cow::Owned(vec![left, right])
+}
+
+/// Offset of the first Ogg page of a Spotify Ogg file, leaving the reader at the start
+///
+/// The page is expected right after the Spotify header, if it is not there
+/// the first bytes are searched for it
+pub(crate) fn find_ogg_start(reader: &mut (impl Read + Seek)) -> Result<u64, Error> {
+	let mut head = vec![];
+	reader.seek(SeekFrom::Start(0))?;
+	reader
+		.by_ref()
+		.take(OGG_SEARCH_SIZE)
+		.read_to_end(&mut head)?;
+	reader.seek(SeekFrom::Start(0))?;
+
+	if head.get(SPOTIFY_OGG_HEADER_END..SPOTIFY_OGG_HEADER_END + 4) == Some(&b"OggS"[..]) {
+		return Ok(SPOTIFY_OGG_HEADER_END as u64);
+	}
+	match head.windows(4).position(|w| w == b"OggS") {
+		Some(start) => {
+			warn!(
+				"Ogg audio starts at {} instead of {}",
+				start, SPOTIFY_OGG_HEADER_END
+			);
+			Ok(start as u64)
+		}
+		None => Err(Error::new(ErrorKind::InvalidData, "No Ogg page found")),
+	}
+}
+
+/// Factor normalizing the loudness of a track, from the header preceding the audio
+///
+/// Lowered to keep the peak of the track from clipping
+pub(crate) fn normalization_gain(header: &[u8]) -> f32 {
+	let (Some(gain_db), Some(peak)) = (header.get(144..148), header.get(148..152)) else {
+		warn!("No normalization data in the header, not normalizing");
+		return 1.0;
+	};
+	let gain_db = f32::from_le_bytes(gain_db.try_into().unwrap());
+	let peak = f32::from_le_bytes(peak.try_into().unwrap());
+	let mut factor = 10f32.powf(gain_db / 20.0);
+	if peak > 0.0 && peak * factor > 1.0 {
+		factor = 1.0 / peak;
+	}
+	debug!(
+		"Normalization gain: {} dB, peak: {}, factor: {}",
+		gain_db, peak, factor
+	);
+	factor
+}
+
+/// FLAC encoder using fixed linear predictors with Rice coded residuals
+///
+/// The source is lossy Vorbis, so the higher orders of LPC would gain little
+struct FlacEncoder {
+	sample_rate: u32,
+	channels: usize,
+	/// Samples per channel not yet written to a frame
+	samples: Vec<Vec<i16>>,
+	frame_number: u32,
+	header_written: bool,
+	/// Samples per channel written to frames
+	total_samples: u64,
+	/// Sizes of the smallest and largest frame in bytes
+	frame_sizes: Option<(usize, usize)>,
+}
+
+impl FlacEncoder {
+	fn new(sample_rate: u32, channels: usize) -> FlacEncoder {
+		FlacEncoder {
+			sample_rate,
+			channels,
+			samples: vec![vec![]; channels],
+			frame_number: 0,
+			header_written: false,
+			total_samples: 0,
+			frame_sizes: None,
+		}
+	}
+
+	/// Stream marker and STREAMINFO block with what is known so far
+	fn stream_info(&self) -> Vec<u8> {
+		let mut header = b"fLaC".to_vec();
+		// Last metadata block, type STREAMINFO, 34 bytes long
+		header.extend_from_slice(&[0x80, 0x00, 0x00, 0x22]);
+		// Min and max block size
+		header.extend_from_slice(&(FLAC_BLOCK_SIZE as u16).to_be_bytes());
+		header.extend_from_slice(&(FLAC_BLOCK_SIZE as u16).to_be_bytes());
+		// Min and max frame size, 0 while unknown
+		let (min, max) = self.frame_sizes.unwrap_or((0, 0));
+		header.extend_from_slice(&(min as u32).to_be_bytes()[1..]);
+		header.extend_from_slice(&(max as u32).to_be_bytes()[1..]);
+		// Sample rate, channels, 16 bits per sample and total samples
+		let info = ((self.sample_rate as u64) << 44)
+			| ((self.channels as u64 - 1) << 41)
+			| (15 << 36)
+			| self.total_samples.min((1 << 36) - 1);
+		header.extend_from_slice(&info.to_be_bytes());
+		// Unknown MD5 signature
+		header.extend_from_slice(&[0; 16]);
+		header
+	}
+
+	fn write_header(&mut self, buffer: &mut Vec<u8>) {
+		if self.header_written {
+			return;
+		}
+		self.header_written = true;
+		buffer.extend(self.stream_info());
+	}
+
+	fn write_frame(&mut self, block_size: usize, buffer: &mut Vec<u8>) {
+		let channels: Vec<Vec<i32>> = self
+			.samples
+			.iter_mut()
+			.map(|samples| samples.drain(..block_size).map(i32::from).collect())
+			.collect();
+		let (assignment, subframes) = match &channels[..] {
+			[left, right] => stereo_subframes(left, right),
+			_ => (
+				channels.len() as u8 - 1,
+				channels
+					.iter()
+					.map(|c| (Subframe::new(c, 16), c.clone(), 16))
+					.collect(),
+			),
+		};
+
+		// Sync code, fixed block size, block size at end of header, sample rate
+		// from STREAMINFO and 16 bits per sample
+		let mut frame = BitWriter {
+			bytes: vec![0xff, 0xf8, 0x70, (assignment << 4) | 0x08],
+			..Default::default()
+		};
+		frame.bytes.extend(flac_utf8(self.frame_number));
+		frame
+			.bytes
+			.extend_from_slice(&((block_size - 1) as u16).to_be_bytes());
+		frame.bytes.push(crc8(&frame.bytes));
+
+		for (subframe, samples, bits) in subframes {
+			subframe.write(&samples, bits, &mut frame);
+		}
+		let mut frame = frame.finish();
+		let crc = crc16(&frame);
+		frame.extend_from_slice(&crc.to_be_bytes());
+
+		self.frame_sizes = Some(match self.frame_sizes {
+			Some((min, max)) => (min.min(frame.len()), max.max(frame.len())),
+			None => (frame.len(), frame.len()),
+		});
+		self.total_samples += block_size as u64;
+		buffer.extend(frame);
+		self.frame_number += 1;
+	}
+}
+
+impl Encoder for FlacEncoder {
+	fn encode(&mut self, pcm: &[Vec<i16>], buffer: &mut Vec<u8>) -> std::io::Result<()> {
+		self.write_header(buffer);
+		for (samples, decoded) in self.samples.iter_mut().zip(pcm) {
+			samples.extend_from_slice(decoded);
+		}
+		while self.samples[0].len() >= FLAC_BLOCK_SIZE {
+			self.write_frame(FLAC_BLOCK_SIZE, buffer);
+		}
+		Ok(())
+	}
+
+	fn flush(&mut self, buffer: &mut Vec<u8>) -> std::io::Result<()> {
+		self.write_header(buffer);
+		let remaining = self.samples[0].len();
+		if remaining > 0 {
+			self.write_frame(remaining, buffer);
+		}
+		Ok(())
+	}
+
+	/// STREAMINFO with the total samples and frame sizes
+	fn header(&self) -> Option<Vec<u8>> {
+		Some(self.stream_info()).filter(|_| self.header_written)
+	}
+}
+
+/// Channel assignment and subframes of the cheapest stereo decorrelation,
+/// with the samples and bits per sample of each subframe
+fn stereo_subframes(left: &[i32], right: &[i32]) -> (u8, Vec<(Subframe, Vec<i32>, u32)>) {
+	let side: Vec<i32> = left.iter().zip(right).map(|(l, r)| l - r).collect();
+	let mid: Vec<i32> = left.iter().zip(right).map(|(l, r)| (l + r) >> 1).collect();
+	let left = (Subframe::new(left, 16), left.to_vec(), 16);
+	let right = (Subframe::new(right, 16), right.to_vec(), 16);
+	let side = (Subframe::new(&side, 17), side, 17);
+	let mid = (Subframe::new(&mid, 16), mid, 16);
+
+	// Independent, left/side, right/side and mid/side
+	let options = [
+		(1, left.0.bits + right.0.bits),
+		(8, left.0.bits + side.0.bits),
+		(9, side.0.bits + right.0.bits),
+		(10, mid.0.bits + side.0.bits),
+	];
+	let (assignment, _) = options.into_iter().min_by_key(|(_, bits)| *bits).unwrap();
+	let subframes = match assignment {
+		1 => vec![left, right],
+		8 => vec![left, side],
+		9 => vec![side, right],
+		_ => vec![mid, side],
+	};
+	(assignment, subframes)
+}
+
+/// Highest order of the fixed FLAC predictors
+const FLAC_MAX_ORDER: usize = 4;
+/// Highest partition order of the residuals, as in the FLAC subset
+const FLAC_MAX_PARTITION_ORDER: u32 = 8;
+/// Highest Rice parameter without escape code
+const FLAC_MAX_RICE_PARAMETER: u32 = 14;
+
+/// Coding of a FLAC subframe
+enum SubframeKind {
+	Constant,
+	Verbatim,
+	Fixed {
+		order: usize,
+		partition_order: u32,
+		parameters: Vec<u32>,
+	},
+}
+
+/// Cheapest coding found for the samples of a channel, with its size in bits
+struct Subframe {
+	kind: SubframeKind,
+	bits: u64,
+}
+
+impl Subframe {
+	/// Find the cheapest coding of samples of bits size
+	fn new(samples: &[i32], bits: u32) -> Subframe {
+		if samples.iter().all(|s| *s == samples[0]) {
+			return Subframe {
+				kind: SubframeKind::Constant,
+				bits: 8 + bits as u64,
+			};
+		}
+
+		let mut best = Subframe {
+			kind: SubframeKind::Verbatim,
+			bits: 8 + bits as u64 * samples.len() as u64,
+		};
+		for order in 0..=FLAC_MAX_ORDER.min(samples.len() - 1) {
+			let residual = fixed_residual(samples, order);
+			let (partition_order, parameters, residual_bits) =
+				rice_partitions(&residual, samples.len(), order);
+			let total = 8 + (order as u64 * bits as u64) + residual_bits;
+			if total < best.bits {
+				best = Subframe {
+					kind: SubframeKind::Fixed {
+						order,
+						partition_order,
+						parameters,
 					},
-					Err(e) => {
-						// Close lame
-						if !*lame_end {
-							*lame_end = true;
-						}
-						warn!("Lawton error: {}, calling EOF", e);
-						Ok(0)
+					bits: total,
+				};
+			}
+		}
+		best
+	}
+
+	fn write(&self, samples: &[i32], bits: u32, writer: &mut BitWriter) {
+		// Zero bit padding, type and no wasted bits
+		match &self.kind {
+			SubframeKind::Constant => {
+				writer.write(0b0000_0000, 8);
+				writer.write(samples[0] as u64, bits);
+			}
+			SubframeKind::Verbatim => {
+				writer.write(0b0000_0010, 8);
+				for sample in samples {
+					writer.write(*sample as u64, bits);
+				}
+			}
+			SubframeKind::Fixed {
+				order,
+				partition_order,
+				parameters,
+			} => {
+				writer.write(0b0001_0000 | (*order as u64) << 1, 8);
+				for sample in &samples[..*order] {
+					writer.write(*sample as u64, bits);
+				}
+				// Rice coding with 4 bit parameters
+				writer.write(0b00, 2);
+				writer.write(*partition_order as u64, 4);
+				let residual = fixed_residual(samples, *order);
+				let partition_size = samples.len() >> partition_order;
+				let mut start = 0;
+				for (i, parameter) in parameters.iter().enumerate() {
+					// The first partition holds the warm-up samples
+					let end = (i + 1) * partition_size - order;
+					writer.write(*parameter as u64, 4);
+					for value in &residual[start..end] {
+						writer.write_rice(zigzag(*value), *parameter);
 					}
+					start = end;
 				}
 			}
 		}
 	}
 }
 
-pub struct ReadWrap {
-	source: Box<(dyn Read + Send + 'static)>,
+/// Residual of the fixed predictor of order
+fn fixed_residual(samples: &[i32], order: usize) -> Vec<i32> {
+	samples
+		.windows(order + 1)
+		.map(|w| match order {
+			0 => w[0],
+			1 => w[1] - w[0],
+			2 => w[2] - 2 * w[1] + w[0],
+			3 => w[3] - 3 * w[2] + 3 * w[1] - w[0],
+			_ => w[4] - 4 * w[3] + 6 * w[2] - 4 * w[1] + w[0],
+		})
+		.collect()
+}
+
+/// Map signed residuals to unsigned the way Rice coding in FLAC expects
+fn zigzag(value: i32) -> u64 {
+	((value << 1) ^ (value >> 31)) as u32 as u64
+}
+
+/// Cheapest partition order with its Rice parameters and the estimated size in bits
+fn rice_partitions(residual: &[i32], block_size: usize, order: usize) -> (u32, Vec<u32>, u64) {
+	let mut best: Option<(u32, Vec<u32>, u64)> = None;
+	for partition_order in 0..=FLAC_MAX_PARTITION_ORDER {
+		let partition_size = block_size >> partition_order;
+		if !block_size.is_multiple_of(1 << partition_order) || partition_size <= order {
+			break;
+		}
+
+		// Method and partition order
+		let mut bits = 6;
+		let mut parameters = vec![];
+		let mut start = 0;
+		for i in 0..1 << partition_order {
+			let end = (i + 1) * partition_size - order;
+			let values = &residual[start..end];
+			let sum: u64 = values.iter().map(|v| zigzag(*v)).sum();
+			let (parameter, size) = (0..=FLAC_MAX_RICE_PARAMETER)
+				.map(|k| (k, values.len() as u64 * (k as u64 + 1) + (sum >> k)))
+				.min_by_key(|(_, size)| *size)
+				.unwrap();
+			parameters.push(parameter);
+			bits += 4 + size;
+			start = end;
+		}
+		if best.as_ref().is_none_or(|(_, _, best)| bits < *best) {
+			best = Some((partition_order, parameters, bits));
+		}
+	}
+	best.unwrap()
+}
+
+/// Writer of big endian bit fields
+#[derive(Default)]
+struct BitWriter {
+	bytes: Vec<u8>,
+	/// Bits not yet forming a whole byte, in the low end
+	partial: u64,
+	length: u32,
+}
+
+impl BitWriter {
+	/// Write the low bits of value, up to 32
+	fn write(&mut self, value: u64, bits: u32) {
+		self.partial = (self.partial << bits) | (value & ((1 << bits) - 1));
+		self.length += bits;
+		while self.length >= 8 {
+			self.length -= 8;
+			self.bytes.push((self.partial >> self.length) as u8);
+		}
+	}
+
+	/// Write value as unary quotient and parameter low bits
+	fn write_rice(&mut self, value: u64, parameter: u32) {
+		let mut quotient = value >> parameter;
+		while quotient >= 32 {
+			self.write(0, 32);
+			quotient -= 32;
+		}
+		self.write(1, quotient as u32 + 1);
+		self.write(value, parameter);
+	}
+
+	/// Pad to a whole byte
+	fn finish(mut self) -> Vec<u8> {
+		if self.length > 0 {
+			self.write(0, 8 - self.length);
+		}
+		self.bytes
+	}
+}
+
+/// Writer of 16 bit PCM in a RIFF/WAV container
+///
+/// The sizes in the header are unknown while streaming, call `finalize_wav` once the file is written
+struct WavEncoder {
+	sample_rate: u32,
+	channels: usize,
+	header_written: bool,
+}
+
+impl WavEncoder {
+	fn new(sample_rate: u32, channels: usize) -> WavEncoder {
+		WavEncoder {
+			sample_rate,
+			channels,
+			header_written: false,
+		}
+	}
+
+	fn write_header(&mut self, buffer: &mut Vec<u8>) {
+		if self.header_written {
+			return;
+		}
+		self.header_written = true;
+
+		let block_align = self.channels as u16 * 2;
+		buffer.extend_from_slice(b"RIFF");
+		buffer.extend_from_slice(&u32::MAX.to_le_bytes());
+		buffer.extend_from_slice(b"WAVE");
+		// Format chunk for PCM
+		buffer.extend_from_slice(b"fmt ");
+		buffer.extend_from_slice(&16u32.to_le_bytes());
+		buffer.extend_from_slice(&1u16.to_le_bytes());
+		buffer.extend_from_slice(&(self.channels as u16).to_le_bytes());
+		buffer.extend_from_slice(&self.sample_rate.to_le_bytes());
+		buffer.extend_from_slice(&(self.sample_rate * block_align as u32).to_le_bytes());
+		buffer.extend_from_slice(&block_align.to_le_bytes());
+		buffer.extend_from_slice(&16u16.to_le_bytes());
+		// Data chunk
+		buffer.extend_from_slice(b"data");
+		buffer.extend_from_slice(&u32::MAX.to_le_bytes());
+	}
+}
+
+impl Encoder for WavEncoder {
+	fn encode(&mut self, pcm: &[Vec<i16>], buffer: &mut Vec<u8>) -> std::io::Result<()> {
+		self.write_header(buffer);
+		// Interleave channels
+		for i in 0..pcm[0].len() {
+			for channel in pcm.iter().take(self.channels) {
+				buffer.extend_from_slice(&channel[i].to_le_bytes());
+			}
+		}
+		Ok(())
+	}
+
+	fn flush(&mut self, buffer: &mut Vec<u8>) -> std::io::Result<()> {
+		self.write_header(buffer);
+		Ok(())
+	}
+}
+
+/// Opus encoder writing an Ogg container, resampling to 48 kHz
+///
+/// Tags are left empty, they are written into the OpusTags header afterwards
+#[cfg(feature = "opus")]
+struct OpusEncoder {
+	opus: Opus,
+	channels: usize,
+	resampler: Resampler,
+	/// Interleaved samples not yet encoded
+	samples: Vec<i16>,
+	/// Encoded packets not yet written to a page
+	packets: Vec<Vec<u8>>,
+	/// Samples per channel the decoder skips at the start
+	pre_skip: u16,
+	/// Sample rate of the source, kept in the header
+	input_rate: u32,
+	/// Samples per channel passed in, without padding
+	samples_in: u64,
+	/// Samples per channel of the encoded packets
+	samples_encoded: u64,
+	sequence: u32,
+	header_written: bool,
+}
+
+#[cfg(feature = "opus")]
+impl OpusEncoder {
+	fn new(sample_rate: u32, channels: u8, bitrate: Bitrate) -> Result<OpusEncoder, SpotifyError> {
+		let error = |e| SpotifyError::Error(format!("Opus error: {}", e));
+		let mut opus = Opus::new(OPUS_SAMPLE_RATE, channels).map_err(error)?;
+		opus.set_kilobitrate(bitrate.kbps()).map_err(error)?;
+		let pre_skip = opus.lookahead().map_err(error)?;
+		Ok(OpusEncoder {
+			opus,
+			channels: channels as usize,
+			resampler: Resampler::new(sample_rate, OPUS_SAMPLE_RATE, channels as usize),
+			samples: vec![],
+			packets: vec![],
+			pre_skip,
+			input_rate: sample_rate,
+			samples_in: 0,
+			samples_encoded: 0,
+			sequence: 0,
+			header_written: false,
+		})
+	}
+
+	fn encode_frame(&mut self, buffer: &mut Vec<u8>) -> std::io::Result<()> {
+		let frame: Vec<i16> = self
+			.samples
+			.drain(..OPUS_FRAME_SIZE * self.channels)
+			.collect();
+		// Largest packet recommended by libopus
+		let mut packet = vec![0; 4000];
+		let size = self
+			.opus
+			.encode(&frame, &mut packet)
+			.map_err(|e| Error::new(ErrorKind::InvalidData, format!("Opus error: {}", e)))?;
+		packet.truncate(size);
+
+		// Pages hold at most 255 lacing values
+		let segments: usize = self.packets.iter().map(|p| p.len() / 255 + 1).sum();
+		if segments + packet.len() / 255 + 1 > 255 {
+			self.write_page(0, self.pre_skip as u64 + self.samples_encoded, buffer);
+		}
+		self.packets.push(packet);
+		self.samples_encoded += OPUS_FRAME_SIZE as u64;
+		if self.packets.len() >= OPUS_PAGE_PACKETS {
+			self.write_page(0, self.pre_skip as u64 + self.samples_encoded, buffer);
+		}
+		Ok(())
+	}
+
+	/// Write the pending packets into a page ending at a granule position
+	fn write_page(&mut self, flags: u8, granule: u64, buffer: &mut Vec<u8>) {
+		let lacing: Vec<u8> = self
+			.packets
+			.iter()
+			.flat_map(|p| ogg_lacing(p.len()))
+			.collect();
+		let data = self.packets.concat();
+		buffer.extend(ogg_page(
+			flags,
+			granule,
+			OGG_SERIAL,
+			self.sequence,
+			&lacing,
+			&data,
+		));
+		self.sequence += 1;
+		self.packets.clear();
+	}
+
+	/// Write OpusHead and empty OpusTags headers
+	fn write_header(&mut self, buffer: &mut Vec<u8>) {
+		if self.header_written {
+			return;
+		}
+		self.header_written = true;
+
+		let mut head = b"OpusHead".to_vec();
+		// Version, channels, pre-skip, source sample rate, no output gain and mono or stereo mapping
+		head.push(1);
+		head.push(self.channels as u8);
+		head.extend_from_slice(&self.pre_skip.to_le_bytes());
+		head.extend_from_slice(&self.input_rate.to_le_bytes());
+		head.extend_from_slice(&0i16.to_le_bytes());
+		head.push(0);
+		buffer.extend(ogg_packet_pages(
+			OGG_FIRST,
+			0,
+			OGG_SERIAL,
+			&mut self.sequence,
+			&head,
+		));
+		buffer.extend(ogg_packet_pages(
+			0,
+			0,
+			OGG_SERIAL,
+			&mut self.sequence,
+			&opus_tags("DownOnSpot", &[]),
+		));
+	}
+}
+
+#[cfg(feature = "opus")]
+impl Encoder for OpusEncoder {
+	fn encode(&mut self, pcm: &[Vec<i16>], buffer: &mut Vec<u8>) -> std::io::Result<()> {
+		self.write_header(buffer);
+		// Only mono and stereo are written, more channels are mixed down
+		let pcm = match self.channels {
+			2 => stereo(pcm),
+			_ => Cow::Borrowed(pcm),
+		};
+		let resampled = self.resampler.process(&pcm);
+		self.samples_in += resampled[0].len() as u64;
+		// Interleave channels
+		for i in 0..resampled[0].len() {
+			for channel in resampled.iter().take(self.channels) {
+				self.samples.push(channel[i]);
+			}
+		}
+		while self.samples.len() >= OPUS_FRAME_SIZE * self.channels {
+			self.encode_frame(buffer)?;
+		}
+		Ok(())
+	}
+
+	fn flush(&mut self, buffer: &mut Vec<u8>) -> std::io::Result<()> {
+		self.write_header(buffer);
+		// Push the delayed samples out of the encoder and fill the last frame
+		let frame = OPUS_FRAME_SIZE * self.channels;
+		let padded = self.samples.len() + self.pre_skip as usize * self.channels;
+		self.samples.resize(padded.div_ceil(frame) * frame, 0);
+		while !self.samples.is_empty() {
+			self.encode_frame(buffer)?;
+		}
+		// The end of the padding is cut off by the granule position of the last page
+		self.write_page(OGG_LAST, self.pre_skip as u64 + self.samples_in, buffer);
+		Ok(())
+	}
+}
+
+/// Linear resampler of planar PCM, keeping the last samples between packets
+#[cfg(feature = "opus")]
+struct Resampler {
+	/// Source samples per output sample
+	step: f64,
+	/// Position of the next output sample, relative to the start of the next packet
+	position: f64,
+	/// Last sample of every channel of the previous packet
+	last: Vec<i16>,
+}
+
+#[cfg(feature = "opus")]
+impl Resampler {
+	fn new(from: u32, to: u32, channels: usize) -> Resampler {
+		Resampler {
+			step: from as f64 / to as f64,
+			position: 0.0,
+			last: vec![0; channels],
+		}
+	}
+
+	fn process(&mut self, pcm: &[Vec<i16>]) -> Vec<Vec<i16>> {
+		let length = pcm[0].len();
+		let mut out = vec![vec![]; pcm.len()];
+		if length == 0 {
+			return out;
+		}
+		// Sample at index -1 is the last one of the previous packet
+		let sample = |channel: usize, i: isize| match i {
+			-1 => self.last[channel] as f64,
+			i => pcm[channel][i as usize] as f64,
+		};
+		while self.position < (length - 1) as f64 {
+			let i = self.position.floor();
+			let fraction = self.position - i;
+			for (channel, out) in out.iter_mut().enumerate() {
+				let a = sample(channel, i as isize);
+				let b = sample(channel, i as isize + 1);
+				out.push((a + (b - a) * fraction).round() as i16);
+			}
+			self.position += self.step;
+		}
+		self.position -= length as f64;
+		for (last, channel) in self.last.iter_mut().zip(pcm) {
+			*last = channel[length - 1];
+		}
+		out
+	}
+}
+
+/// OpusTags header packet with a vendor and Vorbis comments
+pub(crate) fn opus_tags(vendor: &str, comments: &[String]) -> Vec<u8> {
+	let mut tags = b"OpusTags".to_vec();
+	tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+	tags.extend_from_slice(vendor.as_bytes());
+	tags.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+	for comment in comments {
+		tags.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+		tags.extend_from_slice(comment.as_bytes());
+	}
+	tags
+}
+
+/// Lacing values of an Ogg packet
+pub(crate) fn ogg_lacing(size: usize) -> Vec<u8> {
+	let mut lacing = vec![255; size / 255];
+	lacing.push((size % 255) as u8);
+	lacing
+}
+
+/// Ogg page with the data of its lacing values
+pub(crate) fn ogg_page(
+	flags: u8,
+	granule: u64,
+	serial: u32,
+	sequence: u32,
+	lacing: &[u8],
+	data: &[u8],
+) -> Vec<u8> {
+	let mut page = b"OggS".to_vec();
+	page.push(0);
+	page.push(flags);
+	page.extend_from_slice(&granule.to_le_bytes());
+	page.extend_from_slice(&serial.to_le_bytes());
+	page.extend_from_slice(&sequence.to_le_bytes());
+	// Checksum is calculated with zeros in its place
+	page.extend_from_slice(&[0; 4]);
+	page.push(lacing.len() as u8);
+	page.extend_from_slice(lacing);
+	page.extend_from_slice(data);
+	let crc = ogg_crc(&page);
+	page[22..26].copy_from_slice(&crc.to_le_bytes());
+	page
+}
+
+/// Pages of a single packet, continued over several pages if it does not fit into one
+///
+/// The first page gets the OGG_FIRST flag and the last one OGG_LAST and the granule position
+pub(crate) fn ogg_packet_pages(
+	flags: u8,
+	granule: u64,
+	serial: u32,
+	sequence: &mut u32,
+	packet: &[u8],
+) -> Vec<u8> {
+	let lacing = ogg_lacing(packet.len());
+	let count = lacing.len().div_ceil(255);
+	let mut pages = vec![];
+	let mut data = packet;
+	for (i, segments) in lacing.chunks(255).enumerate() {
+		let last = i + 1 == count;
+		let size = segments.iter().map(|s| *s as usize).sum();
+		let page_flags = match i {
+			0 => flags & OGG_FIRST,
+			_ => OGG_CONTINUED,
+		} | if last { flags & OGG_LAST } else { 0 };
+		// Pages without the end of a packet have no granule position
+		let page_granule = if last { granule } else { u64::MAX };
+		pages.extend(ogg_page(
+			page_flags,
+			page_granule,
+			serial,
+			*sequence,
+			segments,
+			&data[..size],
+		));
+		data = &data[size..];
+		*sequence += 1;
+	}
+	pages
+}
+
+/// CRC-32 of Ogg pages with polynomial 0x04c11db7
+pub(crate) fn ogg_crc(data: &[u8]) -> u32 {
+	let mut crc = 0u32;
+	for byte in data {
+		crc ^= (*byte as u32) << 24;
+		for _ in 0..8 {
+			crc = if crc & 0x8000_0000 != 0 {
+				(crc << 1) ^ 0x04c1_1db7
+			} else {
+				crc << 1
+			};
+		}
+	}
+	crc
+}
+
+/// Write the final chunk sizes into the header of a WAV file ( BLOCKING )
+pub(crate) fn finalize_wav(path: impl AsRef<Path>) -> Result<(), SpotifyError> {
+	let mut file = OpenOptions::new().write(true).open(path)?;
+	let size = file.metadata()?.len();
+	// RIFF sizes are 32 bit, the header alone is 44 bytes
+	let size = u32::try_from(size)
+		.map_err(|_| SpotifyError::Error(format!("WAV file of {} bytes is too large", size)))?;
+	let data_size = size.checked_sub(44).ok_or(InvalidFormat)?;
+
+	file.seek(SeekFrom::Start(4))?;
+	file.write_all(&(size - 8).to_le_bytes())?;
+	file.seek(SeekFrom::Start(40))?;
+	file.write_all(&data_size.to_le_bytes())?;
+	Ok(())
+}
+
+/// Encode frame number the way FLAC does, which extends UTF-8
+fn flac_utf8(number: u32) -> Vec<u8> {
+	if number < 0x80 {
+		return vec![number as u8];
+	}
+
+	let length = match number {
+		0x80..=0x7ff => 2,
+		0x800..=0xffff => 3,
+		0x1_0000..=0x1f_ffff => 4,
+		0x20_0000..=0x3ff_ffff => 5,
+		_ => 6,
+	};
+	let mut number = number;
+	let mut bytes = vec![];
+	for _ in 1..length {
+		bytes.push(0x80 | (number & 0x3f) as u8);
+		number >>= 6;
+	}
+	bytes.push(((0xff00u16 >> length) as u8) | number as u8);
+	bytes.reverse();
+	bytes
+}
+
+/// CRC-8 with polynomial x^8 + x^2 + x^1 + x^0
+fn crc8(data: &[u8]) -> u8 {
+	let mut crc = 0u8;
+	for byte in data {
+		crc ^= byte;
+		for _ in 0..8 {
+			crc = if crc & 0x80 != 0 {
+				(crc << 1) ^ 0x07
+			} else {
+				crc << 1
+			};
+		}
+	}
+	crc
+}
+
+/// CRC-16 with polynomial x^16 + x^15 + x^2 + x^0
+fn crc16(data: &[u8]) -> u16 {
+	let mut crc = 0u16;
+	for byte in data {
+		crc ^= (*byte as u16) << 8;
+		for _ in 0..8 {
+			crc = if crc & 0x8000 != 0 {
+				(crc << 1) ^ 0x8005
+			} else {
+				crc << 1
+			};
+		}
+	}
+	crc
+}
+
+pub(crate) struct ReadWrap {
+	source: Box<dyn Read + Send + 'static>,
 }
 
 impl ReadWrap {
-	pub fn new(read: Box<(dyn Read + Send + 'static)>) -> ReadWrap {
+	pub fn new(read: Box<dyn Read + Send + 'static>) -> ReadWrap {
 		ReadWrap {
 			source: Box::new(read),
 		}
@@ -153,3 +1238,281 @@ impl Seek for ReadWrap {
 		Ok(0)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::path::PathBuf;
+
+	/// Half a second of a stereo tone at 44.1 kHz
+	const STEREO_OGG: &[u8] = include_bytes!("../tests/fixtures/stereo.ogg");
+	/// Half a second of a mono tone at 44.1 kHz
+	const MONO_OGG: &[u8] = include_bytes!("../tests/fixtures/mono.ogg");
+
+	/// Convert ogg and write the header over the start, as the downloader does,
+	/// returning the output and the decoded samples per channel
+	fn convert(ogg: &'static [u8], output: OutputFormat) -> (Vec<u8>, u64) {
+		let options = ConvertOptions {
+			output,
+			bitrate: Bitrate::B320,
+			mp3_quality: 2,
+			vbr: None,
+			normalize: false,
+			dither: false,
+		};
+		let mut converter =
+			AudioConverter::new(Box::new(ogg), AudioFormat::Ogg, options, None).unwrap();
+		let mut encoded = vec![];
+		converter.read_to_end(&mut encoded).unwrap();
+		if let Some(header) = converter.header() {
+			encoded[..header.len()].copy_from_slice(&header);
+		}
+		(encoded, converter.samples)
+	}
+
+	/// Decoded samples of ogg, interleaved
+	fn decode(ogg: &'static [u8]) -> Vec<i16> {
+		let mut decoder = OggStreamReader::new(std::io::Cursor::new(ogg)).unwrap();
+		let mut samples = vec![];
+		while let Some(packet) = decoder.read_dec_packet_itl().unwrap() {
+			samples.extend(packet);
+		}
+		samples
+	}
+
+	/// Samples of the MPEG-1 Layer III frames in mp3, without the delay and padding in the LAME tag
+	fn mp3_samples(mp3: &[u8]) -> u64 {
+		const BITRATES: [u64; 15] = [
+			0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+		];
+		const SAMPLE_RATES: [u64; 3] = [44100, 48000, 32000];
+		let (mut position, mut frames, mut gap) = (0, 0, 0);
+		while position + 4 <= mp3.len() {
+			let header = &mp3[position..position + 4];
+			assert_eq!(
+				(header[0], header[1] & 0xfe),
+				(0xff, 0xfa),
+				"no frame at {}",
+				position
+			);
+			let bitrate = BITRATES[(header[2] >> 4) as usize] * 1000;
+			let sample_rate = SAMPLE_RATES[(header[2] >> 2 & 0x03) as usize];
+			let padding = (header[2] >> 1 & 0x01) as usize;
+			let length = (144 * bitrate / sample_rate) as usize + padding;
+			let frame = &mp3[position..(position + length).min(mp3.len())];
+			match frame.windows(4).position(|w| w == b"Info" || w == b"Xing") {
+				// Encoder delay and padding, 12 bits each
+				Some(tag) if position == 0 => {
+					let delay = &frame[tag + 141..tag + 144];
+					gap = ((delay[0] as u64) << 4 | (delay[1] as u64) >> 4)
+						+ (((delay[1] & 0x0f) as u64) << 8 | delay[2] as u64);
+				}
+				_ => frames += 1,
+			}
+			position += length;
+		}
+		frames * 1152 - gap
+	}
+
+	/// Path in the temporary directory, unique to this process
+	fn temp_path(name: &str) -> PathBuf {
+		std::env::temp_dir().join(format!("down_on_spot_{}_{}", std::process::id(), name))
+	}
+
+	#[test]
+	fn finalize_wav_writes_sizes() {
+		let mut wav = vec![];
+		let mut encoder = WavEncoder::new(44100, 2);
+		encoder.encode(&[vec![1, 2], vec![3, 4]], &mut wav).unwrap();
+		encoder.flush(&mut wav).unwrap();
+		let path = temp_path("finalize.wav");
+		std::fs::write(&path, &wav).unwrap();
+
+		finalize_wav(&path).unwrap();
+		let wav = std::fs::read(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(wav.len(), 52);
+		assert_eq!(&wav[4..8], &44u32.to_le_bytes());
+		assert_eq!(&wav[40..44], &8u32.to_le_bytes());
+	}
+
+	#[test]
+	fn finalize_wav_rejects_truncated_file() {
+		let path = temp_path("truncated.wav");
+		std::fs::write(&path, b"RIFF").unwrap();
+		let result = finalize_wav(&path);
+		std::fs::remove_file(&path).unwrap();
+		assert!(matches!(result, Err(SpotifyError::InvalidFormat)));
+	}
+
+	#[test]
+	fn wav_holds_decoded_samples() {
+		let (wav, samples) = convert(STEREO_OGG, OutputFormat::Wav);
+		assert_eq!(wav.len() as u64, 44 + samples * 4);
+		assert_eq!(&wav[..4], b"RIFF");
+		assert_eq!(&wav[8..16], b"WAVEfmt ");
+		assert_eq!(&wav[22..28], &[2, 0, 0x44, 0xac, 0, 0]);
+		let data: Vec<i16> = wav[44..]
+			.chunks(2)
+			.map(|s| i16::from_le_bytes([s[0], s[1]]))
+			.collect();
+		assert_eq!(data, decode(STEREO_OGG));
+	}
+
+	#[test]
+	fn flac_holds_decoded_samples() {
+		let (flac, samples) = convert(STEREO_OGG, OutputFormat::Flac);
+		let mut reader = claxon::FlacReader::new(std::io::Cursor::new(flac)).unwrap();
+		assert_eq!(reader.streaminfo().samples, Some(samples));
+		let decoded: Vec<i16> = reader.samples().map(|s| s.unwrap() as i16).collect();
+		assert_eq!(decoded, decode(STEREO_OGG));
+	}
+
+	#[test]
+	fn mp3_starts_with_info_frame() {
+		let (mp3, _) = convert(STEREO_OGG, OutputFormat::Mp3);
+		// Constant bitrate of 320 kbit/s at 44.1 kHz
+		assert_eq!(&mp3[..3], &[0xff, 0xfb, 0xe0]);
+		assert!(mp3[..1044].windows(4).any(|w| w == b"Info"));
+	}
+
+	#[cfg(feature = "opus")]
+	#[test]
+	fn opus_starts_with_head() {
+		let (opus, _) = convert(STEREO_OGG, OutputFormat::Opus);
+		assert_eq!(&opus[..4], b"OggS");
+		assert_eq!(&opus[28..36], b"OpusHead");
+	}
+
+	/// STEREO_OGG behind a header of size
+	fn with_header(size: usize) -> std::io::Cursor<Vec<u8>> {
+		let mut file = vec![0; size];
+		file.extend_from_slice(STEREO_OGG);
+		std::io::Cursor::new(file)
+	}
+
+	#[test]
+	fn ogg_start_after_spotify_header() {
+		let mut file = with_header(SPOTIFY_OGG_HEADER_END);
+		let start = find_ogg_start(&mut file).unwrap();
+		assert_eq!(start, SPOTIFY_OGG_HEADER_END as u64);
+		assert_eq!(file.position(), 0);
+	}
+
+	#[test]
+	fn ogg_start_searched_in_other_header() {
+		let mut file = with_header(0x100);
+		let start = find_ogg_start(&mut file).unwrap();
+		assert_eq!(start, 0x100);
+
+		// What follows is a valid Vorbis stream
+		file.seek(SeekFrom::Start(start)).unwrap();
+		let mut decoder = OggStreamReader::new(file).unwrap();
+		assert_eq!(decoder.ident_hdr.audio_channels, 2);
+		assert!(decoder.read_dec_packet().unwrap().is_some());
+	}
+
+	#[test]
+	fn ogg_start_missing() {
+		let mut file = std::io::Cursor::new(vec![0; OGG_SEARCH_SIZE as usize * 2]);
+		let error = find_ogg_start(&mut file).unwrap_err();
+		assert_eq!(error.kind(), ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn stereo_duplicates_mono() {
+		let mono = vec![vec![1, -2, 3]];
+		assert_eq!(&stereo(&mono)[..], &[vec![1, -2, 3], vec![1, -2, 3]]);
+	}
+
+	#[test]
+	fn stereo_downmixes_surround() {
+		let surround = vec![vec![100; 5]; 6];
+		let downmix = stereo(&surround);
+		assert_eq!(downmix.len(), 2);
+		assert!(downmix.iter().all(|channel| channel.len() == 5));
+	}
+
+	#[test]
+	fn mono_converts_to_stereo_mp3() {
+		let (mp3, samples) = convert(MONO_OGG, OutputFormat::Mp3);
+		// 44.1 kHz and not single channel
+		assert_eq!(mp3[2] >> 2 & 0x03, 0);
+		assert_ne!(mp3[3] >> 6, 3);
+		assert!(mp3_samples(&mp3).abs_diff(samples) < 1152);
+	}
+
+	#[test]
+	fn mp3_keeps_duration() {
+		let (mp3, samples) = convert(STEREO_OGG, OutputFormat::Mp3);
+		let encoded = mp3_samples(&mp3);
+		assert!(
+			encoded.abs_diff(samples) < 1152,
+			"{} of {} samples",
+			encoded,
+			samples
+		);
+	}
+
+	#[test]
+	fn flac_round_trip() {
+		// Silence, a tone and noise, for constant, fixed and verbatim subframes
+		let count = FLAC_BLOCK_SIZE * 2 + 100;
+		let mut noise = 1u32;
+		let left: Vec<i16> = (0..count)
+			.map(|i| match i / FLAC_BLOCK_SIZE {
+				0 => 0,
+				1 => ((i as f32 * 0.05).sin() * 10000.0) as i16,
+				_ => {
+					noise = noise.wrapping_mul(1664525).wrapping_add(1013904223);
+					(noise >> 16) as i16
+				}
+			})
+			.collect();
+		let right: Vec<i16> = left.iter().map(|s| s / 2 - 3).collect();
+
+		let mut flac = vec![];
+		let mut encoder = FlacEncoder::new(44100, 2);
+		for (left, right) in left.chunks(1000).zip(right.chunks(1000)) {
+			encoder
+				.encode(&[left.to_vec(), right.to_vec()], &mut flac)
+				.unwrap();
+		}
+		encoder.flush(&mut flac).unwrap();
+		let header = encoder.header().unwrap();
+		flac[..header.len()].copy_from_slice(&header);
+		assert!(flac.len() < count * 4);
+
+		let mut reader = claxon::FlacReader::new(std::io::Cursor::new(flac)).unwrap();
+		let info = reader.streaminfo();
+		assert_eq!(info.samples, Some(count as u64));
+		assert_eq!(info.channels, 2);
+		assert_eq!(info.sample_rate, 44100);
+		assert!(info.min_frame_size.unwrap() <= info.max_frame_size.unwrap());
+		let decoded: Vec<i32> = reader.samples().map(|s| s.unwrap()).collect();
+		let expected: Vec<i32> = left
+			.iter()
+			.zip(&right)
+			.flat_map(|(l, r)| [*l as i32, *r as i32])
+			.collect();
+		assert_eq!(decoded, expected);
+	}
+
+	#[test]
+	fn flac_mono_round_trip() {
+		let samples: Vec<i16> = (0..300).map(|i| (i * i % 2000) as i16 - 1000).collect();
+		let mut flac = vec![];
+		let mut encoder = FlacEncoder::new(22050, 1);
+		encoder
+			.encode(std::slice::from_ref(&samples), &mut flac)
+			.unwrap();
+		encoder.flush(&mut flac).unwrap();
+
+		let mut reader = claxon::FlacReader::new(std::io::Cursor::new(flac)).unwrap();
+		let decoded: Vec<i32> = reader.samples().map(|s| s.unwrap()).collect();
+		assert_eq!(
+			decoded,
+			samples.iter().map(|s| *s as i32).collect::<Vec<_>>()
+		);
+	}
+}
@@ -1,25 +1,38 @@
 use async_std::channel::{bounded, Receiver, Sender};
-use async_stream::try_stream;
-use chrono::NaiveDate;
+use async_std::task;
+use async_stream::{stream, try_stream};
+use chrono::{DateTime, Datelike, Utc};
+use clap::ValueEnum;
 use futures::stream::FuturesUnordered;
 use futures::{pin_mut, select, FutureExt, Stream, StreamExt};
 use librespot::audio::{AudioDecrypt, AudioFile};
 use librespot::core::audio_key::AudioKey;
 use librespot::core::session::Session;
-use librespot::core::spotify_id::SpotifyId;
-use librespot::metadata::{FileFormat, Metadata, Track};
-use sanitize_filename::sanitize;
+use librespot::core::spotify_id::{FileId, SpotifyId};
+use librespot::metadata::{Album, Artist, FileFormat, Metadata, Track};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
-use std::io::Read;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::OnceCell;
 
-use crate::converter::AudioConverter;
+use crate::converter::{self, AudioConverter, ConvertOptions};
 use crate::error::SpotifyError;
-use crate::spotify::{Spotify, SpotifyItem};
-use crate::tag::{Field, TagWrap};
+use crate::limiter::RateLimiter;
+use crate::lyrics::Lyrics;
+use crate::naming;
+use crate::spotify::{AlbumGroup, SearchType, Spotify, SpotifyItem};
+use crate::tag::{Field, ReleaseDate, TagWrap};
+
+/// How often download_paths and download_with_callback check the queue for changes
+const UPDATES_POLL_INTERVAL: Duration = Duration::from_millis(250);
 
 /// Wrapper for use with UI
 #[derive(Debug, Clone)]
@@ -27,7 +40,9 @@ pub struct Downloader {
 	rx: Receiver<Response>,
 	tx: Sender<Message>,
 
-	spotify: Spotify,
+	spotify: Arc<SpotifyHandle>,
+	config: DownloaderConfig,
+	control: Arc<DownloadControl>,
 }
 impl Downloader {
 	/// Create new instance
@@ -36,83 +51,359 @@ impl Downloader {
 		let (tx_1, rx_1) = bounded(1);
 
 		let tx_clone = tx_1.clone();
+		let spotify = Arc::new(SpotifyHandle::new(spotify));
 		let spotify_clone = spotify.clone();
+		let config_clone = config.clone();
+		let control = Arc::new(DownloadControl {
+			limiter: config.max_rate.map(RateLimiter::new),
+			cancelled: AtomicBool::new(false),
+			existing: OnceCell::new(),
+		});
+		let control_clone = control.clone();
 		tokio::spawn(async move {
-			communication_thread(config, spotify_clone, rx_1, tx_0, tx_clone).await
+			communication_thread(
+				config_clone,
+				spotify_clone,
+				control_clone,
+				rx_1,
+				tx_0,
+				tx_clone,
+			)
+			.await
 		});
 		Downloader {
 			rx: rx_0,
 			tx: tx_1,
 			spotify,
+			config,
+			control,
 		}
 	}
-	/// Add item to download queue
-	pub async fn add_to_queue(&self, download: Download) {
+
+	/// Create the output directory and check that it is writable
+	/// Parse URI or URL into URI, following the redirect of shortened URLs
+	pub async fn parse_input(&self, input: &str) -> Result<String, SpotifyError> {
+		self.spotify.get().parse_input(input).await
+	}
+
+	pub async fn check_output_dir(&self) -> Result<PathBuf, SpotifyError> {
+		let root = naming::template_root(&self.config.path);
+		let error = |e: std::io::Error| {
+			SpotifyError::IoError(e.kind(), format!("{}: {}", root.display(), e))
+		};
+		tokio::fs::create_dir_all(&root).await.map_err(error)?;
+		let probe = root.join(".down_on_spot_write_test");
+		tokio::fs::write(&probe, b"").await.map_err(error)?;
+		tokio::fs::remove_file(&probe).await.map_err(error)?;
+		Ok(root)
+	}
+
+	/// Cancel all downloads, removing their partial files
+	pub async fn cancel(&self) {
+		self.control.cancelled.store(true, Ordering::SeqCst);
+		self.tx.send(Message::Cancel).await.unwrap();
+	}
+
+	/// Whether the downloads were cancelled, or stopped after a failure in strict mode
+	pub fn is_cancelled(&self) -> bool {
+		self.control.cancelled.load(Ordering::SeqCst)
+	}
+
+	/// Add multiple items to queue
+	pub async fn add_to_queue_multiple(&self, downloads: Vec<Download>) {
 		self.tx
-			.send(Message::AddToQueue(vec![download]))
+			.send(Message::AddToQueue(downloads, None))
 			.await
 			.unwrap();
 	}
 
-	/// Add multiple items to queue
-	pub async fn add_to_queue_multiple(&self, downloads: Vec<Download>) {
-		self.tx.send(Message::AddToQueue(downloads)).await.unwrap();
+	/// Add the tracks of a playlist to queue
+	pub async fn add_playlist_to_queue(&self, name: String, downloads: Vec<Download>) {
+		self.tx
+			.send(Message::AddToQueue(downloads, Some(name)))
+			.await
+			.unwrap();
 	}
 
 	/// handle input, either link or search
 	pub async fn handle_input(
 		&self,
 		input: &str,
+		search_type: SearchType,
 	) -> Result<Option<Vec<SearchResult>>, SpotifyError> {
-		if let Ok(uri) = Spotify::parse_uri(input) {
+		let spotify = self.spotify.connected().await?;
+		// Tracks of an ISRC, added right away if it is unique
+		if let Some(isrc) = Spotify::parse_isrc(input) {
+			let tracks = spotify.search_isrc(&isrc).await?;
+			info!("Found {} tracks with ISRC {}", tracks.len(), isrc);
+			let results: Vec<SearchResult> = tracks.into_iter().map(SearchResult::from).collect();
+			if let [result] = results.as_slice() {
+				self.add_uri(&result.uri).await?;
+				return Ok(None);
+			}
+			return Ok(Some(results));
+		}
+		let query = Spotify::parse_search(input);
+		if let (None, Ok(uri)) = (&query, spotify.parse_input(input).await) {
 			self.add_uri(&uri).await?;
 			Ok(None)
 		} else {
-			let results: Vec<SearchResult> = self
-				.spotify
-				.search(input)
-				.await?
-				.into_iter()
-				.map(SearchResult::from)
-				.collect();
+			let query = query.as_deref().unwrap_or(input);
+			let results = spotify.search(query, search_type).await?;
+			let mut search_results: Vec<SearchResult> = vec![];
+			if let Some(tracks) = results.tracks {
+				search_results.extend(tracks.items.into_iter().map(SearchResult::from));
+			}
+			if let Some(albums) = results.albums {
+				search_results.extend(albums.items.into_iter().filter_map(|a| {
+					Some(SearchResult {
+						uri: format!("spotify:album:{}", a.id?),
+						author: a
+							.artists
+							.first()
+							.map(|a| a.name.to_owned())
+							.unwrap_or_default(),
+						title: a.name,
+					})
+				}));
+			}
+			if let Some(playlists) = results.playlists {
+				search_results.extend(playlists.items.into_iter().map(SearchResult::from));
+			}
 
-			Ok(Some(results))
+			Ok(Some(search_results))
 		}
 	}
 
 	/// Add URL or URI to queue
 	pub async fn add_uri(&self, uri: &str) -> Result<(), SpotifyError> {
-		let uri = Spotify::parse_uri(uri)?;
-		let item = self.spotify.resolve_uri(&uri).await?;
+		match self.resolve_downloads(uri).await? {
+			(downloads, Some(playlist)) => self.add_playlist_to_queue(playlist, downloads).await,
+			(downloads, None) => self.add_to_queue_multiple(downloads).await,
+		}
+		Ok(())
+	}
+
+	/// Resolve URL or URI into downloads, with the name of the playlist if it is one
+	async fn resolve_downloads(
+		&self,
+		uri: &str,
+	) -> Result<(Vec<Download>, Option<String>), SpotifyError> {
+		let spotify = self.spotify.connected().await?;
+		let uri = spotify.parse_input(uri).await?;
+		let item = spotify.resolve_uri(&uri).await?;
+		info!("Resolved {}", item);
 		match item {
 			SpotifyItem::Track(t) => {
-				if !t.is_local {
-					self.add_to_queue(t.into()).await;
+				if t.is_local {
+					return Ok((vec![], None));
 				}
+				Ok((
+					number_tracks(&self.config, vec![(0, t.into())], 1, false),
+					None,
+				))
 			}
 			SpotifyItem::Album(a) => {
-				let tracks = self.spotify.full_album(&a.id).await?;
-				let queue: Vec<Download> = tracks.into_iter().map(|t| t.into()).collect();
-				self.add_to_queue_multiple(queue).await;
+				let tracks = spotify
+					.album_tracks(&a.id, self.config.offset, self.config.limit)
+					.await?;
+				let tracks = tracks
+					.into_iter()
+					.enumerate()
+					.map(|(i, t)| (self.config.offset + i, t.into()))
+					.collect();
+				Ok((
+					number_tracks(&self.config, tracks, a.tracks.total, false),
+					None,
+				))
 			}
 			SpotifyItem::Playlist(p) => {
-				let tracks = self.spotify.full_playlist(&p.id).await?;
-				let queue: Vec<Download> = tracks.into_iter().map(|t| t.into()).collect();
-				self.add_to_queue_multiple(queue).await;
+				let tracks = spotify
+					.playlist_items(&p.id, self.config.offset, self.config.limit)
+					.await?;
+				let tracks = tracks
+					.into_iter()
+					.filter(|(_, added_at, t)| match self.config.added_after {
+						Some(after) => {
+							let keep = added_at.is_some_and(|added_at| added_at > after);
+							if !keep {
+								debug!("Skipping {}, added before {}", t.name, after);
+							}
+							keep
+						}
+						None => true,
+					})
+					.map(|(p, _, t)| (p, t.into()))
+					.collect();
+				Ok((
+					number_tracks(&self.config, tracks, p.tracks.total, true),
+					Some(p.name),
+				))
 			}
 			SpotifyItem::Artist(a) => {
-				let tracks = self.spotify.full_artist(&a.id).await?;
-				let queue: Vec<Download> = tracks.into_iter().map(|t| t.into()).collect();
-				self.add_to_queue_multiple(queue).await;
+				let tracks = spotify
+					.full_artist(
+						&a.id,
+						&self.config.artist_groups,
+						self.config.concurrent_downloads,
+					)
+					.await?;
+				let total = tracks.len();
+				let tracks = window(tracks, self.config.offset, self.config.limit)
+					.into_iter()
+					.map(|(i, t)| (i, t.into()))
+					.collect();
+				Ok((number_tracks(&self.config, tracks, total, false), None))
+			}
+			SpotifyItem::Liked => {
+				let tracks = spotify
+					.liked_tracks(self.config.offset, self.config.limit)
+					.await?;
+				let total = self.config.offset + tracks.len();
+				let tracks = tracks
+					.into_iter()
+					.enumerate()
+					.filter(|(_, t)| !t.is_local)
+					.map(|(i, t)| (self.config.offset + i, t.into()))
+					.collect();
+				Ok((
+					number_tracks(&self.config, tracks, total, true),
+					Some("Liked Songs".to_string()),
+				))
 			}
 
 			// Unsupported
 			SpotifyItem::Other(u) => {
 				error!("Unsupported URI: {}", u);
-				return Err(SpotifyError::Unavailable);
+				Err(SpotifyError::Unavailable)
 			}
-		};
-		Ok(())
+		}
+	}
+
+	/// Resolve what would be downloaded for URL or URI, without downloading anything
+	pub async fn plan_uri(
+		&self,
+		uri: &str,
+	) -> Result<Vec<(Download, Result<PlannedDownload, SpotifyError>)>, SpotifyError> {
+		let (downloads, _) = self.resolve_downloads(uri).await?;
+		let mut planned = vec![];
+		for download in downloads {
+			let plan = self.plan_download(&download).await;
+			planned.push((download, plan));
+		}
+		Ok(planned)
+	}
+
+	/// Resolve the file a track would be downloaded from, without requesting its key or audio
+	pub async fn resolve(&self, track_id: &str) -> Result<ResolvedFile, SpotifyError> {
+		let spotify = self.spotify.connected().await?;
+		let track = DownloaderInternal::get_track(&spotify.session, track_id).await?;
+		let (quality, file_id, file_format) =
+			DownloaderInternal::select_file(&track, &self.config)?;
+		let file =
+			AudioFile::open(&spotify.session, file_id, self.config.read_ahead, false).await?;
+		let source = AudioFormat::from(file_format);
+		Ok(ResolvedFile {
+			quality,
+			format: DownloaderInternal::output_format(&self.config, source.clone()),
+			source,
+			size: file.get_stream_loader_controller().len(),
+		})
+	}
+
+	/// Download a track into a writer without tagging, returning the format of the written audio
+	///
+	/// WAV headers and the Xing frame of MP3 files written this way keep placeholders,
+	/// as the writer can not be rewound
+	pub async fn download_to<W: AsyncWrite + Unpin + Send>(
+		&self,
+		track_id: &str,
+		sink: W,
+	) -> Result<AudioFormat, SpotifyError> {
+		let spotify = self.spotify.connected().await?;
+		let track = DownloaderInternal::get_track(&spotify.session, track_id).await?;
+		let file = DownloaderInternal::select_file(&track, &self.config)?;
+		let format = DownloaderInternal::output_format(&self.config, file.2.into());
+		DownloaderInternal::write_track(
+			&spotify,
+			&track,
+			file,
+			&self.config,
+			sink,
+			0,
+			None,
+			&self.control,
+		)
+		.await?;
+		Ok(format)
+	}
+
+	/// Report the available formats of the tracks of an URL or URI and the file
+	/// each quality setting would pick, without downloading anything
+	pub async fn audit_uri(
+		&self,
+		uri: &str,
+	) -> Result<Vec<(Download, Result<QualityReport, SpotifyError>)>, SpotifyError> {
+		let (downloads, _) = self.resolve_downloads(uri).await?;
+		let reports = futures::stream::iter(downloads)
+			.map(|download| async move {
+				let report = self.audit(&download.track_id).await;
+				(download, report)
+			})
+			.buffered(self.config.concurrent_downloads.max(1))
+			.collect()
+			.await;
+		Ok(reports)
+	}
+
+	/// Report the available formats of a track and the file each quality setting would pick
+	pub async fn audit(&self, track_id: &str) -> Result<QualityReport, SpotifyError> {
+		let spotify = self.spotify.connected().await?;
+		let track = DownloaderInternal::get_track(&spotify.session, track_id).await?;
+		Ok(QualityReport::new(
+			&track,
+			self.config.quality,
+			self.config.min_quality,
+		))
+	}
+
+	/// Resolve the format and path of a download without fetching the audio
+	async fn plan_download(&self, download: &Download) -> Result<PlannedDownload, SpotifyError> {
+		let spotify = self.spotify.connected().await?;
+		let mut track = spotify.track(&download.track_id).await?;
+		DownloaderInternal::apply_metadata_source(
+			&spotify.session,
+			self.config.metadata_source,
+			&mut track,
+		)
+		.await?;
+		let album = spotify
+			.album(track.album.id.as_deref().ok_or(SpotifyError::NotFound)?)
+			.await?;
+
+		let values = naming::template_values(
+			&download.track_id,
+			download.playlist_index.as_deref(),
+			&track,
+			&album,
+			self.config.artists_in_order,
+		);
+		let (path_template, filename_template) = self.config.templates();
+		let path = naming::track_path(&path_template, &filename_template, &values);
+
+		let metadata = DownloaderInternal::get_track(&spotify.session, &download.track_id).await?;
+		let (_, _, file_format) = DownloaderInternal::select_file(&metadata, &self.config)?;
+		let format = DownloaderInternal::output_format(&self.config, file_format.into());
+
+		let artists: Vec<&str> = track.artists.iter().map(|a| a.name.as_str()).collect();
+		Ok(PlannedDownload {
+			artist: match artists.is_empty() {
+				true => naming::UNKNOWN_ARTIST.to_string(),
+				false => artists.join(&self.config.separator),
+			},
+			path: DownloaderInternal::path_with_extension(path, &format),
+			format,
+		})
 	}
 
 	/// Get all downloads
@@ -121,33 +412,166 @@ impl Downloader {
 		let Response::Downloads(d) = self.rx.recv().await.unwrap();
 		d
 	}
+
+	/// Queue URL or URI and yield the path of every track once it is downloaded
+	///
+	/// Tracks skipped as already downloaded yield the existing file, other skipped tracks
+	/// yield nothing and failed ones yield their error. Ends when every track is finished
+	pub fn download_paths<'a>(
+		&'a self,
+		uri: &'a str,
+	) -> impl Stream<Item = Result<PathBuf, SpotifyError>> + 'a {
+		self.download_updates(uri).filter_map(|update| async move {
+			let download = match update {
+				Ok(download) => download,
+				Err(e) => return Some(Err(e)),
+			};
+			match (download.state, download.path) {
+				(DownloadState::Done | DownloadState::Skipped(_), Some(path)) => Some(Ok(path)),
+				(DownloadState::Error(e), _) => Some(Err(SpotifyError::Error(e))),
+				_ => None,
+			}
+		})
+	}
+
+	/// Download URL or URI to completion, calling on_progress whenever the state of one of its tracks changes
+	///
+	/// For embedders which can not poll a stream. Returns the finished downloads
+	pub async fn download_with_callback(
+		&self,
+		uri: &str,
+		mut on_progress: impl FnMut(&Download),
+	) -> Result<Vec<Download>, SpotifyError> {
+		let updates = self.download_updates(uri);
+		pin_mut!(updates);
+		let mut finished = vec![];
+		while let Some(download) = updates.next().await {
+			let download = download?;
+			on_progress(&download);
+			if download.state.is_final() {
+				finished.push(download);
+			}
+		}
+		Ok(finished)
+	}
+
+	/// Queue URL or URI and yield its downloads whenever their state changes
+	///
+	/// Ends when every track is finished
+	fn download_updates<'a>(
+		&'a self,
+		uri: &'a str,
+	) -> impl Stream<Item = Result<Download, SpotifyError>> + 'a {
+		stream! {
+			let (downloads, playlist) = match self.resolve_downloads(uri).await {
+				Ok(resolved) => resolved,
+				Err(e) => {
+					yield Err(e);
+					return;
+				}
+			};
+			let mut pending: HashSet<String> =
+				downloads.iter().map(|d| d.track_id.clone()).collect();
+			match playlist {
+				Some(name) => self.add_playlist_to_queue(name, downloads).await,
+				None => self.add_to_queue_multiple(downloads).await,
+			}
+
+			let mut states: HashMap<String, DownloadState> = HashMap::new();
+			while !pending.is_empty() {
+				task::sleep(UPDATES_POLL_INTERVAL).await;
+				let queue = self.get_downloads().await;
+				// Latest download of each track, earlier ones are from other inputs
+				let mut latest: HashMap<&str, &Download> = HashMap::new();
+				for download in queue.iter().filter(|d| pending.contains(&d.track_id)) {
+					latest.insert(&download.track_id, download);
+				}
+				for download in latest.into_values() {
+					if states.get(&download.track_id) == Some(&download.state) {
+						continue;
+					}
+					states.insert(download.track_id.clone(), download.state.clone());
+					if download.state.is_final() {
+						pending.remove(&download.track_id);
+					}
+					yield Ok(download.clone());
+				}
+			}
+		}
+	}
+}
+
+/// Items from offset and at most limit, with their position from 0
+fn window<T>(items: Vec<T>, offset: usize, limit: Option<usize>) -> Vec<(usize, T)> {
+	items
+		.into_iter()
+		.enumerate()
+		.skip(offset)
+		.take(limit.unwrap_or(usize::MAX))
+		.collect()
+}
+
+/// Set the playlist index of downloads from their position in a playlist of total items
+///
+/// Only tracks of playlists are numbered, unless every file is named by its number.
+/// The index is padded to the digits of the last position
+fn number_tracks(
+	config: &DownloaderConfig,
+	tracks: Vec<(usize, Download)>,
+	total: usize,
+	playlist: bool,
+) -> Vec<Download> {
+	let numbered = config.flat_numbered || (playlist && config.playlist_numbering);
+	let last = config.number_from + total.max(1) - 1;
+	// File names of at least three digits sort well in DJ software
+	let min_width = if config.flat_numbered { 3 } else { 1 };
+	let width = config
+		.playlist_index_width
+		.unwrap_or_else(|| last.to_string().len().max(min_width));
+	tracks
+		.into_iter()
+		.enumerate()
+		.map(|(i, (position, mut download))| {
+			if numbered {
+				// Skipped tracks leave gaps unless renumbering
+				let index = match config.renumber_playlist {
+					true => config.offset + i,
+					false => position,
+				} + config.number_from;
+				download.playlist_index = Some(format!("{:0width$}", index, width = width));
+			}
+			download
+		})
+		.collect()
 }
 
 async fn communication_thread(
 	config: DownloaderConfig,
-	spotify: Spotify,
+	spotify: Arc<SpotifyHandle>,
+	control: Arc<DownloadControl>,
 	rx: Receiver<Message>,
 	tx: Sender<Response>,
 	self_tx: Sender<Message>,
 ) {
 	// Downloader
-	let downloader = DownloaderInternal::new(spotify.clone(), self_tx.clone());
+	let downloader = DownloaderInternal::new(spotify, self_tx.clone(), control.clone());
 	let downloader_tx = downloader.tx.clone();
 	tokio::spawn(async move {
 		downloader.download_loop().await;
 	});
 	let mut waiting_for_job = false;
 	let mut queue: Vec<Download> = vec![];
+	// Playlists waiting for their downloads to finish to write the M3U
+	let mut playlists: Vec<(String, Vec<i64>)> = vec![];
 
 	// Receive messages
 	while let Ok(msg) = rx.recv().await {
 		match msg {
 			// Send job to worker thread
 			Message::GetJob => {
-				if let Some(d) = queue.iter_mut().find(|i| i.state == DownloadState::None) {
-					d.state = DownloadState::Lock;
+				if let Some(job) = next_job(&mut queue) {
 					downloader_tx
-						.send(DownloaderMessage::Job(d.clone().into(), config.clone()))
+						.send(DownloaderMessage::Job(job, config.clone()))
 						.await
 						.unwrap();
 					waiting_for_job = false;
@@ -157,37 +581,85 @@ async fn communication_thread(
 			}
 			// Update state of download
 			Message::UpdateState(id, state) => {
-				let i = queue.iter().position(|i| i.id == id).unwrap();
-				queue[i].state = state.clone();
-				if state == DownloadState::Done {
-					queue.remove(i);
+				update_state(
+					&mut queue,
+					&mut playlists,
+					&control,
+					config.strict,
+					id,
+					state,
+				);
+				write_finished_playlists(&config.path, &queue, &mut playlists).await;
+				// Duplicates wait for the download they link to
+				if waiting_for_job {
+					if let Some(job) = next_job(&mut queue) {
+						downloader_tx
+							.send(DownloaderMessage::Job(job, config.clone()))
+							.await
+							.unwrap();
+						waiting_for_job = false;
+					}
 				}
 			}
-			Message::AddToQueue(download) => {
+			Message::Cancel => cancel_queue(&mut queue, &mut playlists),
+			Message::SetPath(id, path) => {
+				if let Some(d) = queue.iter_mut().find(|i| i.id == id) {
+					d.path = Some(path);
+				}
+			}
+			Message::SetFile(id, format, bitrate) => {
+				if let Some(d) = queue.iter_mut().find(|i| i.id == id) {
+					d.format = Some(format);
+					d.bitrate = bitrate;
+				}
+			}
+			Message::AddToQueue(download, playlist) => {
 				// Assign new IDs and reset state
-				let mut id = queue.iter().map(|i| i.id).max().unwrap_or(0);
-				let downloads: Vec<Download> = download
-					.into_iter()
-					.map(|mut d| {
-						d.id = id;
-						d.state = DownloadState::None;
-						id += 1;
-						d
-					})
-					.collect();
+				let mut id = queue.iter().map(|i| i.id + 1).max().unwrap_or(0);
+				let mut downloads: Vec<Download> = vec![];
+				let mut ids = vec![];
+				for mut d in download {
+					// Playlists refer to the download of the same track queued before,
+					// or to a link to its file
+					if config.dedupe {
+						let existing = queue
+							.iter()
+							.chain(downloads.iter())
+							.find(|q| q.track_id == d.track_id && q.duplicate_of.is_none());
+						match (existing, config.dedupe_link) {
+							(Some(existing), Some(mode)) => {
+								info!("Linking duplicate track ({:?}): {}", mode, d.title);
+								d.duplicate_of = Some(existing.id);
+							}
+							(Some(existing), None) => {
+								info!("Skipping duplicate track: {}", d.title);
+								ids.push(existing.id);
+								continue;
+							}
+							(None, _) => {}
+						}
+					}
+					d.id = id;
+					d.state = DownloadState::None;
+					id += 1;
+					ids.push(d.id);
+					downloads.push(d);
+				}
 				queue.extend(downloads);
-				// Update worker threads if locked
+				if let (Some(name), true) = (playlist, config.write_m3u) {
+					playlists.push((name, ids));
+					// All tracks might have been downloaded already
+					write_finished_playlists(&config.path, &queue, &mut playlists).await;
+				}
+				// Update worker threads if locked, unless every track was a duplicate
 				if waiting_for_job {
-					let d = queue
-						.iter_mut()
-						.find(|i| i.state == DownloadState::None)
-						.unwrap();
-					d.state = DownloadState::Lock;
-					downloader_tx
-						.send(DownloaderMessage::Job(d.clone().into(), config.clone()))
-						.await
-						.unwrap();
-					waiting_for_job = false;
+					if let Some(job) = next_job(&mut queue) {
+						downloader_tx
+							.send(DownloaderMessage::Job(job, config.clone()))
+							.await
+							.unwrap();
+						waiting_for_job = false;
+					}
 				}
 			}
 			Message::GetDownloads => {
@@ -197,27 +669,183 @@ async fn communication_thread(
 	}
 }
 
+/// Lock the next download to start, duplicates only once the download they link to finished
+///
+/// Duplicates link to the file of that download if it has one, otherwise they are downloaded
+fn next_job(queue: &mut [Download]) -> Option<DownloadJob> {
+	let original = |queue: &[Download], id: Option<i64>| {
+		id.and_then(|id| queue.iter().find(|d| d.id == id).cloned())
+	};
+	let index = queue.iter().position(|d| {
+		d.state == DownloadState::None
+			&& original(queue, d.duplicate_of).is_none_or(|o| o.state.is_final())
+	})?;
+	let link = original(queue, queue[index].duplicate_of)
+		.filter(|o| matches!(o.state, DownloadState::Done | DownloadState::Skipped(_)))
+		.and_then(|o| o.path);
+	let download = &mut queue[index];
+	download.state = DownloadState::Lock;
+	let mut job: DownloadJob = download.clone().into();
+	job.link = link;
+	Some(job)
+}
+
+/// Set the state of a download, stopping everything on the first failure in strict mode
+fn update_state(
+	queue: &mut [Download],
+	playlists: &mut Vec<(String, Vec<i64>)>,
+	control: &DownloadControl,
+	strict: bool,
+	id: i64,
+	state: DownloadState,
+) {
+	let Some(download) = queue.iter_mut().find(|d| d.id == id) else {
+		return;
+	};
+	let failed = matches!(state, DownloadState::Error(_));
+	download.state = state;
+	if strict && failed && !control.cancelled.swap(true, Ordering::SeqCst) {
+		warn!("Stopping all downloads, {} failed", download.title);
+		cancel_queue(queue, playlists);
+	}
+}
+
+/// Fail downloads which did not start yet, playlists are left incomplete without M3U
+fn cancel_queue(queue: &mut [Download], playlists: &mut Vec<(String, Vec<i64>)>) {
+	for d in queue.iter_mut().filter(|i| i.state == DownloadState::None) {
+		d.state = DownloadState::Error(SpotifyError::Cancelled.to_string());
+	}
+	playlists.clear();
+}
+
+/// Write the M3U of playlists which finished downloading, keeping the others
+async fn write_finished_playlists(
+	path_template: &str,
+	queue: &[Download],
+	playlists: &mut Vec<(String, Vec<i64>)>,
+) {
+	let (finished, pending): (Vec<_>, Vec<_>) = playlists.drain(..).partition(|(_, ids)| {
+		ids.iter()
+			.all(|id| queue.iter().any(|d| d.id == *id && d.state.is_final()))
+	});
+	*playlists = pending;
+	for (name, ids) in finished {
+		let downloads: Vec<&Download> = ids
+			.iter()
+			.filter_map(|id| queue.iter().find(|d| d.id == *id))
+			.collect();
+		if let Err(e) = write_m3u(path_template, &name, &downloads).await {
+			error!("Failed writing playlist file for {}: {}", name, e);
+		}
+	}
+}
+
+/// Write M3U playlist of the downloaded tracks into the output directory
+async fn write_m3u(
+	path_template: &str,
+	name: &str,
+	downloads: &[&Download],
+) -> Result<(), SpotifyError> {
+	let root = naming::template_root(path_template);
+	let mut m3u = String::from("#EXTM3U\n");
+	for download in downloads {
+		if let Some(path) = &download.path {
+			let path = path.strip_prefix(&root).unwrap_or(path);
+			m3u.push_str(&format!(
+				"#EXTINF:{},{}\n{}\n",
+				download.duration.as_secs(),
+				download.title,
+				path.display()
+			));
+		}
+	}
+	tokio::fs::create_dir_all(&root).await?;
+	let file = root.join(format!("{}.m3u8", naming::sanitize(name)));
+	tokio::fs::write(&file, m3u).await?;
+	info!("Playlist written to: {}", file.display());
+	Ok(())
+}
+
+/// Spotify instance shared by a downloader and its downloads, replaced once the session expires
+#[derive(Debug)]
+pub(crate) struct SpotifyHandle {
+	spotify: RwLock<Arc<Spotify>>,
+	reconnecting: tokio::sync::Mutex<()>,
+}
+
+impl SpotifyHandle {
+	fn new(spotify: Spotify) -> SpotifyHandle {
+		SpotifyHandle {
+			spotify: RwLock::new(Arc::new(spotify)),
+			reconnecting: tokio::sync::Mutex::new(()),
+		}
+	}
+
+	/// Current Spotify instance, to use for a whole download
+	fn get(&self) -> Arc<Spotify> {
+		self.spotify.read().unwrap().clone()
+	}
+
+	/// Current Spotify instance, with a new session if this one expired
+	async fn connected(&self) -> Result<Arc<Spotify>, SpotifyError> {
+		if self.get().session.is_invalid() {
+			self.reconnect().await?;
+		}
+		Ok(self.get())
+	}
+
+	/// Replace an expired session with a new one for all downloads
+	async fn reconnect(&self) -> Result<(), SpotifyError> {
+		let _reconnecting = self.reconnecting.lock().await;
+		// Another download might have reconnected already
+		let spotify = self.get();
+		if !spotify.session.is_invalid() {
+			return Ok(());
+		}
+		let spotify = spotify.reconnect().await?;
+		*self.spotify.write().unwrap() = Arc::new(spotify);
+		info!("Reconnected the expired session");
+		Ok(())
+	}
+}
+
 /// Spotify downloader
-pub struct DownloaderInternal {
-	spotify: Spotify,
+pub(crate) struct DownloaderInternal {
+	spotify: Arc<SpotifyHandle>,
 	pub tx: Sender<DownloaderMessage>,
 	rx: Receiver<DownloaderMessage>,
 	event_tx: Sender<Message>,
+	control: Arc<DownloadControl>,
+}
+
+/// State shared by all downloads of a downloader
+#[derive(Debug)]
+pub(crate) struct DownloadControl {
+	/// Limits the combined rate of the downloads
+	limiter: Option<RateLimiter>,
+	cancelled: AtomicBool,
+	/// Files in the output directory by the Spotify track id in their tags, scanned once
+	existing: OnceCell<HashMap<String, PathBuf>>,
 }
 
-pub enum DownloaderMessage {
+pub(crate) enum DownloaderMessage {
 	Job(DownloadJob, DownloaderConfig),
 }
 
 impl DownloaderInternal {
 	/// Create new instance
-	pub fn new(spotify: Spotify, event_tx: Sender<Message>) -> DownloaderInternal {
+	pub fn new(
+		spotify: Arc<SpotifyHandle>,
+		event_tx: Sender<Message>,
+		control: Arc<DownloadControl>,
+	) -> DownloaderInternal {
 		let (tx, rx) = bounded(1);
 		DownloaderInternal {
 			spotify,
 			tx,
 			rx,
 			event_tx,
+			control,
 		}
 	}
 
@@ -258,21 +886,84 @@ impl DownloaderInternal {
 		}
 	}
 
-	/// Wrapper for download_job for error handling
+	/// Wrapper for download_job for error handling and retries
 	async fn download_job_wrapper(&self, job: DownloadJob, config: DownloaderConfig) {
 		let track_id = job.track_id.clone();
 		let id = job.id;
-		match self.download_job(job, config).await {
-			Ok(_) => {}
-			Err(e) => {
-				error!("Download job for track {} failed. {}", track_id, e);
-				self.event_tx
-					.send(Message::UpdateState(
-						id,
-						DownloadState::Error(e.to_string()),
-					))
-					.await
-					.unwrap();
+		let mut attempt = 0;
+		let mut reconnected = false;
+		loop {
+			let result = if self.control.cancelled.load(Ordering::SeqCst) {
+				Err(SpotifyError::Cancelled)
+			} else if let Some(secs) = config.track_timeout {
+				tokio::time::timeout(
+					Duration::from_secs(secs),
+					self.download_job(job.clone(), config.clone()),
+				)
+				.await
+				.unwrap_or_else(|_| {
+					Err(SpotifyError::Timeout(format!(
+						"took longer than {} seconds",
+						secs
+					)))
+				})
+			} else {
+				self.download_job(job.clone(), config.clone()).await
+			};
+			match result {
+				Ok(_) => break,
+				// Retry once with a new session, without counting it as an attempt
+				Err(e)
+					if !reconnected
+						&& (matches!(e, SpotifyError::AuthenticationError)
+							|| self.spotify.get().session.is_invalid()) =>
+				{
+					warn!(
+						"Session expired downloading track {}, reconnecting. {}",
+						track_id, e
+					);
+					reconnected = true;
+					if let Err(e) = self.spotify.reconnect().await {
+						error!("Reconnecting failed. {}", e);
+					}
+				}
+				// Exponential backoff
+				Err(e) if e.is_transient() && attempt < config.retries => {
+					attempt += 1;
+					let backoff = Duration::from_secs(2u64.pow(attempt as u32));
+					warn!(
+						"Download job for track {} failed, retrying in {}s ({}/{}). {}",
+						track_id,
+						backoff.as_secs(),
+						attempt,
+						config.retries,
+						e
+					);
+					task::sleep(backoff).await;
+				}
+				Err(e @ SpotifyError::AlreadyDownloaded)
+				| Err(e @ SpotifyError::QualityUnavailable(_))
+				| Err(e @ SpotifyError::Filtered(_)) => {
+					self.event_tx
+						.send(Message::UpdateState(
+							id,
+							DownloadState::Skipped(e.to_string()),
+						))
+						.await
+						.unwrap();
+					break;
+				}
+				Err(e) => {
+					error!("Download job for track {} failed. {}", track_id, e);
+					self.event_tx
+						.send(Message::UpdateState(
+							id,
+							DownloadState::Error(e.to_string()),
+						))
+						.await
+						.unwrap();
+					break;
+				}
 			}
 		}
 	}
@@ -283,98 +974,99 @@ impl DownloaderInternal {
 		job: DownloadJob,
 		config: DownloaderConfig,
 	) -> Result<(), SpotifyError> {
-		// Fetch metadata
-		let track = self
-			.spotify
-			.spotify
-			.tracks()
-			.get_track(&job.track_id, None)
-			.await?
-			.data;
-		let album = self
-			.spotify
-			.spotify
-			.albums()
-			.get_album(&track.album.id.ok_or(SpotifyError::Unavailable)?, None)
-			.await?
-			.data;
-
-		let tags: Vec<(&str, String)> = vec![
-			("%title%", sanitize(&track.name)),
-			(
-				"%artist%",
-				sanitize(
-					track
-						.artists
-						.iter()
-						.map(|a| a.name.as_str())
-						.collect::<Vec<&str>>()
-						.first()
-						.unwrap_or(&""),
-				),
-			),
-			(
-				"%artists%",
-				sanitize(
-					track
-						.artists
-						.iter()
-						.map(|a| a.name.as_str())
-						.collect::<Vec<&str>>()
-						.join(", "),
-				),
-			),
-			("%track%", track.track_number.to_string()),
-			("%0track%", format!("{:02}", track.track_number)),
-			("%disc%", track.disc_number.to_string()),
-			("%0disc%", format!("{:02}", track.disc_number)),
-			("%id%", job.track_id.to_string()),
-			("%album%", sanitize(&track.album.name)),
-			(
-				"%albumArtist%",
-				sanitize(
-					track
-						.album
-						.artists
-						.iter()
-						.map(|a| a.name.as_str())
-						.collect::<Vec<&str>>()
-						.first()
-						.unwrap_or(&""),
-				),
-			),
-			(
-				"%albumArtists%",
-				sanitize(
-					track
-						.album
-						.artists
-						.iter()
-						.map(|a| a.name.as_str())
-						.collect::<Vec<&str>>()
-						.join(", "),
-				),
-			),
-		];
+		if config.skip_existing && config.match_existing_by_id {
+			let existing = self
+				.control
+				.existing
+				.get_or_init(|| async {
+					let root = naming::template_root(&config.path);
+					tokio::task::spawn_blocking(move || DownloaderInternal::scan_existing(&root))
+						.await
+						.unwrap_or_default()
+				})
+				.await;
+			if let Some(path) = existing.get(&job.track_id) {
+				self.event_tx
+					.send(Message::SetPath(job.id, path.clone()))
+					.await
+					.ok();
+				return Err(SpotifyError::AlreadyDownloaded);
+			}
+		}
 
-		let mut filename_template = config.filename_template.clone();
-		let mut path_template = config.path.clone();
-		for (tag, value) in tags {
-			filename_template = filename_template.replace(tag, &value);
-			path_template = path_template.replace(tag, &value);
+		// Fetch metadata, with the session current when the job started
+		let spotify = self.spotify.get();
+		let mut track = spotify.track(&job.track_id).await?;
+		DownloaderInternal::apply_metadata_source(
+			&spotify.session,
+			config.metadata_source,
+			&mut track,
+		)
+		.await?;
+		match (config.explicit, track.explicit) {
+			(ExplicitFilter::Skip, true) => {
+				info!("Skipping explicit track: {}", track.name);
+				return Err(SpotifyError::Filtered("Explicit".to_string()));
+			}
+			(ExplicitFilter::Only, false) => {
+				info!("Skipping track which is not explicit: {}", track.name);
+				return Err(SpotifyError::Filtered("Not explicit".to_string()));
+			}
+			_ => {}
 		}
-		let path = Path::new(&path_template).join(&filename_template);
+		let album = spotify
+			.album(track.album.id.as_deref().ok_or(SpotifyError::NotFound)?)
+			.await?;
+
+		let values = naming::template_values(
+			&job.track_id,
+			job.playlist_index.as_deref(),
+			&track,
+			&album,
+			config.artists_in_order,
+		);
+		let (path_template, filename_template) = config.templates();
+		let path = naming::track_path(&path_template, &filename_template, &values);
 
 		tokio::fs::create_dir_all(path.parent().unwrap()).await?;
 
+		// Duplicates link to the file of the first download
+		if let (Some(source), Some(mode)) = (&job.link, config.dedupe_link) {
+			let mut target = path.into_os_string();
+			if let Some(extension) = source.extension() {
+				target.push(".");
+				target.push(extension);
+			}
+			let target = PathBuf::from(target);
+			self.event_tx
+				.send(Message::SetPath(job.id, target.clone()))
+				.await
+				.ok();
+			if target != *source {
+				match tokio::fs::symlink_metadata(&target).await {
+					Ok(_) if config.skip_existing => return Err(SpotifyError::AlreadyDownloaded),
+					Ok(_) => tokio::fs::remove_file(&target).await?,
+					Err(_) => {}
+				}
+				DownloaderInternal::link_file(source, &target, mode).await?;
+				info!("Linked {} to {}", target.display(), source.display());
+			}
+			self.event_tx
+				.send(Message::UpdateState(job.id, DownloadState::Done))
+				.await
+				.ok();
+			return Ok(());
+		}
+
 		// Download
-		let (path, format) = DownloaderInternal::download_track(
-			&self.spotify.session,
+		let (path, format, quality) = DownloaderInternal::download_track(
+			&spotify,
 			&job.track_id,
 			path,
 			config.clone(),
 			self.event_tx.clone(),
 			job.id,
+			&self.control,
 		)
 		.await?;
 		// Post processing
@@ -385,14 +1077,20 @@ impl DownloaderInternal {
 
 		// Download cover
 		let mut cover = None;
-		if let Some(image) = track.album.images.first() {
-			match DownloaderInternal::download_cover(&image.url).await {
+		if let Some(image) = config.cover_size.select(&track.album.images) {
+			match DownloaderInternal::download_cover(&spotify.http, &image.url).await {
 				Ok(c) => cover = Some(c),
 				Err(e) => warn!("Failed downloading cover! {}", e),
 			}
 		}
+		if let (true, Some((mime, data))) = (config.save_cover, &cover) {
+			if let Err(e) = DownloaderInternal::save_cover(path.parent().unwrap(), mime, data).await
+			{
+				warn!("Failed saving cover! {}", e);
+			}
+		}
 
-		let tags = vec![
+		let mut tags = vec![
 			(Field::Title, vec![track.name.to_string()]),
 			(Field::Album, vec![track.album.name.to_string()]),
 			(
@@ -417,23 +1115,62 @@ impl DownloaderInternal {
 			(Field::Genre, album.genres.clone()),
 			(Field::Label, vec![album.label.to_string()]),
 		];
-		let date = album.release_date;
+		// Omitted rather than empty if missing
+		if let Some(isrc) = track.external_ids.get("isrc") {
+			tags.push((Field::Isrc, vec![isrc.to_string()]));
+		}
+		let date = match config.date_format {
+			DateFormat::Full => ReleaseDate::new(album.release_date, &album.release_date_precision),
+			DateFormat::Year => ReleaseDate::Year(album.release_date.year()),
+		};
+
+		// Metadata sidecar, from the same metadata as the tags
+		if config.write_metadata_json {
+			let metadata = json!({
+				"id": job.track_id,
+				"title": track.name,
+				"artists": track.artists.iter().map(|a| &a.name).collect::<Vec<_>>(),
+				"album": track.album.name,
+				"album_artists": track.album.artists.iter().map(|a| &a.name).collect::<Vec<_>>(),
+				"track_number": track.track_number,
+				"disc_number": track.disc_number,
+				"duration_ms": track.duration.as_millis() as u64,
+				"isrc": track.external_ids.get("isrc"),
+				"format": format.extension(),
+				"quality": quality.kbps(),
+			});
+			tokio::fs::write(
+				path.with_extension("json"),
+				serde_json::to_string_pretty(&metadata)?,
+			)
+			.await?;
+		}
+
 		// Write tags
-		let config = config.clone();
+		let tag_config = config.clone();
+		let track_id = job.track_id.to_string();
+		let path_clone = path.clone();
 		tokio::task::spawn_blocking(move || {
-			DownloaderInternal::write_tags(
-				path,
-				job.track_id.to_string(),
-				format,
-				tags,
-				date,
-				cover,
-				config,
-			)
+			DownloaderInternal::write_tags(path, track_id, format, tags, date, cover, tag_config)
 		})
 		.await??;
 
+		// Lyrics
+		if config.lyrics {
+			match Lyrics::fetch(&spotify.session, &spotify.http, &job.track_id).await {
+				Ok(Some(lyrics)) => {
+					tokio::fs::write(path_clone.with_extension("lrc"), lyrics.to_lrc()).await?;
+				}
+				Ok(None) => debug!("No lyrics for: {}", job.track_id),
+				Err(e) => warn!("Failed fetching lyrics! {}", e),
+			}
+		}
+
 		// Done
+		self.event_tx
+			.send(Message::SetPath(job.id, path_clone))
+			.await
+			.ok();
 		self.event_tx
 			.send(Message::UpdateState(job.id, DownloadState::Done))
 			.await
@@ -441,9 +1178,27 @@ impl DownloaderInternal {
 		Ok(())
 	}
 
+	/// Link a file to another one, symbolic links by its absolute path
+	async fn link_file(source: &Path, target: &Path, mode: LinkMode) -> Result<(), SpotifyError> {
+		match mode {
+			LinkMode::Hard => tokio::fs::hard_link(source, target).await?,
+			LinkMode::Symbolic => {
+				let source = tokio::fs::canonicalize(source).await?;
+				#[cfg(unix)]
+				tokio::fs::symlink(source, target).await?;
+				#[cfg(windows)]
+				tokio::fs::symlink_file(source, target).await?;
+			}
+		}
+		Ok(())
+	}
+
 	/// Download cover, returns mime and data
-	async fn download_cover(url: &str) -> Result<(String, Vec<u8>), SpotifyError> {
-		let res = reqwest::get(url).await?;
+	async fn download_cover(
+		client: &reqwest::Client,
+		url: &str,
+	) -> Result<(String, Vec<u8>), SpotifyError> {
+		let res = client.get(url).send().await?;
 		let mime = res
 			.headers()
 			.get("content-type")
@@ -455,16 +1210,48 @@ impl DownloaderInternal {
 		Ok((mime, data))
 	}
 
+	/// Write the cover as cover.jpg into the folder, unless a track of the folder already did
+	async fn save_cover(folder: &Path, mime: &str, data: &[u8]) -> Result<(), SpotifyError> {
+		if mime != "image/jpeg" {
+			return Err(SpotifyError::Error(format!(
+				"Cover is no JPEG but {}",
+				mime
+			)));
+		}
+		// Creating it fails for all but the first of concurrent tracks
+		let file = OpenOptions::new()
+			.write(true)
+			.create_new(true)
+			.open(folder.join("cover.jpg"))
+			.await;
+		match file {
+			Ok(mut file) => file.write_all(data).await?,
+			Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+			Err(e) => return Err(e.into()),
+		}
+		Ok(())
+	}
+
 	/// Write tags to file ( BLOCKING )
 	fn write_tags(
 		path: impl AsRef<Path>,
 		track_id: String,
 		format: AudioFormat,
 		tags: Vec<(Field, Vec<String>)>,
-		date: NaiveDate,
+		date: ReleaseDate,
 		cover: Option<(String, Vec<u8>)>,
 		config: DownloaderConfig,
 	) -> Result<(), SpotifyError> {
+		if let AudioFormat::Wav = format {
+			debug!("Not tagging WAV file");
+			return Ok(());
+		}
+		// The Spotify header in front makes it no valid Ogg file
+		if config.raw && format == AudioFormat::Ogg {
+			debug!("Not tagging raw Ogg file");
+			return Ok(());
+		}
+
 		let mut tag_wrap = TagWrap::new(path, format)?;
 		// Format specific
 		if let TagWrap::Id3(id3) = &mut tag_wrap {
@@ -487,141 +1274,447 @@ impl DownloaderInternal {
 		Ok(())
 	}
 
+	/// Find the tagged audio files below a folder by the Spotify track id in their tags
+	fn scan_existing(root: &Path) -> HashMap<String, PathBuf> {
+		let mut existing = HashMap::new();
+		let mut folders = vec![root.to_path_buf()];
+		while let Some(folder) = folders.pop() {
+			let Ok(entries) = std::fs::read_dir(&folder) else {
+				continue;
+			};
+			for path in entries.flatten().map(|e| e.path()) {
+				if path.is_dir() {
+					folders.push(path);
+					continue;
+				}
+				let format = match path.extension().and_then(|e| e.to_str()) {
+					Some("ogg") => AudioFormat::Ogg,
+					Some("mp3") => AudioFormat::Mp3,
+					Some("flac") => AudioFormat::Flac,
+					Some("opus") => AudioFormat::Opus,
+					_ => continue,
+				};
+				let id = TagWrap::new(&path, format)
+					.ok()
+					.and_then(|mut tag| tag.get_tag().get_unique_file_identifier());
+				if let Some(id) = id {
+					existing.insert(id, path);
+				}
+			}
+		}
+		info!(
+			"Found {} tagged tracks in {}",
+			existing.len(),
+			root.display()
+		);
+		existing
+	}
+
+	/// Find an alternative available in the country of the session, preferring ones with files
+	///
+	/// The alternatives are fetched at once, but the first one in their listed order is chosen
 	async fn find_alternative(session: &Session, track: Track) -> Result<Track, SpotifyError> {
-		for alt in track.alternatives {
-			let t = Track::get(session, alt).await?;
-			if t.available {
+		let count = track.alternatives.len().max(1);
+		let alternatives = futures::stream::iter(track.alternatives)
+			.map(|alt| Track::get(session, alt).map(|r| r.map_err(SpotifyError::from)))
+			.buffered(count)
+			.collect()
+			.await;
+		DownloaderInternal::select_alternative(alternatives)
+	}
+
+	/// First available alternative with files, else the first available one
+	///
+	/// Alternatives which failed to fetch are skipped, their last error is returned if none is
+	/// available
+	fn select_alternative(
+		alternatives: Vec<Result<Track, SpotifyError>>,
+	) -> Result<Track, SpotifyError> {
+		let mut fallback = None;
+		let mut error = None;
+		for t in alternatives {
+			let t = match t {
+				Ok(t) => t,
+				Err(e) => {
+					warn!("Failed fetching alternative track: {}", e);
+					error = Some(e);
+					continue;
+				}
+			};
+			if !t.available {
+				continue;
+			}
+			if !t.files.is_empty() {
 				return Ok(t);
 			}
+			fallback.get_or_insert(t);
 		}
 
-		Err(SpotifyError::Unavailable)
+		fallback.ok_or(error.unwrap_or(SpotifyError::RegionRestricted))
 	}
 
-	/// Download track by id
-	async fn download_track(
-		session: &Session,
-		id: &str,
-		path: impl AsRef<Path>,
-		config: DownloaderConfig,
-		tx: Sender<Message>,
-		job_id: i64,
-	) -> Result<(PathBuf, AudioFormat), SpotifyError> {
+	/// Get track metadata by id, with an alternative if it is unavailable
+	async fn get_track(session: &Session, id: &str) -> Result<Track, SpotifyError> {
 		let id = SpotifyId::from_base62(id)?;
-		let mut track = Track::get(session, id).await?;
+		let track = Track::get(session, id).await?;
 
-		// Fallback if unavailable
-		if !track.available {
-			track = DownloaderInternal::find_alternative(session, track).await?;
+		// Fallback if unavailable, an available track without alternatives fails on its files
+		if track.available && track.alternatives.is_empty() {
+			return Ok(track);
 		}
-
-		// Quality fallback
-		let mut quality = config.quality;
-		let (mut file_id, mut file_format) = (None, None);
-		'outer: loop {
+		if !track.available || track.files.is_empty() {
+			return DownloaderInternal::find_alternative(session, track).await;
+		}
+		Ok(track)
+	}
+
+	/// Replace the Web API names of a track with the ones of librespot as the metadata source says
+	///
+	/// librespot only knows the names of the track, its album and artists, everything else
+	/// always comes from the Web API
+	async fn apply_metadata_source(
+		session: &Session,
+		source: MetadataSource,
+		track: &mut aspotify::Track,
+	) -> Result<(), SpotifyError> {
+		if source == MetadataSource::Spotify {
+			return Ok(());
+		}
+		let id = SpotifyId::from_base62(track.id.as_deref().ok_or(SpotifyError::NotFound)?)?;
+		let metadata = Track::get(session, id).await?;
+		let album = Album::get(session, metadata.album).await?;
+		let artists = futures::future::try_join_all(
+			metadata.artists.iter().map(|id| Artist::get(session, *id)),
+		)
+		.await?;
+
+		// Empty names are never taken, so either source falls back to the other
+		let set = |field: &mut String, value: String| {
+			let keep = source == MetadataSource::PreferSpotify && !field.is_empty();
+			if !keep && !value.is_empty() {
+				*field = value;
+			}
+		};
+		set(&mut track.name, metadata.name);
+		set(&mut track.album.name, album.name);
+		for (artist, metadata) in track.artists.iter_mut().zip(artists) {
+			set(&mut artist.name, metadata.name);
+		}
+		Ok(())
+	}
+
+	/// Select the file of the best available quality, falling back to worse qualities
+	/// down to the minimum quality
+	fn select_file(
+		track: &Track,
+		config: &DownloaderConfig,
+	) -> Result<(Quality, FileId, FileFormat), SpotifyError> {
+		let id = track.id.to_base62().unwrap();
+		let file = DownloaderInternal::pick_file(track, config.quality, config.min_quality)?;
+		if file.0 != config.quality {
+			warn!("{} Falling back to: {:?}", id, file.0);
+		}
+		info!("{} Using {:?} format.", id, file.2);
+		Ok(file)
+	}
+
+	/// File of the best available quality starting from a quality, without logging
+	fn pick_file(
+		track: &Track,
+		quality: Quality,
+		min_quality: Option<Quality>,
+	) -> Result<(Quality, FileId, FileFormat), SpotifyError> {
+		if track.files.is_empty() {
+			return Err(SpotifyError::NoSuitableFormat(quality.to_string()));
+		}
+		let requested = quality;
+		let mut quality = quality;
+		loop {
+			if let Some(min_quality) = min_quality {
+				if quality.kbps() < min_quality.kbps() {
+					return Err(SpotifyError::QualityUnavailable(min_quality.to_string()));
+				}
+			}
 			for format in quality.get_file_formats() {
 				if let Some(f) = track.files.get(&format) {
-					info!("{} Using {:?} format.", id.to_base62().unwrap(), format);
-					file_id = Some(f);
-					file_format = Some(format);
-					break 'outer;
+					return Ok((quality, *f, format));
 				}
 			}
 			// Fallback to worser quality
-			match quality.fallback() {
-				Some(q) => quality = q,
-				None => break,
-			}
-			warn!("{} Falling back to: {:?}", id.to_base62().unwrap(), quality);
+			quality = quality
+				.fallback()
+				.ok_or_else(|| SpotifyError::NoSuitableFormat(requested.to_string()))?;
+		}
+	}
+
+	/// Format of the written file for a source format
+	fn output_format(config: &DownloaderConfig, source: AudioFormat) -> AudioFormat {
+		match config.format {
+			OutputFormat::Original => source,
+			OutputFormat::Mp3 => AudioFormat::Mp3,
+			OutputFormat::Flac => AudioFormat::Flac,
+			OutputFormat::Wav => AudioFormat::Wav,
+			OutputFormat::Opus => AudioFormat::Opus,
 		}
+	}
+
+	/// Append the extension of the format to a path
+	fn path_with_extension(path: impl AsRef<Path>, format: &AudioFormat) -> PathBuf {
+		let mut path = path.as_ref().as_os_str().to_owned();
+		path.push(".");
+		path.push(format.extension());
+		path.into()
+	}
 
-		let file_id = file_id.ok_or(SpotifyError::Unavailable)?;
-		let file_format = file_format.unwrap();
+	/// Download track by id
+	async fn download_track(
+		spotify: &Spotify,
+		id: &str,
+		path: impl AsRef<Path>,
+		config: DownloaderConfig,
+		tx: Sender<Message>,
+		job_id: i64,
+		control: &DownloadControl,
+	) -> Result<(PathBuf, AudioFormat, Quality), SpotifyError> {
+		let track = DownloaderInternal::get_track(&spotify.session, id).await?;
+		let (quality, file_id, file_format) = DownloaderInternal::select_file(&track, &config)?;
 
 		// Path with extension
-		let mut audio_format: AudioFormat = file_format.into();
-		let path = format!(
-			"{}.{}",
-			path.as_ref().to_str().unwrap(),
-			match config.convert_to_mp3 {
-				true => "mp3".to_string(),
-				false => audio_format.extension(),
-			}
-		);
-		let path = Path::new(&path).to_owned();
+		let audio_format = DownloaderInternal::output_format(&config, file_format.into());
+		let path = DownloaderInternal::path_with_extension(path, &audio_format);
 
 		// Don't download if we are skipping and the path exists.
 		if config.skip_existing && path.is_file() {
+			tx.send(Message::SetPath(job_id, path)).await.ok();
 			return Err(SpotifyError::AlreadyDownloaded);
 		}
 
-		let path_clone = path.clone();
+		// Download into a temporary file, so an existing file is only replaced when finished.
+		// Named after the served file, so only a previous download of the same file is resumed
+		let mut part = match &config.temp_dir {
+			Some(dir) => {
+				tokio::fs::create_dir_all(dir).await?;
+				Path::new(dir)
+					.join(path.file_name().unwrap())
+					.into_os_string()
+			}
+			None => path.clone().into_os_string(),
+		};
+		part.push(format!(".{}.part", file_id.to_base16().unwrap()));
+		let part = PathBuf::from(part);
 
-		let key = session.audio_key().request(track.id, *file_id).await?;
-		let encrypted = AudioFile::open(session, *file_id, 1024 * 1024, true).await?;
-		let size = encrypted.get_stream_loader_controller().len();
-		// Download
-		let s = match config.convert_to_mp3 {
-			true => {
-				let s = DownloaderInternal::download_track_convert_stream(
-					path_clone,
-					encrypted,
-					key,
-					audio_format.clone(),
-					quality,
-				)
-				.boxed();
-				audio_format = AudioFormat::Mp3;
-				s
+		// Copied files can continue where an interrupted download stopped
+		let resumable = !DownloaderInternal::transcodes(&config, file_format);
+		let offset = match tokio::fs::metadata(&part).await {
+			Ok(metadata) if resumable => metadata.len(),
+			_ => 0,
+		};
+		let file = if offset > 0 {
+			info!("{} Resuming download at {} bytes", id, offset);
+			OpenOptions::new().append(true).open(&part).await?
+		} else {
+			File::create(&part).await?
+		};
+		let header = match DownloaderInternal::write_track(
+			spotify,
+			&track,
+			(quality, file_id, file_format),
+			&config,
+			file,
+			offset,
+			Some((&tx, job_id)),
+			control,
+		)
+		.await
+		{
+			Ok(header) => header,
+			Err(e) => {
+				// Keep what was copied for the next attempt, unless cancelled or corrupt
+				if !resumable
+					|| matches!(e, SpotifyError::Cancelled | SpotifyError::SizeMismatch(..))
+				{
+					tokio::fs::remove_file(part).await.ok();
+				}
+				return Err(e);
 			}
-			false => DownloaderInternal::download_track_stream(path_clone, encrypted, key).boxed(),
+		};
+		if let Some(header) = header {
+			let mut file = OpenOptions::new().write(true).open(&part).await?;
+			file.write_all(&header).await?;
+			file.flush().await?;
+		}
+
+		// Sizes are unknown until the whole file is written
+		if let AudioFormat::Wav = audio_format {
+			let part = part.clone();
+			tokio::task::spawn_blocking(move || converter::finalize_wav(part)).await??;
+		}
+		// The temporary folder can be on another file system
+		if tokio::fs::rename(&part, &path).await.is_err() {
+			tokio::fs::copy(&part, &path).await?;
+			tokio::fs::remove_file(&part).await?;
+		}
+
+		// Nominal bitrate of the written file, lossless and variable bitrate files have none
+		let transcodes = DownloaderInternal::transcodes(&config, file_format);
+		let bitrate = match audio_format {
+			AudioFormat::Flac | AudioFormat::Wav => None,
+			AudioFormat::Mp3 if transcodes && config.vbr.is_some() => None,
+			_ if transcodes => Some(config.bitrate.unwrap_or_else(|| quality.into()).kbps() as u32),
+			_ => Some(quality.kbps()),
+		};
+		tx.send(Message::SetFile(job_id, audio_format.clone(), bitrate))
+			.await
+			.ok();
+
+		info!("Done downloading: {}", track.id.to_base62().unwrap());
+		Ok((path, audio_format, quality))
+	}
+
+	/// Whether the served file has to be converted into the configured format
+	fn transcodes(config: &DownloaderConfig, file_format: FileFormat) -> bool {
+		let source = AudioFormat::from(file_format);
+		DownloaderInternal::output_format(config, source.clone()) != source
+	}
+
+	/// Download the audio of a track into a writer, reporting the progress to the job if given
+	///
+	/// Copied files start at offset bytes of the audio, converted files always start at the beginning.
+	/// Waits on the rate limiter and checks for cancellation after each chunk.
+	/// Returns the data to write over the start of the file, such as the Xing frame of MP3 files
+	#[allow(clippy::too_many_arguments)]
+	async fn write_track<W: AsyncWrite + Unpin + Send>(
+		spotify: &Spotify,
+		track: &Track,
+		(quality, file_id, file_format): (Quality, FileId, FileFormat),
+		config: &DownloaderConfig,
+		sink: W,
+		offset: u64,
+		progress: Option<(&Sender<Message>, i64)>,
+		control: &DownloadControl,
+	) -> Result<Option<Vec<u8>>, SpotifyError> {
+		let key = spotify.audio_key(track.id, file_id).await?;
+		let encrypted = AudioFile::open(&spotify.session, file_id, config.read_ahead, true).await?;
+		let size = encrypted.get_stream_loader_controller().len();
+		// Keep the decrypted bytes if the source already is in the requested format
+		let source = AudioFormat::from(file_format);
+		let transcode = DownloaderInternal::transcodes(config, file_format);
+		let file_header = Arc::new(Mutex::new(None));
+		let s = if !transcode {
+			// Only Ogg files start with the Spotify header, other formats are kept whole
+			let skip_header = source == AudioFormat::Ogg && !config.raw;
+			DownloaderInternal::download_track_stream(
+				sink,
+				encrypted,
+				key,
+				skip_header,
+				offset,
+				config.buffer_size.max(1),
+			)
+			.map(|r| r.map(|r| (r, None)))
+			.boxed()
+		} else {
+			DownloaderInternal::download_track_convert_stream(
+				sink,
+				encrypted,
+				key,
+				source,
+				ConvertOptions {
+					output: config.format,
+					bitrate: config.bitrate.unwrap_or_else(|| quality.into()),
+					mp3_quality: config.mp3_quality,
+					vbr: config.vbr,
+					normalize: config.normalize,
+					dither: config.dither,
+				},
+				config.buffer_size.max(1),
+				file_header.clone(),
+			)
+			.boxed()
 		};
 		pin_mut!(s);
 		// Read progress
-		let mut read = 0;
-		while let Some(result) = s.next().await {
-			match result {
-				Ok(r) => {
-					read += r;
-					tx.send(Message::UpdateState(
-						job_id,
-						DownloadState::Downloading(read, size),
-					))
+		let mut read = if transcode { 0 } else { offset as usize };
+		let duration = Duration::from_millis(track.duration.max(0) as u64);
+		loop {
+			let next = match config.timeout {
+				Some(secs) => tokio::time::timeout(Duration::from_secs(secs), s.next())
 					.await
-					.ok();
-				}
-				Err(e) => {
-					tokio::fs::remove_file(path).await.ok();
-					return Err(e);
-				}
+					.map_err(|_| SpotifyError::Timeout(format!("no data for {} seconds", secs)))?,
+				None => s.next().await,
+			};
+			let Some(result) = next else {
+				break;
+			};
+			let (r, position) = result?;
+			read += r;
+			if control.cancelled.load(Ordering::SeqCst) {
+				return Err(SpotifyError::Cancelled);
+			}
+			if let Some(limiter) = &control.limiter {
+				limiter.take(r).await;
+			}
+			if let Some((tx, job_id)) = progress {
+				// Converted files differ in size, so report the decoded share of the source
+				let current = match position {
+					Some(position) if !duration.is_zero() => {
+						let share = position.as_secs_f64() / duration.as_secs_f64();
+						((size as f64 * share) as usize).min(size)
+					}
+					_ => read,
+				};
+				tx.send(Message::UpdateState(
+					job_id,
+					DownloadState::Downloading(current, size),
+				))
+				.await
+				.ok();
 			}
 		}
-
-		info!("Done downloading: {}", track.id.to_base62().unwrap());
-		Ok((path, audio_format))
+		let header = file_header.lock().unwrap().take();
+		Ok(header)
 	}
 
-	fn download_track_stream(
-		path: impl AsRef<Path>,
+	fn download_track_stream<W: AsyncWrite + Unpin + Send>(
+		sink: W,
 		encrypted: AudioFile,
 		key: AudioKey,
+		skip_header: bool,
+		offset: u64,
+		buffer_size: usize,
+	) -> impl Stream<Item = Result<usize, SpotifyError>> {
+		let size = encrypted.get_stream_loader_controller().len() as u64;
+		let decrypted = AudioDecrypt::new(key, encrypted);
+		DownloaderInternal::copy_stream(sink, decrypted, size, skip_header, offset, buffer_size)
+	}
+
+	/// Copy the audio of a source of size bytes, continuing at offset into the audio
+	fn copy_stream<W: AsyncWrite + Unpin + Send, R: Read + Seek + Send + 'static>(
+		mut sink: W,
+		mut decrypted: R,
+		size: u64,
+		skip_header: bool,
+		offset: u64,
+		buffer_size: usize,
 	) -> impl Stream<Item = Result<usize, SpotifyError>> {
 		try_stream! {
-			let mut file = File::create(path).await?;
-			let mut decrypted = AudioDecrypt::new(key, encrypted);
-			// Skip (i guess encrypted shit)
-			let mut skip: [u8; 0xa7] = [0; 0xa7];
-			let mut decrypted = tokio::task::spawn_blocking(move || {
-				match decrypted.read_exact(&mut skip) {
-					Ok(_) => Ok(decrypted),
-					Err(e) => Err(e)
+			let (d, header) = tokio::task::spawn_blocking(move || -> Result<_, SpotifyError> {
+				let header = if skip_header { converter::find_ogg_start(&mut decrypted)? } else { 0 };
+				debug!("Skipping {} header bytes", header);
+				// A partial file longer than the audio is not from this source
+				if header + offset > size {
+					return Err(SpotifyError::SizeMismatch(offset, size.saturating_sub(header)));
 				}
+				decrypted.seek(SeekFrom::Start(header + offset))?;
+				Ok((decrypted, header))
 			}).await??;
+			decrypted = d;
+			let mut written = 0;
 			// Custom reader loop for decrypting
 			loop {
 				// Blocking reader
 				let (d, read, buf) = tokio::task::spawn_blocking(move || {
-					let mut buf = vec![0; 1024 * 64];
+					let mut buf = vec![0; buffer_size];
 					match decrypted.read(&mut buf) {
 						Ok(r) => Ok((decrypted, r, buf)),
 						Err(e) => Err(e)
@@ -631,40 +1724,52 @@ impl DownloaderInternal {
 				if read == 0 {
 					break;
 				}
-				file.write_all(&buf[0..read]).await?;
+				sink.write_all(&buf[0..read]).await?;
+				written += read as u64;
 				yield read;
 			}
+			sink.flush().await?;
+
+			// Copied audio has to end where the served file ends
+			let expected = size.saturating_sub(header + offset);
+			if written != expected {
+				warn!("Copied {} bytes of audio, expected {}", written, expected);
+				Err(SpotifyError::SizeMismatch(written, expected))?;
+			}
 		}
 	}
-	/// Download and convert to MP3
-	fn download_track_convert_stream(
-		path: impl AsRef<Path>,
+	/// Download and convert to output format
+	///
+	/// Sets the data to write over the start of the file once it is complete
+	fn download_track_convert_stream<W: AsyncWrite + Unpin + Send>(
+		mut sink: W,
 		encrypted: AudioFile,
 		key: AudioKey,
 		format: AudioFormat,
-		quality: Quality,
-	) -> impl Stream<Item = Result<usize, SpotifyError>> {
+		options: ConvertOptions,
+		buffer_size: usize,
+		file_header: Arc<Mutex<Option<Vec<u8>>>>,
+	) -> impl Stream<Item = Result<(usize, Option<Duration>), SpotifyError>> {
 		try_stream! {
-			let mut file = File::create(path).await?;
 			let mut decrypted = AudioDecrypt::new(key, encrypted);
-			// Skip (i guess encrypted shit)
-			let mut skip: [u8; 0xa7] = [0; 0xa7];
-			let decrypted = tokio::task::spawn_blocking(move || {
-				match decrypted.read_exact(&mut skip) {
-					Ok(_) => Ok(decrypted),
-					Err(e) => Err(e)
-				}
+			// Skip the Spotify header, which holds the normalization data
+			let (decrypted, header) = tokio::task::spawn_blocking(move || -> std::io::Result<_> {
+				let start = converter::find_ogg_start(&mut decrypted)?;
+				let mut header = vec![0; start as usize];
+				decrypted.read_exact(&mut header)?;
+				Ok((decrypted, header))
 			}).await??;
+			let gain = options.normalize.then(|| converter::normalization_gain(&header));
 			// Convertor
 			let mut decrypted = tokio::task::spawn_blocking(move || {
-				AudioConverter::new(Box::new(decrypted), format, quality)
+				AudioConverter::new(Box::new(decrypted), format, options, gain)
 			}).await??;
 
 			// Custom reader loop for decrypting
 			loop {
 				// Blocking reader
 				let (d, read, buf) = tokio::task::spawn_blocking(move || {
-					let mut buf = vec![0; 1024 * 64];
+					let mut buf = vec![0; buffer_size];
 					match decrypted.read(&mut buf) {
 						Ok(r) => Ok((decrypted, r, buf)),
 						Err(e) => Err(e)
@@ -674,33 +1779,40 @@ impl DownloaderInternal {
 				if read == 0 {
 					break;
 				}
-				file.write_all(&buf[0..read]).await?;
-				yield read;
+				sink.write_all(&buf[0..read]).await?;
+				yield (read, Some(decrypted.position()));
 			}
+			sink.flush().await?;
+			*file_header.lock().unwrap() = decrypted.header();
 		}
 	}
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AudioFormat {
 	Ogg,
 	Aac,
 	Mp3,
 	Mp4,
+	Flac,
+	Wav,
+	Opus,
 	Unknown,
 }
 
 impl AudioFormat {
-	/// Get extension
-	pub fn extension(&self) -> String {
+	/// Extension of files in this format, used for every path of a download
+	pub fn extension(&self) -> &'static str {
 		match self {
 			AudioFormat::Ogg => "ogg",
 			AudioFormat::Aac => "m4a",
 			AudioFormat::Mp3 => "mp3",
 			AudioFormat::Mp4 => "mp4",
+			AudioFormat::Flac => "flac",
+			AudioFormat::Wav => "wav",
+			AudioFormat::Opus => "opus",
 			AudioFormat::Unknown => "",
 		}
-		.to_string()
 	}
 }
 
@@ -726,6 +1838,16 @@ impl From<FileFormat> for AudioFormat {
 }
 
 impl Quality {
+	/// Get bitrate in kbit/s
+	pub fn kbps(&self) -> u32 {
+		match self {
+			Self::Q320 => 320,
+			Self::Q256 => 256,
+			Self::Q160 => 160,
+			Self::Q96 => 96,
+		}
+	}
+
 	/// Get librespot AudioFileFormat
 	pub fn get_file_formats(&self) -> Vec<FileFormat> {
 		match self {
@@ -756,25 +1878,34 @@ impl Quality {
 }
 
 #[derive(Debug, Clone)]
-pub struct DownloadJob {
+pub(crate) struct DownloadJob {
 	pub id: i64,
 	pub track_id: String,
+	pub playlist_index: Option<String>,
+	/// File of the same track to link instead of downloading it again
+	pub link: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone)]
-pub enum Message {
+pub(crate) enum Message {
 	// Send job to worker
 	GetJob,
 	// Update state of download
 	UpdateState(i64, DownloadState),
-	//add to download
-	AddToQueue(Vec<Download>),
+	// Set final path of download
+	SetPath(i64, PathBuf),
+	// Set format and bitrate of the written file
+	SetFile(i64, AudioFormat, Option<u32>),
+	//add to download, optionally as tracks of a named playlist
+	AddToQueue(Vec<Download>, Option<String>),
 	// Get all downloads to UI
 	GetDownloads,
+	// Cancel all downloads
+	Cancel,
 }
 
 #[derive(Debug, Clone)]
-pub enum Response {
+pub(crate) enum Response {
 	Downloads(Vec<Download>),
 }
 
@@ -783,12 +1914,76 @@ pub struct Download {
 	pub id: i64,
 	pub track_id: String,
 	pub title: String,
+	pub duration: Duration,
+	pub path: Option<PathBuf>,
 	pub state: DownloadState,
+	/// Padded position in the playlist the track was queued from
+	pub playlist_index: Option<String>,
+	/// Format of the written file
+	pub format: Option<AudioFormat>,
+	/// Nominal bitrate of the written file in kbit/s, none for lossless and variable bitrate files
+	pub bitrate: Option<u32>,
+	/// Download of the same track queued before, whose file this one links to
+	pub duplicate_of: Option<i64>,
+}
+
+/// Download resolved without fetching the audio
+#[derive(Debug, Clone)]
+pub struct PlannedDownload {
+	pub artist: String,
+	pub format: AudioFormat,
+	pub path: PathBuf,
+}
+
+/// File a track would be downloaded from
+#[derive(Debug, Clone)]
+pub struct ResolvedFile {
+	/// Quality after falling back to the available ones
+	pub quality: Quality,
+	/// Format served by Spotify
+	pub source: AudioFormat,
+	/// Format of the written file
+	pub format: AudioFormat,
+	/// Size of the served file in bytes
+	pub size: usize,
+}
+
+/// Quality and format of the file picked for a track, or why there is none
+pub type QualityPick = Result<(Quality, AudioFormat), SpotifyError>;
+
+/// Formats of a track and the files picked for it
+#[derive(Debug, Clone)]
+pub struct QualityReport {
+	/// Formats served by Spotify, such as OGG_VORBIS_320
+	pub available: Vec<String>,
+	/// Quality and format picked when starting from each quality
+	pub picks: Vec<(Quality, QualityPick)>,
+	/// Quality and format picked with the quality and minimum quality of the config
+	pub selected: QualityPick,
+}
+
+impl QualityReport {
+	fn new(track: &Track, quality: Quality, min_quality: Option<Quality>) -> QualityReport {
+		let mut available: Vec<String> = track.files.keys().map(|f| format!("{:?}", f)).collect();
+		available.sort();
+		let pick = |quality, min_quality| {
+			DownloaderInternal::pick_file(track, quality, min_quality)
+				.map(|(quality, _, format)| (quality, AudioFormat::from(format)))
+		};
+		QualityReport {
+			available,
+			picks: Quality::value_variants()
+				.iter()
+				.map(|q| (*q, pick(*q, None)))
+				.collect(),
+			selected: pick(quality, min_quality),
+		}
+	}
 }
 
 #[derive(Debug, Clone)]
 pub struct SearchResult {
-	pub track_id: String,
+	pub uri: String,
 	pub author: String,
 	pub title: String,
 }
@@ -796,8 +1991,22 @@ pub struct SearchResult {
 impl From<aspotify::Track> for SearchResult {
 	fn from(val: aspotify::Track) -> Self {
 		SearchResult {
-			track_id: val.id.unwrap(),
-			author: val.artists[0].name.to_owned(),
+			uri: format!("spotify:track:{}", val.id.unwrap()),
+			author: val
+				.artists
+				.first()
+				.map(|a| a.name.to_owned())
+				.unwrap_or_else(|| naming::UNKNOWN_ARTIST.to_string()),
+			title: val.name,
+		}
+	}
+}
+
+impl From<aspotify::PlaylistSimplified> for SearchResult {
+	fn from(val: aspotify::PlaylistSimplified) -> Self {
+		SearchResult {
+			uri: format!("spotify:playlist:{}", val.id),
+			author: val.owner.display_name.unwrap_or(val.owner.id),
 			title: val.name,
 		}
 	}
@@ -809,7 +2018,13 @@ impl From<aspotify::Track> for Download {
 			id: 0,
 			track_id: val.id.unwrap(),
 			title: val.name,
+			duration: val.duration,
+			path: None,
 			state: DownloadState::None,
+			playlist_index: None,
+			format: None,
+			bitrate: None,
+			duplicate_of: None,
 		}
 	}
 }
@@ -820,7 +2035,13 @@ impl From<aspotify::TrackSimplified> for Download {
 			id: 0,
 			track_id: val.id.unwrap(),
 			title: val.name,
+			duration: val.duration,
+			path: None,
 			state: DownloadState::None,
+			playlist_index: None,
+			format: None,
+			bitrate: None,
+			duplicate_of: None,
 		}
 	}
 }
@@ -830,6 +2051,8 @@ impl From<Download> for DownloadJob {
 		DownloadJob {
 			id: val.id,
 			track_id: val.track_id,
+			playlist_index: val.playlist_index,
+			link: None,
 		}
 	}
 }
@@ -838,18 +2061,35 @@ impl From<Download> for DownloadJob {
 pub enum DownloadState {
 	None,
 	Lock,
+	/// Bytes of the source file downloaded and its size, for converted files the decoded share of it
 	Downloading(usize, usize),
 	Post,
 	Done,
+	/// Not downloaded, with the reason
+	Skipped(String),
 	Error(String),
 }
 
+impl DownloadState {
+	/// Whether the download will not change state anymore
+	pub fn is_final(&self) -> bool {
+		matches!(
+			self,
+			DownloadState::Done | DownloadState::Skipped(_) | DownloadState::Error(_)
+		)
+	}
+}
+
 /// Bitrate of music
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Copy)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Copy, ValueEnum)]
 pub enum Quality {
+	#[value(name = "320")]
 	Q320,
+	#[value(name = "256")]
 	Q256,
+	#[value(name = "160")]
 	Q160,
+	#[value(name = "96")]
 	Q96,
 }
 
@@ -868,16 +2108,221 @@ impl Display for Quality {
 	}
 }
 
+/// Bitrate of converted MP3 files
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Copy, ValueEnum)]
+pub enum Bitrate {
+	#[value(name = "320")]
+	B320,
+	#[value(name = "256")]
+	B256,
+	#[value(name = "192")]
+	B192,
+	#[value(name = "160")]
+	B160,
+	#[value(name = "96")]
+	B96,
+}
+
+impl Bitrate {
+	/// Get bitrate in kbit/s
+	pub fn kbps(&self) -> i32 {
+		match self {
+			Bitrate::B320 => 320,
+			Bitrate::B256 => 256,
+			Bitrate::B192 => 192,
+			Bitrate::B160 => 160,
+			Bitrate::B96 => 96,
+		}
+	}
+}
+
+/// Bitrate matching the quality of the source
+impl From<Quality> for Bitrate {
+	fn from(quality: Quality) -> Self {
+		match quality {
+			Quality::Q320 => Bitrate::B320,
+			Quality::Q256 => Bitrate::B256,
+			Quality::Q160 => Bitrate::B160,
+			Quality::Q96 => Bitrate::B96,
+		}
+	}
+}
+
+/// Which tracks to download by their explicit flag
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExplicitFilter {
+	/// Explicit and clean tracks
+	#[default]
+	All,
+	/// Clean tracks only
+	Skip,
+	/// Explicit tracks only
+	Only,
+}
+
+/// How the files of duplicate tracks link to the first download
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum LinkMode {
+	Hard,
+	Symbolic,
+}
+
+/// Where the names of tracks, albums and artists in tags and paths come from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum MetadataSource {
+	/// The Web API
+	#[default]
+	Spotify,
+	/// librespot, falling back to the Web API for missing names
+	Librespot,
+	/// The Web API, falling back to librespot for missing names
+	PreferSpotify,
+}
+
+/// Precision of the release date in tags
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum DateFormat {
+	/// As precise as Spotify knows it, e.g. 2016-05-20, 2016-05 or 2016
+	#[default]
+	Full,
+	/// Year only
+	Year,
+}
+
+/// Size of the embedded cover
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum CoverSize {
+	/// 64 pixels
+	Small,
+	/// 300 pixels
+	Medium,
+	/// 640 pixels
+	#[default]
+	Large,
+}
+
+impl CoverSize {
+	/// Width in pixels of covers of this size
+	fn width(&self) -> usize {
+		match self {
+			CoverSize::Small => 64,
+			CoverSize::Medium => 300,
+			CoverSize::Large => 640,
+		}
+	}
+
+	/// Image closest to this size, the first one if their sizes are unknown
+	fn select<'a>(&self, images: &'a [aspotify::Image]) -> Option<&'a aspotify::Image> {
+		images.iter().min_by_key(|i| {
+			i.width
+				.map(|w| w.abs_diff(self.width()))
+				.unwrap_or(usize::MAX)
+		})
+	}
+}
+
+/// Format of the downloaded files
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Copy, ValueEnum)]
+pub enum OutputFormat {
+	/// Keep the format served by Spotify
+	Original,
+	Mp3,
+	Flac,
+	Wav,
+	/// Opus in an Ogg container, needs a build with the opus feature
+	Opus,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct DownloaderConfig {
 	pub concurrent_downloads: usize,
 	pub quality: Quality,
 	pub path: String,
 	pub filename_template: String,
 	pub id3v24: bool,
-	pub convert_to_mp3: bool,
+	pub format: OutputFormat,
+	/// Bitrate of converted MP3 and Opus files, defaults to the quality of the source
+	pub bitrate: Option<Bitrate>,
 	pub separator: String,
 	pub skip_existing: bool,
+	/// Retries of downloads failing with a transient error
+	pub retries: usize,
+	/// Write an M3U file next to the tracks of downloaded playlists
+	pub write_m3u: bool,
+	/// Kinds of releases downloaded for artists
+	pub artist_groups: Vec<AlbumGroup>,
+	/// Tracks of albums, playlists and artists to skip, only set from the command line
+	#[serde(skip)]
+	pub offset: usize,
+	/// Maximum of tracks of albums, playlists and artists, only set from the command line
+	#[serde(skip)]
+	pub limit: Option<usize>,
+	/// Only download tracks added to playlists after this time, only set from the command line
+	#[serde(skip)]
+	pub added_after: Option<DateTime<Utc>>,
+	/// Write the lyrics of tracks into LRC files next to them
+	pub lyrics: bool,
+	/// Apply the normalization gain of tracks when converting them
+	pub normalize: bool,
+	/// Maximum combined download rate of all downloads in bytes per second
+	pub max_rate: Option<usize>,
+	/// Skip tracks not available in at least this quality instead of falling back
+	pub min_quality: Option<Quality>,
+	/// Write the metadata of tracks into JSON files next to them
+	pub write_metadata_json: bool,
+	/// Queue every track only once per run, also when it is in several playlists or albums
+	pub dedupe: bool,
+	/// Link the files of duplicates to the first download instead of leaving them out
+	pub dedupe_link: Option<LinkMode>,
+	/// Stop all downloads when one fails, instead of downloading the others
+	pub strict: bool,
+	/// Size of the embedded cover
+	pub cover_size: CoverSize,
+	/// Write the cover as cover.jpg into the folder of the tracks
+	pub save_cover: bool,
+	/// Which tracks to download by their explicit flag
+	pub explicit: ExplicitFilter,
+	/// Seconds without receiving audio after which a download fails
+	pub timeout: Option<u64>,
+	/// Seconds after which a download fails, including metadata and tagging
+	pub track_timeout: Option<u64>,
+	/// Keep the Spotify header in front of copied Ogg files, only set from the command line
+	#[serde(skip)]
+	pub raw: bool,
+	/// Recognize existing files by the Spotify track id in their tags, whatever their path
+	pub match_existing_by_id: bool,
+	/// Algorithm quality of lame from 0, the best, to 9, the fastest
+	pub mp3_quality: u8,
+	/// Encode MP3 files with variable bitrate of this quality from 0, the best, to 9 instead of the bitrate
+	pub vbr: Option<u8>,
+	/// Dither the samples of converted files when applying the normalization gain
+	pub dither: bool,
+	/// Bytes read from the decrypted audio at once
+	pub buffer_size: usize,
+	/// Expected bytes per second of the audio, which librespot sizes its read ahead by
+	pub read_ahead: usize,
+	/// Folder of the partial files instead of next to the final ones
+	pub temp_dir: Option<String>,
+	/// Fill %playlist_index% with the position of tracks in playlists
+	pub playlist_numbering: bool,
+	/// Playlist index of the first track of playlists
+	pub number_from: usize,
+	/// Digits the playlist index is padded to, defaults to the digits of the playlist length
+	pub playlist_index_width: Option<usize>,
+	/// Number the downloaded tracks of playlists without gaps for the skipped ones
+	pub renumber_playlist: bool,
+	/// Precision of the release date in tags
+	pub date_format: DateFormat,
+	/// Name files by all artists in their order for %artist% instead of the primary one
+	pub artists_in_order: bool,
+	/// Where the names of tracks, albums and artists come from
+	pub metadata_source: MetadataSource,
+	/// Name files only by their position, like 001, in the folder of path before any variable
+	pub flat_numbered: bool,
+	/// Switch of settings from before format, read as format Mp3 when loading them
+	#[serde(default, skip_serializing)]
+	pub convert_to_mp3: Option<bool>,
 }
 
 impl DownloaderConfig {
@@ -889,9 +2334,498 @@ impl DownloaderConfig {
 			path: "downloads".to_string(),
 			filename_template: "%artist% - %title%".to_string(),
 			id3v24: true,
-			convert_to_mp3: false,
+			format: OutputFormat::Original,
+			bitrate: None,
 			separator: ", ".to_string(),
 			skip_existing: true,
+			retries: 3,
+			write_m3u: false,
+			artist_groups: vec![
+				AlbumGroup::Album,
+				AlbumGroup::Single,
+				AlbumGroup::AppearsOn,
+				AlbumGroup::Compilation,
+			],
+			offset: 0,
+			limit: None,
+			added_after: None,
+			lyrics: false,
+			normalize: false,
+			max_rate: None,
+			min_quality: None,
+			write_metadata_json: false,
+			dedupe: false,
+			dedupe_link: None,
+			strict: false,
+			cover_size: CoverSize::Large,
+			save_cover: false,
+			timeout: None,
+			track_timeout: None,
+			explicit: ExplicitFilter::All,
+			raw: false,
+			match_existing_by_id: false,
+			mp3_quality: 0,
+			vbr: None,
+			dither: false,
+			buffer_size: 1024 * 64,
+			read_ahead: 1024 * 1024,
+			temp_dir: None,
+			playlist_numbering: true,
+			number_from: 1,
+			playlist_index_width: None,
+			renumber_playlist: false,
+			date_format: DateFormat::Full,
+			artists_in_order: false,
+			metadata_source: MetadataSource::Spotify,
+			flat_numbered: false,
+			convert_to_mp3: None,
+		}
+	}
+
+	/// Path and filename templates, replaced by the numbered files of flat_numbered
+	fn templates(&self) -> (String, String) {
+		match self.flat_numbered {
+			true => (
+				naming::template_root(&self.path)
+					.to_string_lossy()
+					.into_owned(),
+				"%playlist_index%".to_string(),
+			),
+			false => (self.path.clone(), self.filename_template.clone()),
+		}
+	}
+}
+
+impl Default for DownloaderConfig {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn download(id: i64, state: DownloadState) -> Download {
+		Download {
+			id,
+			track_id: format!("track{}", id),
+			title: format!("Track {}", id),
+			duration: Duration::from_secs(180),
+			path: None,
+			state,
+			playlist_index: None,
+			format: None,
+			bitrate: None,
+			duplicate_of: None,
+		}
+	}
+
+	fn control() -> DownloadControl {
+		DownloadControl {
+			limiter: None,
+			cancelled: AtomicBool::new(false),
+			existing: OnceCell::new(),
+		}
+	}
+
+	#[test]
+	fn update_state_sets_state() {
+		let mut queue = vec![
+			download(0, DownloadState::Lock),
+			download(1, DownloadState::None),
+		];
+		let mut playlists = vec![];
+		let control = control();
+		update_state(
+			&mut queue,
+			&mut playlists,
+			&control,
+			false,
+			0,
+			DownloadState::Done,
+		);
+		let failed = DownloadState::Error("Not found".to_string());
+		update_state(
+			&mut queue,
+			&mut playlists,
+			&control,
+			false,
+			1,
+			failed.clone(),
+		);
+		assert_eq!(queue[0].state, DownloadState::Done);
+		assert_eq!(queue[1].state, failed);
+		assert!(!control.cancelled.load(Ordering::SeqCst));
+	}
+
+	#[test]
+	fn strict_failure_cancels_queue() {
+		let mut queue = vec![
+			download(0, DownloadState::Lock),
+			download(1, DownloadState::None),
+			download(2, DownloadState::Done),
+		];
+		let mut playlists = vec![("Playlist".to_string(), vec![0, 1, 2])];
+		let control = control();
+		let failed = DownloadState::Error("Not found".to_string());
+		update_state(
+			&mut queue,
+			&mut playlists,
+			&control,
+			true,
+			0,
+			failed.clone(),
+		);
+		assert_eq!(queue[0].state, failed);
+		assert_eq!(
+			queue[1].state,
+			DownloadState::Error(SpotifyError::Cancelled.to_string())
+		);
+		assert_eq!(queue[2].state, DownloadState::Done);
+		assert!(playlists.is_empty());
+		assert!(control.cancelled.load(Ordering::SeqCst));
+	}
+
+	/// Copy the audio of source into sink, continuing at offset
+	async fn copy(source: &[u8], sink: &mut Vec<u8>, offset: u64) -> Result<(), SpotifyError> {
+		let size = source.len() as u64;
+		let source = std::io::Cursor::new(source.to_vec());
+		let stream = DownloaderInternal::copy_stream(sink, source, size, true, offset, 100);
+		futures::pin_mut!(stream);
+		while let Some(read) = stream.next().await {
+			read?;
+		}
+		Ok(())
+	}
+
+	/// Ogg file behind a Spotify header
+	fn spotify_ogg() -> Vec<u8> {
+		let mut file = vec![0; 0xa7];
+		file.extend_from_slice(include_bytes!("../tests/fixtures/stereo.ogg"));
+		file
+	}
+
+	#[tokio::test]
+	async fn resumed_copy_is_identical() {
+		let source = spotify_ogg();
+		let mut whole = vec![];
+		copy(&source, &mut whole, 0).await.unwrap();
+		assert_eq!(whole, &source[0xa7..]);
+
+		let mut part = whole[..1234].to_vec();
+		copy(&source, &mut part, 1234).await.unwrap();
+		assert_eq!(part, whole);
+	}
+
+	#[tokio::test]
+	async fn resume_beyond_audio_fails() {
+		let source = spotify_ogg();
+		let audio = (source.len() - 0xa7) as u64;
+		let mut part = vec![];
+		let result = copy(&source, &mut part, audio + 1).await;
+		assert!(
+			matches!(result, Err(SpotifyError::SizeMismatch(o, a)) if o == audio + 1 && a == audio)
+		);
+
+		// Nothing left to copy of a complete part
+		copy(&source, &mut part, audio).await.unwrap();
+		assert!(part.is_empty());
+	}
+
+	#[tokio::test]
+	async fn playlist_written_once_all_tracks_finish() {
+		let root = std::env::temp_dir().join(format!("down_on_spot_{}_m3u", std::process::id()));
+		let template = root.join("%artist%").to_string_lossy().into_owned();
+		let mut queue: Vec<Download> = (0..4).map(|id| download(id, DownloadState::None)).collect();
+		let mut playlists = vec![("Mix".to_string(), vec![2, 0, 3, 1])];
+		let m3u = root.join("Mix.m3u8");
+
+		// Finishing in another order than the playlist
+		for id in [3, 1, 0, 2] {
+			assert!(!m3u.exists());
+			let download = &mut queue[id as usize];
+			download.path = Some(root.join("Artist").join(format!("{}.mp3", id)));
+			download.state = match id {
+				1 => DownloadState::Skipped("Exists".to_string()),
+				_ => DownloadState::Done,
+			};
+			write_finished_playlists(&template, &queue, &mut playlists).await;
+		}
+		let written = std::fs::read_to_string(&m3u).unwrap();
+		std::fs::remove_dir_all(&root).unwrap();
+		assert!(playlists.is_empty());
+		let files: Vec<&str> = written.lines().filter(|l| !l.starts_with('#')).collect();
+		let separator = std::path::MAIN_SEPARATOR;
+		let expected: Vec<String> = [2, 0, 3, 1]
+			.iter()
+			.map(|id| format!("Artist{}{}.mp3", separator, id))
+			.collect();
+		assert_eq!(files, expected);
+		assert!(written.starts_with("#EXTM3U\n#EXTINF:180,Track 2\n"));
+	}
+
+	#[test]
+	fn duplicates_wait_for_their_first_download() {
+		let mut duplicate = download(1, DownloadState::None);
+		duplicate.duplicate_of = Some(0);
+		let mut queue = vec![download(0, DownloadState::None), duplicate];
+
+		assert_eq!(next_job(&mut queue).unwrap().id, 0);
+		assert!(next_job(&mut queue).is_none());
+
+		queue[0].state = DownloadState::Done;
+		queue[0].path = Some(PathBuf::from("Album/Track.mp3"));
+		let job = next_job(&mut queue).unwrap();
+		assert_eq!(job.id, 1);
+		assert_eq!(job.link, Some(PathBuf::from("Album/Track.mp3")));
+		assert_eq!(queue[1].state, DownloadState::Lock);
+	}
+
+	#[test]
+	fn duplicates_of_failed_downloads_are_downloaded() {
+		let mut duplicate = download(1, DownloadState::None);
+		duplicate.duplicate_of = Some(0);
+		let mut queue = vec![
+			download(0, DownloadState::Error("Not found".to_string())),
+			duplicate,
+		];
+		let job = next_job(&mut queue).unwrap();
+		assert_eq!(job.id, 1);
+		assert_eq!(job.link, None);
+	}
+
+	fn track(id: u128, available: bool, formats: &[FileFormat]) -> Track {
+		let track_files = formats.iter().map(|f| (*f, FileId([0; 20]))).collect();
+		Track {
+			id: SpotifyId::from_raw(&id.to_be_bytes()).unwrap(),
+			name: format!("Track {}", id),
+			duration: 180_000,
+			album: SpotifyId::from_raw(&[0; 16]).unwrap(),
+			artists: vec![],
+			files: track_files,
+			alternatives: vec![],
+			available,
+		}
+	}
+
+	fn selected(alternatives: Vec<Result<Track, SpotifyError>>) -> Result<String, SpotifyError> {
+		DownloaderInternal::select_alternative(alternatives).map(|t| t.name)
+	}
+
+	#[test]
+	fn select_alternative_prefers_available_with_files() {
+		let name = selected(vec![
+			Err(SpotifyError::Error("Timeout".to_string())),
+			Ok(track(1, false, &[FileFormat::OGG_VORBIS_320])),
+			Ok(track(2, true, &[])),
+			Ok(track(3, true, &[FileFormat::OGG_VORBIS_320])),
+			Ok(track(4, true, &[FileFormat::OGG_VORBIS_320])),
+		]);
+		assert_eq!(name.unwrap(), "Track 3");
+
+		// Without files, the first available one is used
+		let name = selected(vec![
+			Ok(track(1, false, &[FileFormat::OGG_VORBIS_320])),
+			Err(SpotifyError::Error("Timeout".to_string())),
+			Ok(track(2, true, &[])),
+			Ok(track(3, true, &[])),
+		]);
+		assert_eq!(name.unwrap(), "Track 2");
+	}
+
+	#[test]
+	fn select_alternative_without_available() {
+		let result = selected(vec![
+			Ok(track(1, false, &[FileFormat::OGG_VORBIS_320])),
+			Err(SpotifyError::Error("Timeout".to_string())),
+		]);
+		assert!(matches!(result, Err(SpotifyError::Error(e)) if e == "Timeout"));
+
+		let result = selected(vec![Ok(track(1, false, &[FileFormat::OGG_VORBIS_320]))]);
+		assert!(matches!(result, Err(SpotifyError::RegionRestricted)));
+		assert!(matches!(
+			selected(vec![]),
+			Err(SpotifyError::RegionRestricted)
+		));
+	}
+
+	/// Playlist indexes of tracks at positions of a playlist of total tracks
+	fn indexes(
+		config: &DownloaderConfig,
+		positions: &[usize],
+		total: usize,
+		playlist: bool,
+	) -> Vec<Option<String>> {
+		let tracks = positions
+			.iter()
+			.map(|p| (*p, download(*p as i64, DownloadState::None)))
+			.collect();
+		number_tracks(config, tracks, total, playlist)
+			.into_iter()
+			.map(|d| d.playlist_index)
+			.collect()
+	}
+
+	fn some(indexes: &[&str]) -> Vec<Option<String>> {
+		indexes.iter().map(|i| Some(i.to_string())).collect()
+	}
+
+	#[test]
+	fn number_tracks_pads_to_playlist_length() {
+		let mut config = DownloaderConfig::new();
+		assert_eq!(
+			indexes(&config, &[0, 1, 8], 9, true),
+			some(&["1", "2", "9"])
+		);
+		assert_eq!(
+			indexes(&config, &[0, 9, 99], 100, true),
+			some(&["001", "010", "100"])
+		);
+
+		// The last index decides the width, not the number of tracks
+		config.number_from = 0;
+		assert_eq!(indexes(&config, &[0, 9], 10, true), some(&["0", "9"]));
+		config.number_from = 5;
+		assert_eq!(indexes(&config, &[0, 5], 6, true), some(&["05", "10"]));
+
+		config.number_from = 1;
+		config.playlist_index_width = Some(4);
+		assert_eq!(
+			indexes(&config, &[0, 41], 42, true),
+			some(&["0001", "0042"])
+		);
+	}
+
+	#[test]
+	fn number_tracks_gaps_and_renumbering() {
+		let mut config = DownloaderConfig::new();
+		config.offset = 10;
+		// Tracks keep their playlist position with gaps for skipped ones
+		assert_eq!(
+			indexes(&config, &[10, 12, 15], 20, true),
+			some(&["11", "13", "16"])
+		);
+		config.renumber_playlist = true;
+		assert_eq!(
+			indexes(&config, &[10, 12, 15], 20, true),
+			some(&["11", "12", "13"])
+		);
+	}
+
+	#[test]
+	fn number_tracks_only_playlists() {
+		let mut config = DownloaderConfig::new();
+		assert_eq!(indexes(&config, &[0, 1], 2, false), vec![None, None]);
+		config.playlist_numbering = false;
+		assert_eq!(indexes(&config, &[0, 1], 2, true), vec![None, None]);
+		// Flat numbering names every file by its position, padded to three digits
+		config.flat_numbered = true;
+		assert_eq!(indexes(&config, &[0, 1], 2, false), some(&["001", "002"]));
+	}
+
+	#[test]
+	fn window_starts_at_offset() {
+		let items: Vec<usize> = (0..300).collect();
+		// --start-at 250 is offset 249
+		let resumed = window(items.clone(), 249, None);
+		assert_eq!(resumed.len(), 51);
+		assert_eq!(resumed[0], (249, 249));
+		assert_eq!(resumed[50], (299, 299));
+
+		let limited = window(items.clone(), 249, Some(10));
+		assert_eq!(
+			limited.iter().map(|(p, _)| *p).collect::<Vec<_>>(),
+			(249..259).collect::<Vec<_>>()
+		);
+		assert!(window(items.clone(), 300, None).is_empty());
+		assert_eq!(window(items, 0, Some(0)), vec![]);
+	}
+
+	#[test]
+	fn start_at_keeps_playlist_numbering() {
+		let config = DownloaderConfig {
+			offset: 249,
+			..DownloaderConfig::new()
+		};
+		let positions: Vec<usize> = window((0..300).collect::<Vec<usize>>(), config.offset, None)
+			.into_iter()
+			.map(|(p, _)| p)
+			.collect();
+		let numbered = indexes(&config, &positions, 300, true);
+		assert_eq!(numbered[0].as_deref(), Some("250"));
+		assert_eq!(numbered[50].as_deref(), Some("300"));
+	}
+
+	#[test]
+	fn pick_file_falls_back_down_to_min_quality() {
+		let served = track(1, true, &[FileFormat::MP3_256, FileFormat::OGG_VORBIS_96]);
+		let pick = |quality, min_quality| {
+			DownloaderInternal::pick_file(&served, quality, min_quality)
+				.map(|(quality, _, format)| (quality, format))
+		};
+		assert!(matches!(
+			pick(Quality::Q320, None),
+			Ok((Quality::Q256, FileFormat::MP3_256))
+		));
+		assert!(matches!(
+			pick(Quality::Q160, None),
+			Ok((Quality::Q96, FileFormat::OGG_VORBIS_96))
+		));
+		assert!(matches!(
+			pick(Quality::Q320, Some(Quality::Q256)),
+			Ok((Quality::Q256, FileFormat::MP3_256))
+		));
+		assert!(matches!(
+			pick(Quality::Q160, Some(Quality::Q160)),
+			Err(SpotifyError::QualityUnavailable(_))
+		));
+		assert!(matches!(
+			DownloaderInternal::pick_file(&track(2, true, &[]), Quality::Q320, None),
+			Err(SpotifyError::NoSuitableFormat(_))
+		));
+	}
+
+	#[test]
+	fn quality_report_lists_every_quality() {
+		let track = track(1, true, &[FileFormat::OGG_VORBIS_160, FileFormat::MP3_96]);
+		let report = QualityReport::new(&track, Quality::Q320, Some(Quality::Q256));
+		assert_eq!(report.available, vec!["MP3_96", "OGG_VORBIS_160"]);
+		let picks: Vec<(Quality, Option<(Quality, AudioFormat)>)> =
+			report.picks.into_iter().map(|(q, p)| (q, p.ok())).collect();
+		assert_eq!(
+			picks,
+			vec![
+				(Quality::Q320, Some((Quality::Q160, AudioFormat::Ogg))),
+				(Quality::Q256, Some((Quality::Q160, AudioFormat::Ogg))),
+				(Quality::Q160, Some((Quality::Q160, AudioFormat::Ogg))),
+				(Quality::Q96, Some((Quality::Q96, AudioFormat::Mp3))),
+			]
+		);
+		assert!(matches!(
+			report.selected,
+			Err(SpotifyError::QualityUnavailable(_))
+		));
+	}
+
+	#[tokio::test]
+	async fn link_file_links_to_source() {
+		let root = std::env::temp_dir().join(format!("down_on_spot_{}_link", std::process::id()));
+		std::fs::create_dir_all(&root).unwrap();
+		let source = root.join("source.mp3");
+		std::fs::write(&source, b"audio").unwrap();
+
+		for mode in [LinkMode::Hard, LinkMode::Symbolic] {
+			let target = root.join(format!("{:?}.mp3", mode));
+			DownloaderInternal::link_file(&source, &target, mode)
+				.await
+				.unwrap();
+			assert_eq!(std::fs::read(&target).unwrap(), b"audio");
+			assert_eq!(
+				std::fs::symlink_metadata(&target).unwrap().is_symlink(),
+				mode == LinkMode::Symbolic
+			);
 		}
+		std::fs::remove_dir_all(&root).unwrap();
 	}
 }
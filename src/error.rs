@@ -7,6 +7,14 @@ pub enum SpotifyError {
 	MercuryError,
 	AuthenticationError,
 	Unavailable,
+	/// The item or its metadata does not exist
+	NotFound,
+	/// Neither the track nor any alternative is available in the country of the session
+	RegionRestricted,
+	/// None of the files of the track are in a format of this quality or below
+	NoSuitableFormat(String),
+	/// Playlist is private or collaborative and the logged in user has no access to it
+	PlaylistNotAccessible,
 	SpotifyIdError,
 	ChannelError,
 	AudioKeyError,
@@ -20,6 +28,55 @@ pub enum SpotifyError {
 	Reqwest(String),
 	InvalidFormat,
 	AlreadyDownloaded,
+	/// Not available in the minimum quality
+	QualityUnavailable(String),
+	Cancelled,
+	/// Download stalled or took too long
+	Timeout(String),
+	/// Bytes of a copied download and the expected bytes
+	SizeMismatch(u64, u64),
+	/// Left out by a filter of the settings
+	Filtered(String),
+}
+
+impl SpotifyError {
+	/// Process exit code for failing with this error
+	///
+	/// 2: authentication failed, 3: unavailable, 4: IO error, 130: cancelled, 1: anything else
+	pub fn exit_code(&self) -> i32 {
+		match self {
+			SpotifyError::AuthenticationError => 2,
+			SpotifyError::Unavailable
+			| SpotifyError::NotFound
+			| SpotifyError::RegionRestricted
+			| SpotifyError::NoSuitableFormat(_)
+			| SpotifyError::PlaylistNotAccessible => 3,
+			SpotifyError::IoError(_, _) => 4,
+			SpotifyError::Cancelled => 130,
+			_ => 1,
+		}
+	}
+
+	/// Whether the error is likely temporary and worth retrying
+	pub fn is_transient(&self) -> bool {
+		matches!(
+			self,
+			SpotifyError::MercuryError
+				| SpotifyError::ChannelError
+				| SpotifyError::AudioKeyError
+				| SpotifyError::ASpotify(_)
+				| SpotifyError::Reqwest(_)
+				| SpotifyError::Timeout(_)
+				| SpotifyError::SizeMismatch(..)
+				| SpotifyError::IoError(
+					std::io::ErrorKind::ConnectionReset
+						| std::io::ErrorKind::ConnectionAborted
+						| std::io::ErrorKind::TimedOut
+						| std::io::ErrorKind::UnexpectedEof,
+					_
+				)
+		)
+	}
 }
 
 impl std::error::Error for SpotifyError {}
@@ -31,6 +88,13 @@ impl fmt::Display for SpotifyError {
 			SpotifyError::IoError(kind, err) => write!(f, "IO: {:?} {}", kind, err),
 			SpotifyError::AuthenticationError => write!(f, "Authentication Error"),
 			SpotifyError::Unavailable => write!(f, "Unavailable!"),
+			SpotifyError::NotFound => write!(f, "Not found"),
+			SpotifyError::RegionRestricted => write!(f, "Not available in this region"),
+			SpotifyError::NoSuitableFormat(q) => write!(f, "No suitable format for {}", q),
+			SpotifyError::PlaylistNotAccessible => write!(
+				f,
+				"Playlist not accessible, it is private or needs a login with a user it is shared with"
+			),
 			SpotifyError::SpotifyIdError => write!(f, "Invalid Spotify ID"),
 			SpotifyError::ChannelError => write!(f, "Channel Error"),
 			SpotifyError::AudioKeyError => write!(f, "Audio Key Error"),
@@ -44,6 +108,13 @@ impl fmt::Display for SpotifyError {
 			SpotifyError::Reqwest(e) => write!(f, "Reqwest Error: {}", e),
 			SpotifyError::InvalidFormat => write!(f, "Invalid Format!"),
 			SpotifyError::AlreadyDownloaded => write!(f, "Already Downloaded"),
+			SpotifyError::QualityUnavailable(q) => write!(f, "Not available in {}", q),
+			SpotifyError::Cancelled => write!(f, "Cancelled"),
+			SpotifyError::Timeout(e) => write!(f, "Timed out: {}", e),
+			SpotifyError::SizeMismatch(written, expected) => {
+				write!(f, "Incomplete download: {} of {} bytes", written, expected)
+			}
+			SpotifyError::Filtered(reason) => write!(f, "{}", reason),
 		}
 	}
 }
@@ -134,3 +205,9 @@ impl From<lewton::VorbisError> for SpotifyError {
 		SpotifyError::Error(format!("Lewton: {}", e))
 	}
 }
+
+impl From<metaflac::Error> for SpotifyError {
+	fn from(e: metaflac::Error) -> Self {
+		SpotifyError::Error(format!("FLAC: {}", e))
+	}
+}
@@ -0,0 +1,145 @@
+use std::os::raw::{c_int, c_short, c_uchar};
+
+/// vbr_mtrh, the default VBR mode of lame
+const VBR_DEFAULT: c_int = 4;
+
+/// Largest MP3 frame, at 320 kbit/s and 32 kHz with padding
+const MAX_FRAME_SIZE: usize = 1441;
+
+/// Opaque lame_global_flags
+#[repr(C)]
+struct LameGlobalFlags {
+	_private: [u8; 0],
+}
+
+#[link(name = "mp3lame")]
+extern "C" {
+	fn lame_init() -> *mut LameGlobalFlags;
+	fn lame_close(gfp: *mut LameGlobalFlags) -> c_int;
+	fn lame_set_num_channels(gfp: *mut LameGlobalFlags, channels: c_int) -> c_int;
+	fn lame_set_in_samplerate(gfp: *mut LameGlobalFlags, sample_rate: c_int) -> c_int;
+	fn lame_set_brate(gfp: *mut LameGlobalFlags, bitrate: c_int) -> c_int;
+	fn lame_set_quality(gfp: *mut LameGlobalFlags, quality: c_int) -> c_int;
+	fn lame_set_VBR(gfp: *mut LameGlobalFlags, mode: c_int) -> c_int;
+	fn lame_set_VBR_q(gfp: *mut LameGlobalFlags, quality: c_int) -> c_int;
+	fn lame_get_lametag_frame(
+		gfp: *const LameGlobalFlags,
+		buffer: *mut c_uchar,
+		size: usize,
+	) -> usize;
+	fn lame_init_params(gfp: *mut LameGlobalFlags) -> c_int;
+	fn lame_encode_buffer(
+		gfp: *mut LameGlobalFlags,
+		buffer_l: *const c_short,
+		buffer_r: *const c_short,
+		samples: c_int,
+		mp3buf: *mut c_uchar,
+		mp3buf_size: c_int,
+	) -> c_int;
+	fn lame_encode_flush(gfp: *mut LameGlobalFlags, mp3buf: *mut c_uchar, size: c_int) -> c_int;
+}
+
+/// Safe wrapper around a libmp3lame encoder
+pub(crate) struct Lame {
+	ptr: *mut LameGlobalFlags,
+}
+
+impl Lame {
+	/// Create new encoder
+	pub fn new() -> Option<Lame> {
+		let ptr = unsafe { lame_init() };
+		match ptr.is_null() {
+			true => None,
+			false => Some(Lame { ptr }),
+		}
+	}
+
+	pub fn set_channels(&mut self, channels: u8) -> Result<(), i32> {
+		check(unsafe { lame_set_num_channels(self.ptr, channels as c_int) })
+	}
+
+	pub fn set_sample_rate(&mut self, sample_rate: u32) -> Result<(), i32> {
+		check(unsafe { lame_set_in_samplerate(self.ptr, sample_rate as c_int) })
+	}
+
+	pub fn set_kilobitrate(&mut self, bitrate: i32) -> Result<(), i32> {
+		check(unsafe { lame_set_brate(self.ptr, bitrate) })
+	}
+
+	/// Set algorithm quality, 0 is best and 9 is worst
+	pub fn set_quality(&mut self, quality: u8) -> Result<(), i32> {
+		check(unsafe { lame_set_quality(self.ptr, quality as c_int) })
+	}
+
+	/// Encode with variable bitrate of a quality instead of the bitrate, 0 is best and 9 is worst
+	pub fn set_vbr_quality(&mut self, quality: u8) -> Result<(), i32> {
+		check(unsafe { lame_set_VBR(self.ptr, VBR_DEFAULT) })?;
+		check(unsafe { lame_set_VBR_q(self.ptr, quality as c_int) })
+	}
+
+	/// Apply the parameters, has to be called before encoding
+	pub fn init_params(&mut self) -> Result<(), i32> {
+		check(unsafe { lame_init_params(self.ptr) })
+	}
+
+	/// Encode stereo PCM, returns the amount of bytes written to the buffer
+	///
+	/// The buffer should hold at least 1.25 * samples + 7200 bytes
+	pub fn encode(&mut self, left: &[i16], right: &[i16], mp3: &mut [u8]) -> Result<usize, i32> {
+		if left.len() != right.len() {
+			return Err(-1);
+		}
+
+		let size = unsafe {
+			lame_encode_buffer(
+				self.ptr,
+				left.as_ptr(),
+				right.as_ptr(),
+				left.len() as c_int,
+				mp3.as_mut_ptr(),
+				mp3.len() as c_int,
+			)
+		};
+		match size < 0 {
+			true => Err(size),
+			false => Ok(size as usize),
+		}
+	}
+
+	/// Write the remaining buffered frames, returns the amount of bytes written
+	///
+	/// The buffer should hold at least 7200 bytes
+	pub fn flush(&mut self, mp3: &mut [u8]) -> Result<usize, i32> {
+		let size = unsafe { lame_encode_flush(self.ptr, mp3.as_mut_ptr(), mp3.len() as c_int) };
+		match size < 0 {
+			true => Err(size),
+			false => Ok(size as usize),
+		}
+	}
+
+	/// Xing/Info frame with the final frame count and seek table, call after flushing
+	///
+	/// Replaces the placeholder lame wrote as the first frame
+	pub fn lametag_frame(&self) -> Vec<u8> {
+		let mut frame = vec![0; MAX_FRAME_SIZE];
+		let size = unsafe { lame_get_lametag_frame(self.ptr, frame.as_mut_ptr(), frame.len()) };
+		// Nothing is written if the buffer is too small
+		frame.truncate(if size > frame.len() { 0 } else { size });
+		frame
+	}
+}
+
+impl Drop for Lame {
+	fn drop(&mut self) {
+		unsafe {
+			lame_close(self.ptr);
+		}
+	}
+}
+
+fn check(code: c_int) -> Result<(), i32> {
+	match code {
+		0 => Ok(()),
+		e => Err(e),
+	}
+}
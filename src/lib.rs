@@ -0,0 +1,31 @@
+//! Download songs, albums, playlists and artists from Spotify
+//!
+//! Connect with [`Spotify::new`] or wrap an existing session with
+//! [`Spotify::with_session`], then queue URIs on a [`Downloader`] and poll
+//! [`Downloader::get_downloads`] for their [`DownloadState`].
+
+#[macro_use]
+extern crate log;
+
+mod converter;
+mod downloader;
+mod error;
+mod lame;
+mod limiter;
+mod lyrics;
+mod metadata;
+mod naming;
+#[cfg(feature = "opus")]
+mod opus;
+pub mod settings;
+mod spotify;
+mod tag;
+
+pub use downloader::{
+	AudioFormat, Bitrate, CoverSize, DateFormat, Download, DownloadState, Downloader,
+	DownloaderConfig, ExplicitFilter, LinkMode, MetadataSource, OutputFormat, PlannedDownload,
+	Quality, QualityPick, QualityReport, ResolvedFile, SearchResult,
+};
+pub use error::SpotifyError;
+pub use settings::{AuthMethod, ConfigFile, Settings};
+pub use spotify::{AlbumGroup, SearchType, Spotify, SpotifyItem};
@@ -0,0 +1,65 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+/// Limits the throughput shared by all downloads
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+	bytes_per_second: usize,
+	/// End of the time reserved by the bytes taken so far
+	next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+	pub fn new(bytes_per_second: usize) -> RateLimiter {
+		RateLimiter {
+			bytes_per_second: bytes_per_second.max(1),
+			next: Mutex::new(Instant::now()),
+		}
+	}
+
+	/// Take bytes, waiting until the earlier bytes are within the rate
+	pub async fn take(&self, bytes: usize) {
+		let wait = {
+			let mut next = self.next.lock().unwrap();
+			let now = Instant::now();
+			let start = (*next).max(now);
+			*next = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64);
+			start - now
+		};
+		if !wait.is_zero() {
+			time::sleep(wait).await;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test(start_paused = true)]
+	async fn take_waits_for_the_rate() {
+		let limiter = RateLimiter::new(1000);
+		let start = Instant::now();
+		limiter.take(1000).await;
+		assert_eq!(start.elapsed(), Duration::ZERO);
+
+		// The bytes of the first second have to pass before the next
+		limiter.take(100).await;
+		assert_eq!(start.elapsed(), Duration::from_secs(1));
+		for _ in 0..10 {
+			limiter.take(100).await;
+		}
+		assert_eq!(start.elapsed(), Duration::from_secs(2));
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn take_after_idle_does_not_wait() {
+		let limiter = RateLimiter::new(1000);
+		limiter.take(1000).await;
+		time::sleep(Duration::from_secs(5)).await;
+		let start = Instant::now();
+		limiter.take(1000).await;
+		assert_eq!(start.elapsed(), Duration::ZERO);
+	}
+}
@@ -0,0 +1,81 @@
+use librespot::core::keymaster;
+use librespot::core::session::Session;
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+use crate::error::SpotifyError;
+use crate::spotify::KEYMASTER_CLIENT_ID;
+
+#[derive(Debug, Clone, Deserialize)]
+struct LyricsResponse {
+	lyrics: Lyrics,
+}
+
+/// Lyrics of a track
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Lyrics {
+	sync_type: String,
+	lines: Vec<Line>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Line {
+	start_time_ms: String,
+	words: String,
+}
+
+impl Lyrics {
+	/// Fetch lyrics of a track, None if it has no lyrics or no token is issued for them
+	pub(crate) async fn fetch(
+		session: &Session,
+		client: &reqwest::Client,
+		track_id: &str,
+	) -> Result<Option<Lyrics>, SpotifyError> {
+		let token =
+			match keymaster::get_token(session, KEYMASTER_CLIENT_ID, "user-read-private").await {
+				Ok(token) => token,
+				Err(e) => {
+					debug!("No token for lyrics, skipping them: {:?}", e);
+					return Ok(None);
+				}
+			};
+		let res = client
+			.get(format!(
+				"https://spclient.wg.spotify.com/color-lyrics/v2/track/{}?format=json&market=from_token",
+				track_id
+			))
+			.bearer_auth(token.access_token)
+			.header("App-Platform", "WebPlayer")
+			.send()
+			.await?;
+		if res.status() == StatusCode::NOT_FOUND {
+			return Ok(None);
+		}
+		let res: LyricsResponse = serde_json::from_str(&res.error_for_status()?.text().await?)?;
+		Ok(Some(res.lyrics))
+	}
+
+	/// Format as LRC, lines are only timestamped if the lyrics are synced
+	pub(crate) fn to_lrc(&self) -> String {
+		let synced = self.sync_type == "LINE_SYNCED";
+		self.lines
+			.iter()
+			.map(|line| {
+				let ms: u64 = line.start_time_ms.parse().unwrap_or(0);
+				if synced {
+					format!(
+						"[{:02}:{:02}.{:02}]{}\n",
+						ms / 60_000,
+						ms / 1000 % 60,
+						ms % 1000 / 10,
+						line.words
+					)
+				} else {
+					format!("{}\n", line.words)
+				}
+			})
+			.collect()
+	}
+}
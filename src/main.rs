@@ -2,25 +2,30 @@
 extern crate log;
 
 mod arg;
-mod converter;
-mod downloader;
-mod error;
-mod settings;
-mod spotify;
-mod tag;
-
-use arg::Args;
+
+use arg::{Args, ColorMode, OutputMode};
 use async_std::task;
+use clap::ValueEnum;
 use colored::Colorize;
-use downloader::{DownloadState, Downloader};
-use settings::Settings;
-use spotify::Spotify;
+use down_on_spot::settings::{self, ConfigFile};
+use down_on_spot::{
+	Download, DownloadState, Downloader, ExplicitFilter, OutputFormat, Quality, QualityPick,
+	QualityReport, SearchType, Settings, Spotify, SpotifyError,
+};
+use env_logger::{Target, WriteStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use url::Url;
 
 #[cfg(not(windows))]
 #[tokio::main]
 async fn main() {
-	start().await;
+	std::process::exit(start().await);
 }
 
 #[cfg(windows)]
@@ -30,170 +35,1074 @@ async fn main() {
 
 	//backwards compatibility.
 	if control::set_virtual_terminal(true).is_ok() {};
-	start().await;
+	std::process::exit(start().await);
 }
 
-async fn start() {
-	env_logger::init();
+/// Run the program, returns the exit code
+async fn start() -> i32 {
+	let mut args = Args::from_cli();
 
-	let args = Args::from_cli();
+	// NO_COLOR only turns off the automatic colors
+	let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+	colored::control::set_override(match args.color {
+		ColorMode::Always => true,
+		ColorMode::Never => false,
+		ColorMode::Auto => !no_color && std::io::stdout().is_terminal(),
+	});
 
-	let settings = match Settings::load().await {
-		Ok(settings) => {
-			println!(
-				"{} {}.",
-				"Settings successfully loaded.\nContinuing with spotify account:".green(),
-				settings.username
-			);
-			settings
+	// Draw a progress bar per download only when attached to a terminal,
+	// otherwise print a line whenever the state of a download changes
+	let interactive = !args.no_progress
+		&& args.output_format == OutputMode::Text
+		&& std::io::stdout().is_terminal();
+	let multi = MultiProgress::with_draw_target(ProgressDrawTarget::stdout());
+
+	// Info is logged by default unless RUST_LOG is set, only errors when the audio is
+	// written to stdout
+	let mut logger = env_logger::Builder::from_default_env();
+	match args.log_filter() {
+		Some(level) => {
+			logger.filter_level(level);
+		}
+		None if args.stdout => {
+			logger.filter_level(log::LevelFilter::Error);
+		}
+		None if std::env::var_os("RUST_LOG").is_none() => {
+			logger.filter_level(log::LevelFilter::Info);
+		}
+		None => {}
+	}
+	match args.color {
+		ColorMode::Always => logger.write_style(WriteStyle::Always),
+		ColorMode::Never => logger.write_style(WriteStyle::Never),
+		ColorMode::Auto if no_color => logger.write_style(WriteStyle::Never),
+		// The log goes through the progress bars then, which can not tell if stderr is a terminal
+		ColorMode::Auto if interactive && std::io::stderr().is_terminal() => {
+			logger.write_style(WriteStyle::Always)
+		}
+		// Colored if stderr is a terminal
+		ColorMode::Auto => logger.write_style(WriteStyle::Auto),
+	};
+	if interactive {
+		logger.target(Target::Pipe(Box::new(LogWriter(multi.clone()))));
+	}
+	logger.init();
+
+	// Anything else printed to stdout would break up the audio or the JSON events
+	let quiet = args.stdout || args.output_format == OutputMode::Json;
+
+	let config_file = match ConfigFile::load().await {
+		Ok(config_file) => config_file,
+		Err(e) => {
+			eprintln!("{} {}", "Failed loading the config file:".red(), e);
+			return e.exit_code();
+		}
+	};
+
+	// Precedence: options > down_on_spot.toml > environment variables > settings.json
+	let mut settings = match Settings::load().await {
+		Ok(settings) => settings,
+		// The config file can stand in for the settings file
+		Err(SpotifyError::IoError(std::io::ErrorKind::NotFound, _)) if config_file.is_some() => {
+			Settings::new("", "", "", "")
 		}
 		Err(e) => {
-			println!(
+			eprintln!(
 				"{} {}...",
 				"Settings could not be loaded, because of the following error:".red(),
 				e
 			);
-			let default_settings = Settings::new("username", "password", "client_id", "secret");
+			let default_settings = Settings::new("username", "password", "", "");
 			match default_settings.save().await {
 				Ok(path) => {
-					println!(
+					eprintln!(
 						"{}{}",
 						"..but default settings have been created successfully. Edit them and run the program again.\nFind the settings file at: ".green(),
 						path.to_string_lossy()
 					);
 				}
 				Err(e) => {
-					println!(
+					eprintln!(
 						"{} {}",
 						"..and default settings could not be written:".red(),
 						e
 					);
 				}
 			};
-			return;
+			return 1;
+		}
+	};
+	if let Some(config_file) = &config_file {
+		config_file.apply(&mut settings);
+		args.merge_config(config_file);
+	}
+	if !quiet {
+		println!(
+			"{} {}.",
+			"Settings successfully loaded.\nContinuing with spotify account:".green(),
+			settings.username
+		);
+	}
+
+	if let Some(client_id) = args.client_id.clone() {
+		settings.client_id = client_id;
+	}
+	if let Some(client_secret) = args.client_secret.clone() {
+		settings.client_secret = client_secret;
+	}
+	if let Some(format) = args.format {
+		settings.downloader.format = format;
+	}
+	if let Some(output) = args.output.clone() {
+		settings.downloader.path = output;
+	}
+	if let Some(filename_template) = args.filename_template.clone() {
+		settings.downloader.filename_template = filename_template;
+	}
+	if let Some(quality) = args.quality {
+		settings.downloader.quality = quality;
+	}
+	if let Some(concurrent_downloads) = args.concurrent_downloads {
+		settings.downloader.concurrent_downloads = concurrent_downloads;
+	}
+	if args.no_transcode {
+		settings.downloader.format = OutputFormat::Original;
+	}
+	if let Some(bitrate) = args.bitrate {
+		settings.downloader.bitrate = Some(bitrate);
+	}
+	if let Some(mp3_quality) = args.mp3_quality {
+		settings.downloader.mp3_quality = mp3_quality;
+	}
+	if let Some(vbr) = args.vbr {
+		settings.downloader.vbr = Some(vbr);
+	}
+	if args.dither {
+		settings.downloader.dither = true;
+	}
+	if let Some(retries) = args.retries {
+		settings.downloader.retries = retries;
+	}
+	if args.write_m3u {
+		settings.downloader.write_m3u = true;
+	}
+	if let Some(include) = &args.include {
+		settings.downloader.artist_groups = include.clone();
+	}
+	settings.downloader.offset = args.offset;
+	// Positions are counted from 1 like the numbering of the tracks
+	if let Some(start_at) = args.start_at {
+		settings.downloader.offset = start_at as usize - 1;
+	}
+	settings.downloader.limit = args.limit;
+	settings.downloader.added_after = args.added_after;
+	if let Some(number_from) = args.number_from {
+		settings.downloader.number_from = number_from;
+	}
+	if let Some(width) = args.playlist_index_width {
+		settings.downloader.playlist_index_width = Some(width);
+	}
+	if args.renumber_playlist {
+		settings.downloader.renumber_playlist = true;
+	}
+	if args.no_playlist_numbering {
+		settings.downloader.playlist_numbering = false;
+	}
+	if args.flat_numbered {
+		settings.downloader.flat_numbered = true;
+	}
+	if args.lyrics {
+		settings.downloader.lyrics = true;
+	}
+	if args.write_metadata_json {
+		settings.downloader.write_metadata_json = true;
+	}
+	if args.dedupe {
+		settings.downloader.dedupe = true;
+	}
+	if let Some(mode) = args.dedupe_link {
+		settings.downloader.dedupe = true;
+		settings.downloader.dedupe_link = Some(mode);
+	}
+	if args.artists_in_order {
+		settings.downloader.artists_in_order = true;
+	}
+	if args.strict {
+		settings.downloader.strict = true;
+	}
+	settings.downloader.raw = args.raw;
+	if let Some(cover_size) = args.cover_size {
+		settings.downloader.cover_size = cover_size;
+	}
+	if let Some(date_format) = args.date_format {
+		settings.downloader.date_format = date_format;
+	}
+	if let Some(metadata_source) = args.metadata_source {
+		settings.downloader.metadata_source = metadata_source;
+	}
+	if args.save_cover {
+		settings.downloader.save_cover = true;
+	}
+	if let Some(timeout) = args.timeout {
+		settings.downloader.timeout = Some(timeout);
+	}
+	if let Some(track_timeout) = args.track_timeout {
+		settings.downloader.track_timeout = Some(track_timeout);
+	}
+	if args.skip_explicit {
+		settings.downloader.explicit = ExplicitFilter::Skip;
+	} else if args.only_explicit {
+		settings.downloader.explicit = ExplicitFilter::Only;
+	}
+	if let Some(market) = args.market {
+		settings.market_country_code = Some(market);
+	}
+	if args.normalize {
+		settings.downloader.normalize = true;
+	}
+	if args.overwrite {
+		settings.downloader.skip_existing = false;
+	}
+	if args.match_by_id {
+		settings.downloader.match_existing_by_id = true;
+	}
+	if let Some(max_rate) = args.max_rate {
+		settings.downloader.max_rate = Some(max_rate);
+	}
+	if let Some(min_quality) = args.min_quality {
+		settings.downloader.min_quality = Some(min_quality);
+	}
+	if settings.downloader.normalize && settings.downloader.format == OutputFormat::Original {
+		warn!("Normalization only applies to converted formats, the original files are kept as is");
+	}
+
+	if let Some(auth_method) = args.auth_method {
+		settings.auth_method = auth_method;
+	}
+
+	let proxy = args
+		.proxy
+		.clone()
+		.or_else(|| settings.proxy.clone())
+		.or_else(|| {
+			["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy"]
+				.iter()
+				.find_map(|var| std::env::var(var).ok())
+		})
+		.filter(|proxy| !proxy.is_empty());
+	let proxy = match proxy.map(|proxy| (Url::parse(&proxy), proxy)) {
+		// librespot can only tunnel through HTTP proxies
+		Some((Ok(url), _)) if url.scheme() == "http" && url.host().is_some() => Some(url),
+		Some((_, proxy)) => {
+			eprintln!(
+				"{} {}",
+				"Invalid proxy, expected an HTTP proxy URL like http://localhost:8080:".red(),
+				proxy
+			);
+			return 1;
 		}
+		None => None,
 	};
 
+	let cache_dir = args
+		.cache_dir
+		.clone()
+		.or_else(|| settings.cache_dir.clone())
+		.map(PathBuf::from)
+		.unwrap_or_else(settings::get_cache_path);
+	let audio_cache_dir = match args.no_audio_cache {
+		true => None,
+		false => args
+			.audio_cache_dir
+			.clone()
+			.or_else(|| settings.audio_cache_dir.clone()),
+	};
+	let cache = match Spotify::cache(
+		&cache_dir,
+		audio_cache_dir.as_deref().map(Path::new),
+		settings.audio_cache_limit,
+	) {
+		Ok(cache) => cache,
+		Err(e) => {
+			eprintln!("{} {}", "Failed opening the cache:".red(), e);
+			return e.exit_code();
+		}
+	};
 	let spotify = match Spotify::new(
-		&settings.username,
-		&settings.password,
+		settings.credentials(),
 		&settings.client_id,
 		&settings.client_secret,
 		settings.market_country_code,
+		proxy,
+		args.device_id
+			.clone()
+			.or_else(|| settings.device_id.clone()),
+		cache,
 	)
 	.await
 	{
 		Ok(spotify) => {
-			println!("{}", "Login succeeded.".green());
+			if !quiet {
+				println!("{}", "Login succeeded.".green());
+			}
+			if !spotify.has_web_api() {
+				info!("No client_id and client_secret set, using librespot metadata. Searching and artists need them.");
+			}
 			spotify
 		}
 		Err(e) => {
-			println!(
+			eprintln!(
 				"{} {}",
 				"Login failed, possibly due to invalid credentials or settings:".red(),
 				e
 			);
-			return;
+			return e.exit_code();
 		}
 	};
 
+	let strict = settings.downloader.strict;
 	let downloader = Downloader::new(settings.downloader, spotify);
-	match downloader.handle_input(&args.input).await {
-		Ok(search_results) => {
-			if let Some(search_results) = search_results {
-				print!("{esc}[2J{esc}[1;1H", esc = 27 as char);
+	let inputs = match read_inputs(&args).await {
+		Ok(inputs) => inputs,
+		Err(e) => {
+			eprintln!("{} {}", "Reading input file failed:".red(), e);
+			return e.exit_code();
+		}
+	};
+	if args.dry_run {
+		return dry_run(&downloader, &inputs).await;
+	}
+	if args.simulate_quality {
+		return simulate_quality(&downloader, &inputs).await;
+	}
+	if args.stdout {
+		return write_stdout(&downloader, args.input.as_deref().unwrap_or_default()).await;
+	}
+	if let Err(e) = downloader.check_output_dir().await {
+		eprintln!("{} {}", "Output directory is not writable:".red(), e);
+		return e.exit_code();
+	}
+	let batch = match &args.input_file {
+		Some(_) => add_batch(&downloader, &inputs, args.search_type, args.fail_fast).await,
+		None => match downloader
+			.handle_input(args.input.as_deref().unwrap_or_default(), args.search_type)
+			.await
+		{
+			Ok(search_results) => {
+				if let Some(search_results) = search_results {
+					if search_results.is_empty() {
+						println!("{}", "No search results found.".red());
+						return 1;
+					}
 
-				for (i, track) in search_results.iter().enumerate() {
-					println!("{}: {} - {}", i + 1, track.author, track.title);
-				}
-				println!("{}", "Select the track (default: 1): ".green());
+					print!("{esc}[2J{esc}[1;1H", esc = 27 as char);
 
-				let mut selection;
-				loop {
-					let mut input = String::new();
-					std::io::stdin()
-						.read_line(&mut input)
-						.expect("Failed to read line");
+					for (i, result) in search_results.iter().enumerate() {
+						println!("{}: {} - {}", i + 1, result.author, result.title);
+					}
+					println!("{}", "Select the result (default: 1): ".green());
 
-					selection = input.trim().parse::<usize>().unwrap_or(1) - 1;
+					let mut selection;
+					loop {
+						let mut input = String::new();
+						std::io::stdin()
+							.read_line(&mut input)
+							.expect("Failed to read line");
 
-					if selection < search_results.len() {
-						break;
+						selection = input.trim().parse::<usize>().unwrap_or(1) - 1;
+
+						if selection < search_results.len() {
+							break;
+						}
+						println!("{}", "Invalid selection. Try again or quit (CTRL+C):".red());
 					}
-					println!("{}", "Invalid selection. Try again or quit (CTRL+C):".red());
-				}
 
-				let track = &search_results[selection];
-
-				if let Err(e) = downloader
-					.add_uri(&format!("spotify:track:{}", track.track_id))
-					.await
-				{
-					error!(
-						"{}",
-						format!(
-							"{}: {}",
-							"Track could not be added to download queue.".red(),
-							e
-						)
-					);
-					return;
+					let result = &search_results[selection];
+
+					if let Err(e) = downloader.add_uri(&result.uri).await {
+						error!(
+							"{}",
+							format!(
+								"{}: {}",
+								"Selection could not be added to download queue.".red(),
+								e
+							)
+						);
+						return e.exit_code();
+					}
 				}
+
+				vec![]
 			}
+			Err(e) => {
+				error!("{} {}", "Handling input failed:".red(), e);
+				return e.exit_code();
+			}
+		},
+	};
 
-			let refresh = Duration::from_secs(settings.refresh_ui_seconds);
-			let now = Instant::now();
-			let mut time_elapsed: u64;
-
-			'outer: loop {
-				print!("{esc}[2J{esc}[1;1H", esc = 27 as char);
-				let mut exit_flag: i8 = 1;
-
-				for download in downloader.get_downloads().await {
-					let state = download.state;
-
-					let progress = if state != DownloadState::Done {
-						match state {
-							DownloadState::Downloading(r, t) => {
-								exit_flag &= 0;
-								let p = r as f32 / t as f32 * 100.0;
-								if p > 100.0 {
-									"100%".to_string()
-								} else {
-									format!("{}%", p as i8)
-								}
-							}
-							DownloadState::Post => {
-								exit_flag &= 0;
-								"Postprocessing... ".to_string()
-							}
-							DownloadState::None | DownloadState::Lock => {
-								exit_flag &= 0;
-								"Preparing... ".to_string()
-							}
-							DownloadState::Error(e) => {
-								format!("{} ", e)
-							}
-							DownloadState::Done => "Impossible state".to_string(),
-						}
-					} else {
-						"Done.".to_string()
-					};
+	let batch_failed = batch.iter().any(|(_, result)| result.is_err());
+	if args.fail_fast && batch_failed {
+		downloader.cancel().await;
+		print_batch(&inputs, &batch);
+		save_manifest(
+			args.json_manifest.as_deref(),
+			&inputs,
+			&batch,
+			&downloader.get_downloads().await,
+		)
+		.await;
+		return 1;
+	}
+
+	if !quiet && downloader.get_downloads().await.is_empty() {
+		println!("{}", "Nothing to download.".yellow());
+		print_batch(&inputs, &batch);
+		let saved = save_manifest(args.json_manifest.as_deref(), &inputs, &batch, &[]).await;
+		return if batch_failed || !saved { 1 } else { 0 };
+	}
+
+	// Cancel the downloads on the first Ctrl-C, exit on the second
+	let cancel_downloader = downloader.clone();
+	tokio::spawn(async move {
+		if tokio::signal::ctrl_c().await.is_ok() {
+			eprintln!("{}", "Cancelling downloads...".yellow());
+			cancel_downloader.cancel().await;
+		}
+		if tokio::signal::ctrl_c().await.is_ok() {
+			std::process::exit(SpotifyError::Cancelled.exit_code());
+		}
+	});
+
+	let refresh = Duration::from_secs(settings.refresh_ui_seconds);
+	let now = Instant::now();
+	let mut time_elapsed: u64;
+	let mut failed: usize;
+	let mut downloads: Vec<Download>;
+
+	let mut last_status: HashMap<i64, String> = HashMap::new();
+	let mut started: HashSet<i64> = HashSet::new();
+	let mut bars: HashMap<i64, ProgressBar> = HashMap::new();
+	let bar_style = ProgressStyle::with_template(
+		"[{bar:20}] {percent:>3}% {bytes:>10}/{total_bytes:<10} | {msg}",
+	)
+	.unwrap()
+	.progress_chars("#-");
+	let overall = match interactive {
+		true => multi.add(ProgressBar::new(0).with_style(
+			ProgressStyle::with_template("Elapsed {elapsed} | {pos}/{len} finished").unwrap(),
+		)),
+		false => ProgressBar::hidden(),
+	};
+
+	'outer: loop {
+		let mut exit_flag: i8 = 1;
+		failed = 0;
+
+		downloads = downloader.get_downloads().await;
+
+		for download in &downloads {
+			let state = &download.state;
+
+			let status = match state {
+				DownloadState::Downloading(_, _) => {
+					exit_flag &= 0;
+					"Downloading... ".to_string()
+				}
+				DownloadState::Post => {
+					exit_flag &= 0;
+					"Postprocessing... ".to_string()
+				}
+				DownloadState::None | DownloadState::Lock => {
+					exit_flag &= 0;
+					"Preparing... ".to_string()
+				}
+				DownloadState::Error(e) => {
+					failed += 1;
+					format!("{} ", e)
+				}
+				DownloadState::Skipped(reason) => {
+					format!("{} ", reason)
+				}
+				DownloadState::Done => "Done.".to_string(),
+			};
 
-					println!("{:<19}| {}", progress, download.title);
+			if interactive {
+				let title = format!(
+					"{} ({})",
+					download.title,
+					format_duration(download.duration)
+				);
+				match *state {
+					DownloadState::Downloading(read, total) => {
+						let bar = bars.entry(download.id).or_insert_with(|| {
+							multi.add(ProgressBar::new(total as u64).with_style(bar_style.clone()))
+						});
+						bar.set_length(total as u64);
+						bar.set_position(read as u64);
+						bar.set_message(title);
+					}
+					DownloadState::Post => {
+						if let Some(bar) = bars.get(&download.id) {
+							bar.set_message(format!("{}{}", status, title));
+						}
+					}
+					DownloadState::None | DownloadState::Lock => {}
+					// Finished downloads replace their bar with a line above the others
+					_ => {
+						if let Some(bar) = bars.remove(&download.id) {
+							bar.finish_and_clear();
+							multi.remove(&bar);
+						}
+						let line = format!("{:<19}| {}", status, title);
+						if last_status.get(&download.id) != Some(&line) {
+							multi.println(&line).ok();
+							last_status.insert(download.id, line);
+						}
+					}
 				}
-				time_elapsed = now.elapsed().as_secs();
-				if exit_flag == 1 {
-					break 'outer;
+				continue;
+			}
+
+			// Announce the track with its size once the download started
+			if let DownloadState::Downloading(_, total) = download.state {
+				if started.insert(download.id) {
+					match args.output_format {
+						OutputMode::Text => println!(
+							"{:<19}| {} ({}, {})",
+							"Started",
+							download.title,
+							format_duration(download.duration),
+							format_size(total)
+						),
+						OutputMode::Json => println!("{}", started_event(download, total)),
+					}
 				}
+			}
 
-				println!("\nElapsed second(s): {}", time_elapsed);
-				task::sleep(refresh).await
+			let line = match args.output_format {
+				OutputMode::Text => format!("{:<19}| {}", status, download.title),
+				OutputMode::Json => json_event(download),
+			};
+			if last_status.get(&download.id) != Some(&line) {
+				println!("{}", line);
+				last_status.insert(download.id, line);
 			}
+		}
+		overall.set_length(downloads.len() as u64);
+		overall.set_position(last_status.len() as u64);
+		time_elapsed = now.elapsed().as_secs();
+		if exit_flag == 1 {
+			break 'outer;
+		}
+		task::sleep(refresh).await
+	}
+	overall.finish_and_clear();
+	match args.output_format {
+		OutputMode::Text => {
 			println!("Finished download(s) in {} second(s).", time_elapsed);
+			print_summary(&downloads);
+		}
+		OutputMode::Json => println!(
+			"{}",
+			json!({
+				"event": "finished",
+				"done": count(&downloads, |s| *s == DownloadState::Done),
+				"skipped": count(&downloads, |s| matches!(s, DownloadState::Skipped(_))),
+				"failed": failed,
+				"seconds": time_elapsed,
+			})
+		),
+	}
+
+	print_batch(&inputs, &batch);
+	let saved = save_manifest(args.json_manifest.as_deref(), &inputs, &batch, &downloads).await;
+	// Failures of strict mode stop the downloads like a cancellation
+	let cancelled = SpotifyError::Cancelled.to_string();
+	let stopped = strict
+		&& downloads
+			.iter()
+			.any(|d| matches!(&d.state, DownloadState::Error(e) if *e != cancelled));
+	if stopped {
+		eprintln!(
+			"{}",
+			"Stopped after a failure, the downloaded tracks are incomplete.".red()
+		);
+		return 1;
+	}
+	if downloader.is_cancelled() {
+		return SpotifyError::Cancelled.exit_code();
+	}
+	if failed > 0 || batch_failed || !saved {
+		return 1;
+	}
+	0
+}
+
+/// Inputs of the command line, or the lines of the input file without empty lines and comments
+async fn read_inputs(args: &Args) -> Result<Vec<String>, SpotifyError> {
+	let Some(path) = &args.input_file else {
+		return Ok(args.input.clone().into_iter().collect());
+	};
+	let content = if path.as_os_str() == "-" {
+		let mut content = String::new();
+		tokio::io::stdin().read_to_string(&mut content).await?;
+		content
+	} else {
+		tokio::fs::read_to_string(path).await?
+	};
+	Ok(content
+		.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty() && !line.starts_with('#'))
+		.map(String::from)
+		.collect())
+}
+
+/// Add the inputs to the queue one after another, the first result for search terms,
+/// returning the result of every attempted input
+///
+/// With fail_fast the inputs after the first failing one are not attempted
+async fn add_batch(
+	downloader: &Downloader,
+	inputs: &[String],
+	search_type: SearchType,
+	fail_fast: bool,
+) -> Vec<(String, Result<(), SpotifyError>)> {
+	let mut results = vec![];
+	for input in inputs {
+		let result = match downloader.handle_input(input, search_type).await {
+			Ok(Some(found)) => match found.first() {
+				Some(result) => downloader.add_uri(&result.uri).await,
+				None => Err(SpotifyError::Error("No search results found".into())),
+			},
+			Ok(None) => Ok(()),
+			Err(e) => Err(e),
+		};
+		if let Err(e) = &result {
+			warn!("Failed adding {}: {}", input, e);
+		}
+		let stop = fail_fast && result.is_err();
+		results.push((input.clone(), result));
+		if stop {
+			break;
+		}
+	}
+	results
+}
+
+/// Print the status of every line of the input file to stderr
+fn print_batch(inputs: &[String], batch: &[(String, Result<(), SpotifyError>)]) {
+	if batch.is_empty() {
+		return;
+	}
+	eprintln!("\nInputs:");
+	for (i, input) in inputs.iter().enumerate() {
+		let status = match batch.get(i) {
+			Some((_, Ok(()))) => "Added".green(),
+			Some((_, Err(e))) => e.to_string().red(),
+			None => "Not attempted".yellow(),
+		};
+		eprintln!("{} | {}", input, status);
+	}
+}
+
+/// Print the tracks of the inputs with their format and path without downloading
+async fn dry_run(downloader: &Downloader, inputs: &[String]) -> i32 {
+	let mut planned = vec![];
+	let mut code = 0;
+	for input in inputs {
+		match downloader.plan_uri(input).await {
+			Ok(mut p) => planned.append(&mut p),
+			Err(e) => {
+				error!("{} {} {}", "Resolving input failed:".red(), input, e);
+				code = e.exit_code();
+			}
+		}
+	}
+
+	let mut unavailable = 0;
+	for (download, plan) in &planned {
+		match plan {
+			Ok(plan) => println!(
+				"{:<5}| {} - {} | {}",
+				plan.format.extension(),
+				plan.artist,
+				download.title,
+				plan.path.display()
+			),
+			Err(e) => {
+				unavailable += 1;
+				println!("{:<5}| {} | {}", "-", download.title, e.to_string().red());
+			}
+		}
+	}
+	println!("{} tracks, {} unavailable", planned.len(), unavailable);
+	code
+}
+
+/// Print the available formats of the tracks of the inputs and the file each quality would pick
+async fn simulate_quality(downloader: &Downloader, inputs: &[String]) -> i32 {
+	let mut reports = vec![];
+	let mut code = 0;
+	for input in inputs {
+		match downloader.audit_uri(input).await {
+			Ok(mut r) => reports.append(&mut r),
+			Err(e) => {
+				error!("{} {} {}", "Resolving input failed:".red(), input, e);
+				code = e.exit_code();
+			}
+		}
+	}
+
+	let mut header = String::new();
+	for quality in Quality::value_variants() {
+		header.push_str(&format!("{:<9}", quality.kbps()));
+	}
+	println!("{}{:<9}| Track | Available", header, "Selected");
+	let mut unavailable = 0;
+	for (download, report) in &reports {
+		match report {
+			Ok(report) => println!("{}", quality_row(report, &download.title)),
+			Err(e) => {
+				unavailable += 1;
+				println!("{:<45}| {} | {}", "-", download.title, e.to_string().red());
+			}
+		}
+	}
+	println!("{} tracks, {} unavailable", reports.len(), unavailable);
+	code
+}
+
+/// Row of the quality audit, with the file picked by each quality and the selected one
+fn quality_row(report: &QualityReport, title: &str) -> String {
+	let pick = |pick: &QualityPick| match pick {
+		Ok((quality, format)) => format!("{} {}", format.extension(), quality.kbps()),
+		Err(_) => "-".to_string(),
+	};
+	let mut picks = String::new();
+	for (_, p) in &report.picks {
+		picks.push_str(&format!("{:<9}", pick(p)));
+	}
+	format!(
+		"{}{:<9}| {} | {}",
+		picks,
+		pick(&report.selected),
+		title,
+		report.available.join(", ")
+	)
+}
+
+/// Write the audio of a single track to stdout, reporting to stderr
+async fn write_stdout(downloader: &Downloader, input: &str) -> i32 {
+	let uri = match downloader.parse_input(input).await {
+		Ok(uri) => uri,
+		Err(e) => {
+			eprintln!("{} {}", "Invalid track URL or URI:".red(), e);
+			return e.exit_code();
+		}
+	};
+	let Some(track_id) = uri.strip_prefix("spotify:track:") else {
+		eprintln!(
+			"{}",
+			"Only a single track can be written to stdout, not an album, playlist or artist.".red()
+		);
+		return 1;
+	};
+
+	eprintln!("Downloading {}...", track_id);
+	match downloader.download_to(track_id, tokio::io::stdout()).await {
+		Ok(_) => {
+			eprintln!("{}", "Done.".green());
+			0
 		}
 		Err(e) => {
-			error!("{} {}", "Handling input failed:".red(), e)
+			eprintln!("{} {}", "Download failed:".red(), e);
+			e.exit_code()
 		}
 	}
 }
+
+/// Number of downloads in a state
+fn count(downloads: &[Download], f: impl Fn(&DownloadState) -> bool) -> usize {
+	downloads.iter().filter(|d| f(&d.state)).count()
+}
+
+/// Print how many downloads succeeded, were skipped or failed, with the errors
+fn print_summary(downloads: &[Download]) {
+	let failed: Vec<(&str, &str)> = downloads
+		.iter()
+		.filter_map(|d| match &d.state {
+			DownloadState::Error(e) => Some((e.as_str(), d.title.as_str())),
+			_ => None,
+		})
+		.collect();
+	println!(
+		"{} downloaded, {} skipped, {} failed.",
+		count(downloads, |s| *s == DownloadState::Done)
+			.to_string()
+			.green(),
+		count(downloads, |s| matches!(s, DownloadState::Skipped(_)))
+			.to_string()
+			.yellow(),
+		failed.len().to_string().red()
+	);
+	for (e, title) in failed {
+		println!("{:<19}| {}", e.red(), title);
+	}
+}
+
+/// Version of the manifest format, increased on incompatible changes
+const MANIFEST_VERSION: u32 = 1;
+
+/// Write the JSON manifest if requested, returns false if writing it failed
+async fn save_manifest(
+	path: Option<&Path>,
+	inputs: &[String],
+	batch: &[(String, Result<(), SpotifyError>)],
+	downloads: &[Download],
+) -> bool {
+	let Some(path) = path else {
+		return true;
+	};
+	let manifest = manifest(inputs, batch, downloads);
+	let result = match serde_json::to_string_pretty(&manifest) {
+		Ok(json) => tokio::fs::write(path, json)
+			.await
+			.map_err(SpotifyError::from),
+		Err(e) => Err(e.into()),
+	};
+	if let Err(e) = result {
+		error!("{} {}", "Writing the manifest failed:".red(), e);
+		return false;
+	}
+	true
+}
+
+/// Outcome of every input and track of the run, without timings so runs can be compared
+fn manifest(
+	inputs: &[String],
+	batch: &[(String, Result<(), SpotifyError>)],
+	downloads: &[Download],
+) -> serde_json::Value {
+	let inputs: Vec<_> = inputs
+		.iter()
+		.enumerate()
+		.map(|(i, input)| {
+			let (status, reason) = match batch.get(i) {
+				Some((_, Ok(()))) => ("added", None),
+				Some((_, Err(e))) => ("failed", Some(e.to_string())),
+				// Single inputs which could not be added end the run before
+				None if batch.is_empty() => ("added", None),
+				None => ("not_attempted", None),
+			};
+			json!({"input": input, "status": status, "reason": reason})
+		})
+		.collect();
+	let tracks: Vec<_> = downloads
+		.iter()
+		.map(|d| {
+			let (outcome, reason) = match &d.state {
+				DownloadState::Done => ("downloaded", None),
+				DownloadState::Skipped(reason) => ("skipped", Some(reason.as_str())),
+				DownloadState::Error(e) => ("failed", Some(e.as_str())),
+				_ => ("unfinished", None),
+			};
+			json!({
+				"id": d.id,
+				"track": d.track_id,
+				"title": d.title,
+				"duration": d.duration.as_secs(),
+				"playlist_index": d.playlist_index,
+				"outcome": outcome,
+				"reason": reason,
+				"path": d.path.as_ref().map(|p| p.display().to_string()),
+				"format": d.format.as_ref().map(|f| f.extension()),
+				"bitrate": d.bitrate,
+			})
+		})
+		.collect();
+	json!({
+		"schema_version": MANIFEST_VERSION,
+		"inputs": inputs,
+		"tracks": tracks,
+		"downloaded": count(downloads, |s| *s == DownloadState::Done),
+		"skipped": count(downloads, |s| matches!(s, DownloadState::Skipped(_))),
+		"failed": count(downloads, |s| matches!(s, DownloadState::Error(_))),
+	})
+}
+
+/// Single line JSON event describing the state of a download
+fn json_event(download: &Download) -> String {
+	let mut event = match &download.state {
+		DownloadState::None | DownloadState::Lock => json!({"event": "queued"}),
+		DownloadState::Downloading(current, total) => {
+			json!({"event": "progress", "current": current, "total": total})
+		}
+		DownloadState::Post => json!({"event": "postprocessing"}),
+		DownloadState::Done => json!({
+			"event": "done",
+			"path": download.path.as_ref().map(|p| p.display().to_string()),
+		}),
+		DownloadState::Skipped(reason) => json!({"event": "skipped", "reason": reason}),
+		DownloadState::Error(e) => json!({"event": "error", "reason": e}),
+	};
+	event["id"] = json!(download.id);
+	event["track"] = json!(download.track_id);
+	event["title"] = json!(download.title);
+	event["duration"] = json!(download.duration.as_secs());
+	event.to_string()
+}
+
+/// Single line JSON event of a download which started, with its total size
+fn started_event(download: &Download, total: usize) -> String {
+	json!({
+		"event": "started",
+		"id": download.id,
+		"track": download.track_id,
+		"title": download.title,
+		"duration": download.duration.as_secs(),
+		"total": total,
+	})
+	.to_string()
+}
+
+/// Writes the log above the progress bars instead of into them
+struct LogWriter(MultiProgress);
+
+impl Write for LogWriter {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.0.suspend(|| std::io::stderr().write(buf))
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		std::io::stderr().flush()
+	}
+}
+
+/// Format a duration as minutes and seconds
+fn format_duration(duration: Duration) -> String {
+	let secs = duration.as_secs();
+	format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+/// Format bytes as MiB
+fn format_size(bytes: usize) -> String {
+	format!("{:.1} MiB", bytes as f32 / 1024.0 / 1024.0)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use down_on_spot::AudioFormat;
+
+	fn download(id: i64, state: DownloadState) -> Download {
+		Download {
+			id,
+			track_id: "6ocbgoVGwYJhOv1GgI9NsF".to_string(),
+			title: "7 rings".to_string(),
+			duration: Duration::from_secs(178),
+			path: None,
+			state,
+			playlist_index: None,
+			format: None,
+			bitrate: None,
+			duplicate_of: None,
+		}
+	}
+
+	#[test]
+	fn manifest_lists_inputs_and_tracks() {
+		let inputs = vec![
+			"first".to_string(),
+			"second".to_string(),
+			"third".to_string(),
+		];
+		let batch = vec![
+			("first".to_string(), Ok(())),
+			(
+				"second".to_string(),
+				Err(SpotifyError::Error("Not found".to_string())),
+			),
+		];
+		let mut done = download(0, DownloadState::Done);
+		done.playlist_index = Some("01".to_string());
+		done.path = Some(PathBuf::from("downloads/7 rings.mp3"));
+		done.format = Some(AudioFormat::Mp3);
+		done.bitrate = Some(320);
+		let downloads = vec![
+			done,
+			download(1, DownloadState::Skipped("Already downloaded".to_string())),
+			download(
+				2,
+				DownloadState::Error("Not available in this region".to_string()),
+			),
+		];
+
+		let manifest = manifest(&inputs, &batch, &downloads);
+		assert_eq!(manifest["schema_version"], MANIFEST_VERSION);
+		assert_eq!(
+			manifest["inputs"],
+			json!([
+				{"input": "first", "status": "added", "reason": null},
+				{"input": "second", "status": "failed", "reason": "Error: Not found"},
+				{"input": "third", "status": "not_attempted", "reason": null},
+			])
+		);
+		assert_eq!(
+			manifest["tracks"][0],
+			json!({
+				"id": 0,
+				"track": "6ocbgoVGwYJhOv1GgI9NsF",
+				"title": "7 rings",
+				"duration": 178,
+				"playlist_index": "01",
+				"outcome": "downloaded",
+				"reason": null,
+				"path": "downloads/7 rings.mp3",
+				"format": "mp3",
+				"bitrate": 320,
+			})
+		);
+		assert_eq!(manifest["tracks"][1]["outcome"], "skipped");
+		assert_eq!(manifest["tracks"][1]["reason"], "Already downloaded");
+		assert_eq!(manifest["tracks"][2]["outcome"], "failed");
+		assert_eq!(manifest["tracks"][2]["format"], json!(null));
+		assert_eq!(
+			(
+				&manifest["downloaded"],
+				&manifest["skipped"],
+				&manifest["failed"]
+			),
+			(&json!(1), &json!(1), &json!(1))
+		);
+	}
+
+	#[test]
+	fn start_at_counts_from_one() {
+		use clap::Parser;
+		let parse =
+			|args: &[&str]| Args::try_parse_from([&["down_on_spot", "liked"], args].concat());
+		assert_eq!(parse(&["--start-at", "250"]).unwrap().start_at, Some(250));
+		assert!(parse(&["--start-at", "0"]).is_err());
+		assert!(parse(&["--start-at", "250", "--offset", "249"]).is_err());
+	}
+
+	#[test]
+	fn quality_row_lists_picks() {
+		let report = QualityReport {
+			available: vec!["MP3_96".to_string(), "OGG_VORBIS_160".to_string()],
+			picks: vec![
+				(Quality::Q320, Ok((Quality::Q160, AudioFormat::Ogg))),
+				(Quality::Q256, Ok((Quality::Q160, AudioFormat::Ogg))),
+				(Quality::Q160, Ok((Quality::Q160, AudioFormat::Ogg))),
+				(Quality::Q96, Ok((Quality::Q96, AudioFormat::Mp3))),
+			],
+			selected: Err(SpotifyError::QualityUnavailable("Q256".to_string())),
+		};
+		assert_eq!(
+			quality_row(&report, "7 rings"),
+			"ogg 160  ogg 160  ogg 160  mp3 96   -        | 7 rings | MP3_96, OGG_VORBIS_160"
+		);
+	}
+}
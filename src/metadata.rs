@@ -0,0 +1,301 @@
+use aspotify::{
+	Album, AlbumSimplified, AlbumType, ArtistSimplified, Copyright, DatePrecision, Image, Page,
+	Track, TrackSimplified, TypeAlbum, TypeArtist, TypeTrack,
+};
+use chrono::NaiveDate;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use librespot::core::session::Session;
+use librespot::core::spotify_id::SpotifyId;
+use librespot::protocol::metadata;
+use protobuf::Message;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::error::SpotifyError;
+
+/// Tracks of an album fetched at once
+const ALBUM_TRACK_REQUESTS: usize = 8;
+
+/// Fetch a metadata message from librespot, of kind track or album
+async fn request<M: Message>(session: &Session, kind: &str, id: &str) -> Result<M, SpotifyError> {
+	let id = SpotifyId::from_base62(id)?
+		.to_base16()
+		.map_err(|_| SpotifyError::SpotifyIdError)?;
+	let response = session
+		.mercury()
+		.get(format!("hm://metadata/3/{}/{}", kind, id))
+		.await?;
+	let data = response.payload.first().ok_or(SpotifyError::NotFound)?;
+	M::parse_from_bytes(data).map_err(|e| SpotifyError::Error(format!("Invalid metadata: {}", e)))
+}
+
+/// Get a track like the Web API would, for sessions without Web API credentials
+///
+/// Its album has the fields of the track message, which leaves out the album tracks
+pub(crate) async fn track(session: &Session, id: &str) -> Result<Track, SpotifyError> {
+	let track: metadata::Track = request(session, "track", id).await?;
+	Ok(Track {
+		album: album_simplified(track.get_album()),
+		artists: artists(track.get_artist()),
+		available_markets: None,
+		disc_number: track.get_disc_number().max(0) as usize,
+		duration: Duration::from_millis(track.get_duration().max(0) as u64),
+		explicit: track.get_explicit(),
+		external_ids: external_ids(track.get_external_id()),
+		external_urls: HashMap::new(),
+		// The requested ID, the message may be of a relinked track
+		id: Some(id.to_string()),
+		is_playable: None,
+		linked_from: None,
+		restrictions: None,
+		name: track.get_name().to_string(),
+		popularity: track.get_popularity().max(0) as u32,
+		preview_url: None,
+		track_number: track.get_number().max(0) as usize,
+		item_type: TypeTrack,
+		is_local: false,
+	})
+}
+
+/// Get an album like the Web API would, for sessions without Web API credentials
+///
+/// The page of its tracks is empty apart from the total, album_tracks fetches them
+pub(crate) async fn album(session: &Session, id: &str) -> Result<Album, SpotifyError> {
+	let album: metadata::Album = request(session, "album", id).await?;
+	let simplified = album_simplified(&album);
+	Ok(Album {
+		album_type: simplified.album_type.unwrap_or(AlbumType::Album),
+		artists: simplified.artists,
+		available_markets: None,
+		copyrights: album
+			.get_copyright()
+			.iter()
+			.map(|c| Copyright {
+				text: c.get_text().to_string(),
+				performance_copyright: c.get_typ() == metadata::Copyright_Type::P,
+			})
+			.collect(),
+		external_ids: external_ids(album.get_external_id()),
+		external_urls: HashMap::new(),
+		genres: album.get_genre().to_vec(),
+		id: id.to_string(),
+		images: simplified.images,
+		label: album.get_label().to_string(),
+		name: simplified.name,
+		popularity: album.get_popularity().max(0) as u32,
+		release_date: simplified.release_date.unwrap_or_default(),
+		release_date_precision: simplified
+			.release_date_precision
+			.unwrap_or(DatePrecision::Year),
+		restrictions: None,
+		tracks: Page {
+			items: vec![],
+			limit: 0,
+			offset: 0,
+			total: track_ids(&album).len(),
+		},
+		item_type: TypeAlbum,
+	})
+}
+
+/// Get tracks of an album, starting at offset and at most limit
+pub(crate) async fn album_tracks(
+	session: &Session,
+	id: &str,
+	offset: usize,
+	limit: Option<usize>,
+) -> Result<Vec<TrackSimplified>, SpotifyError> {
+	let album: metadata::Album = request(session, "album", id).await?;
+	let ids = track_ids(&album);
+	// Buffered keeps the order of the album
+	stream::iter(ids.iter().skip(offset).take(limit.unwrap_or(usize::MAX)))
+		.map(|id| track(session, id))
+		.buffered(ALBUM_TRACK_REQUESTS)
+		.map_ok(Track::simplify)
+		.try_collect()
+		.await
+}
+
+/// IDs of all tracks of an album, disc by disc
+fn track_ids(album: &metadata::Album) -> Vec<String> {
+	album
+		.get_disc()
+		.iter()
+		.flat_map(|disc| disc.get_track())
+		.filter_map(|track| base62(track.get_gid()))
+		.collect()
+}
+
+fn album_simplified(album: &metadata::Album) -> AlbumSimplified {
+	let album_type = match album.get_typ() {
+		metadata::Album_Type::ALBUM => AlbumType::Album,
+		// The Web API calls EPs singles as well
+		metadata::Album_Type::SINGLE | metadata::Album_Type::EP => AlbumType::Single,
+		metadata::Album_Type::COMPILATION => AlbumType::Compilation,
+	};
+	let (release_date, release_date_precision) = release_date(album.get_date());
+	AlbumSimplified {
+		album_type: Some(album_type),
+		artists: artists(album.get_artist()),
+		available_markets: None,
+		external_urls: HashMap::new(),
+		id: base62(album.get_gid()),
+		images: images(album),
+		name: album.get_name().to_string(),
+		release_date,
+		release_date_precision,
+		restrictions: None,
+		item_type: TypeAlbum,
+	}
+}
+
+/// Date with the precision the message has, none without a year
+fn release_date(date: &metadata::Date) -> (Option<NaiveDate>, Option<DatePrecision>) {
+	if !date.has_year() {
+		return (None, None);
+	}
+	let precision = match (date.has_month(), date.has_day()) {
+		(true, true) => DatePrecision::Day,
+		(true, false) => DatePrecision::Month,
+		_ => DatePrecision::Year,
+	};
+	let (month, day) = match precision {
+		DatePrecision::Day => (date.get_month(), date.get_day()),
+		DatePrecision::Month => (date.get_month(), 1),
+		DatePrecision::Year => (1, 1),
+	};
+	let date = NaiveDate::from_ymd_opt(date.get_year(), month.max(1) as u32, day.max(1) as u32);
+	(date, Some(precision))
+}
+
+/// Cover images of an album, with the sizes of Spotify's covers if the message has none
+fn images(album: &metadata::Album) -> Vec<Image> {
+	album
+		.get_cover_group()
+		.get_image()
+		.iter()
+		.map(|image| {
+			let size = match image.get_size() {
+				metadata::Image_Size::SMALL => 64,
+				metadata::Image_Size::DEFAULT => 300,
+				metadata::Image_Size::LARGE => 640,
+				metadata::Image_Size::XLARGE => 1280,
+			};
+			let id: String = image
+				.get_file_id()
+				.iter()
+				.map(|b| format!("{:02x}", b))
+				.collect();
+			Image {
+				url: format!("https://i.scdn.co/image/{}", id),
+				width: Some(match image.has_width() {
+					true => image.get_width().max(0) as usize,
+					false => size,
+				}),
+				height: Some(match image.has_height() {
+					true => image.get_height().max(0) as usize,
+					false => size,
+				}),
+			}
+		})
+		.collect()
+}
+
+fn artists(artists: &[metadata::Artist]) -> Vec<ArtistSimplified> {
+	artists
+		.iter()
+		.map(|artist| ArtistSimplified {
+			external_urls: HashMap::new(),
+			id: base62(artist.get_gid()),
+			name: artist.get_name().to_string(),
+			item_type: TypeArtist,
+		})
+		.collect()
+}
+
+/// External IDs by type, such as isrc for tracks and upc for albums
+fn external_ids(ids: &[metadata::ExternalId]) -> HashMap<String, String> {
+	ids.iter()
+		.map(|id| (id.get_typ().to_string(), id.get_id().to_string()))
+		.collect()
+}
+
+fn base62(gid: &[u8]) -> Option<String> {
+	SpotifyId::from_raw(gid).ok()?.to_base62().ok()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn date(year: Option<i32>, month: Option<i32>, day: Option<i32>) -> metadata::Date {
+		let mut date = metadata::Date::new();
+		if let Some(year) = year {
+			date.set_year(year);
+		}
+		if let Some(month) = month {
+			date.set_month(month);
+		}
+		if let Some(day) = day {
+			date.set_day(day);
+		}
+		date
+	}
+
+	#[test]
+	fn release_date_precision() {
+		assert_eq!(
+			release_date(&date(Some(2009), Some(6), Some(15))),
+			(
+				NaiveDate::from_ymd_opt(2009, 6, 15),
+				Some(DatePrecision::Day)
+			)
+		);
+		assert_eq!(
+			release_date(&date(Some(2009), Some(6), None)),
+			(
+				NaiveDate::from_ymd_opt(2009, 6, 1),
+				Some(DatePrecision::Month)
+			)
+		);
+		assert_eq!(
+			release_date(&date(Some(2009), None, None)),
+			(
+				NaiveDate::from_ymd_opt(2009, 1, 1),
+				Some(DatePrecision::Year)
+			)
+		);
+		assert_eq!(release_date(&date(None, None, None)), (None, None));
+	}
+
+	#[test]
+	fn album_from_message() {
+		let mut artist = metadata::Artist::new();
+		artist.set_gid(vec![0; 16]);
+		artist.set_name("Artist".to_string());
+		let mut image = metadata::Image::new();
+		image.set_file_id(vec![0xab; 20]);
+		image.set_size(metadata::Image_Size::LARGE);
+		let mut album = metadata::Album::new();
+		album.set_name("Album".to_string());
+		album.set_typ(metadata::Album_Type::EP);
+		album.set_date(date(Some(2009), None, None));
+		album.mut_artist().push(artist);
+		album.mut_cover_group().mut_image().push(image);
+
+		let album = album_simplified(&album);
+		assert_eq!(album.name, "Album");
+		assert_eq!(album.album_type, Some(AlbumType::Single));
+		assert_eq!(album.artists[0].name, "Artist");
+		assert_eq!(
+			album.artists[0].id.as_deref(),
+			Some("0000000000000000000000")
+		);
+		assert_eq!(
+			album.images[0].url,
+			format!("https://i.scdn.co/image/{}", "ab".repeat(20))
+		);
+		assert_eq!(album.images[0].width, Some(640));
+		assert_eq!(album.release_date_precision, Some(DatePrecision::Year));
+	}
+}
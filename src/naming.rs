@@ -0,0 +1,325 @@
+use aspotify::{Album, Track};
+use chrono::Datelike;
+use sanitize_filename::{sanitize_with_options, Options};
+use std::path::{Component, Path, PathBuf};
+
+/// Bytes of a file or folder name most filesystems allow
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Room left in file names for the extension and the suffix of partial files,
+/// like `.flac` and `.<file id>.part`
+const SUFFIX_LENGTH: usize = 56;
+
+/// Length of whole paths, Windows allows 260 characters without long path support
+const MAX_PATH_LENGTH: usize = if cfg!(windows) { 260 } else { 4096 };
+
+/// Bytes names are never shortened below to fit the path length
+const MIN_NAME_LENGTH: usize = 16;
+
+/// Name used in place of the artists of tracks and albums listing none
+pub(crate) const UNKNOWN_ARTIST: &str = "Unknown Artist";
+
+/// Values of the path and filename template variables for a track
+pub(crate) fn template_values(
+	track_id: &str,
+	playlist_index: Option<&str>,
+	track: &Track,
+	album: &Album,
+	artists_in_order: bool,
+) -> Vec<(&'static str, String)> {
+	let mut artists = track
+		.artists
+		.iter()
+		.map(|a| a.name.as_str())
+		.collect::<Vec<&str>>();
+	if artists.is_empty() {
+		warn!(
+			"Track {} lists no artists, naming it by {}",
+			track_id, UNKNOWN_ARTIST
+		);
+		artists.push(UNKNOWN_ARTIST);
+	}
+	let mut album_artists = track
+		.album
+		.artists
+		.iter()
+		.map(|a| a.name.as_str())
+		.collect::<Vec<&str>>();
+	if album_artists.is_empty() {
+		album_artists.push(UNKNOWN_ARTIST);
+	}
+	// Pad track numbers to the width of the largest number on the album
+	let width = album.tracks.total.to_string().len().max(2);
+	let track_number = format!("{:0width$}", track.track_number, width = width);
+
+	vec![
+		("%title%", sanitize(&track.name)),
+		(
+			"%artist%",
+			match artists_in_order {
+				true => sanitize(&artists.join(", ")),
+				false => sanitize(artists[0]),
+			},
+		),
+		("%artists%", sanitize(&artists.join(", "))),
+		("%track%", track.track_number.to_string()),
+		("%0track%", track_number.clone()),
+		("%disc%", track.disc_number.to_string()),
+		("%0disc%", format!("{:02}", track.disc_number)),
+		(
+			"%discTrack%",
+			format!("{}-{}", track.disc_number, track_number),
+		),
+		("%id%", track_id.to_string()),
+		(
+			"%playlist_index%",
+			playlist_index.unwrap_or_default().to_string(),
+		),
+		("%album%", sanitize(&track.album.name)),
+		("%year%", album.release_date.year().to_string()),
+		("%albumArtist%", sanitize(album_artists[0])),
+		("%albumArtists%", sanitize(&album_artists.join(", "))),
+	]
+}
+
+/// Replace the template variables in a template
+pub(crate) fn apply_template(template: &str, values: &[(&str, String)]) -> String {
+	let mut template = template.to_string();
+	for (tag, value) in values {
+		template = template.replace(tag, value);
+	}
+	template
+}
+
+/// Path of a track without extension from the path and filename templates
+pub(crate) fn track_path(
+	path_template: &str,
+	filename_template: &str,
+	values: &[(&str, String)],
+) -> PathBuf {
+	let path = Path::new(&apply_template(path_template, values))
+		.join(apply_template(filename_template, values));
+	// Relative paths start in the working directory
+	limit_path(&path, &std::env::current_dir().unwrap_or_default())
+}
+
+/// Shorten the names of a path without extension to the limits of file names and paths
+///
+/// Names are cut to 255 bytes, the file name leaving room for the extension. If the whole
+/// path is still too long, the longest names are shortened further. Relative paths count
+/// the length of base in front of them
+fn limit_path(path: &Path, base: &Path) -> PathBuf {
+	let mut names: Vec<(String, bool)> = path
+		.components()
+		.map(|c| {
+			let name = c.as_os_str().to_string_lossy().into_owned();
+			(name, matches!(c, Component::Normal(_)))
+		})
+		.collect();
+	let last = names.len().saturating_sub(1);
+	let mut shortened = false;
+	for (i, (name, normal)) in names.iter_mut().enumerate() {
+		let max = match i == last {
+			true => MAX_NAME_LENGTH - SUFFIX_LENGTH,
+			false => MAX_NAME_LENGTH,
+		};
+		if *normal && name.len() > max {
+			truncate(name, max);
+			shortened = true;
+		}
+	}
+
+	let base = match path.is_relative() && !base.as_os_str().is_empty() {
+		true => base.as_os_str().len() + 1,
+		false => 0,
+	};
+	let length = |names: &[(String, bool)]| {
+		base + names.iter().map(|(n, _)| n.len() + 1).sum::<usize>() + SUFFIX_LENGTH
+	};
+	loop {
+		let excess = length(&names).saturating_sub(MAX_PATH_LENGTH);
+		let longest = names
+			.iter_mut()
+			.filter(|(n, normal)| *normal && n.len() > MIN_NAME_LENGTH)
+			.max_by_key(|(n, _)| n.len());
+		match (excess, longest) {
+			(0, _) | (_, None) => break,
+			(excess, Some((name, _))) => {
+				let max = name.len().saturating_sub(excess).max(MIN_NAME_LENGTH);
+				truncate(name, max);
+				shortened = true;
+			}
+		}
+	}
+
+	let limited: PathBuf = names.into_iter().map(|(n, _)| n).collect();
+	if shortened {
+		warn!("Shortened path {} to {}", path.display(), limited.display());
+	}
+	limited
+}
+
+/// Cut a name to at most max bytes, without trailing whitespace or dots
+fn truncate(name: &mut String, max: usize) {
+	let mut end = max.min(name.len());
+	while !name.is_char_boundary(end) {
+		end -= 1;
+	}
+	name.truncate(end);
+	let trimmed = name.trim_end().trim_end_matches('.').trim_end().len();
+	name.truncate(trimmed);
+}
+
+/// Leading part of the path template without any template variables
+pub(crate) fn template_root(path_template: &str) -> PathBuf {
+	Path::new(path_template)
+		.components()
+		.take_while(|c| !c.as_os_str().to_string_lossy().contains('%'))
+		.collect()
+}
+
+/// Make a name safe to use as a single path segment on all platforms
+///
+/// Replaces characters invalid on Windows or macOS with underscores, removes surrounding
+/// whitespace and trailing dots, and truncates the name to 255 bytes
+pub(crate) fn sanitize(name: &str) -> String {
+	// Trimmed first, the trailing dots and spaces would be replaced as well
+	let name = sanitize_with_options(
+		name.trim().trim_end_matches('.').trim_end(),
+		Options {
+			windows: true,
+			truncate: true,
+			replacement: "_",
+		},
+	);
+	name.trim().trim_end_matches('.').trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	/// Track by artists on an album of 12 tracks
+	fn track(artists: &[&str]) -> (Track, Album) {
+		let artists: Vec<_> = artists
+			.iter()
+			.map(|name| json!({ "external_urls": {}, "name": name, "type": "artist" }))
+			.collect();
+		let simplified = json!({
+			"album_type": "album",
+			"artists": [],
+			"external_urls": {},
+			"id": "album",
+			"images": [],
+			"name": "Album",
+			"release_date": "2001-02-03",
+			"release_date_precision": "day",
+			"type": "album",
+		});
+		let track = json!({
+			"album": simplified,
+			"artists": artists,
+			"disc_number": 1,
+			"duration_ms": 1000,
+			"explicit": false,
+			"external_ids": {},
+			"external_urls": {},
+			"id": "track",
+			"name": "Song: Reprise?",
+			"popularity": 0,
+			"track_number": 3,
+			"type": "track",
+			"is_local": false,
+		});
+		let mut album = simplified;
+		album["copyrights"] = json!([]);
+		album["external_ids"] = json!({});
+		album["genres"] = json!([]);
+		album["label"] = json!("");
+		album["popularity"] = json!(0);
+		album["tracks"] = json!({ "items": [], "limit": 0, "offset": 0, "total": 12 });
+		(
+			serde_json::from_value(track).unwrap(),
+			serde_json::from_value(album).unwrap(),
+		)
+	}
+
+	#[test]
+	fn template_values_without_artists() {
+		let (track, album) = track(&[]);
+		let values = template_values("track", None, &track, &album, false);
+		let path = apply_template("%artist%/%albumArtist%/%0track% %title%", &values);
+		assert_eq!(path, "Unknown Artist/Unknown Artist/03 Song_ Reprise_");
+	}
+
+	#[test]
+	fn template_values_with_artists() {
+		let (track, album) = track(&["AC/DC", "Other"]);
+		let values = template_values("track", Some("7"), &track, &album, true);
+		let path = apply_template("%artist% - %artists% %year% %playlist_index%", &values);
+		assert_eq!(path, "AC_DC, Other - AC_DC, Other 2001 7");
+	}
+
+	/// Length of path the way limit_path counts it
+	fn path_length(path: &Path) -> usize {
+		path.components()
+			.map(|c| c.as_os_str().len() + 1)
+			.sum::<usize>()
+			+ SUFFIX_LENGTH
+	}
+
+	#[test]
+	fn limit_path_cuts_long_file_name() {
+		let title = "a".repeat(300);
+		let limited = limit_path(Path::new("Artist").join(&title).as_path(), Path::new(""));
+		assert_eq!(
+			limited,
+			Path::new("Artist").join("a".repeat(MAX_NAME_LENGTH - SUFFIX_LENGTH))
+		);
+	}
+
+	#[test]
+	fn limit_path_cuts_on_char_boundaries() {
+		let title = "é".repeat(150);
+		let limited = limit_path(Path::new(&title), Path::new(""));
+		let name = limited.to_str().unwrap();
+		assert_eq!(name, "é".repeat((MAX_NAME_LENGTH - SUFFIX_LENGTH) / 2));
+	}
+
+	#[test]
+	fn limit_path_shortens_deep_path() {
+		let folder = "f".repeat(200);
+		let path: PathBuf = (0..MAX_PATH_LENGTH / 100)
+			.map(|_| folder.as_str())
+			.collect();
+		let limited = limit_path(&path, Path::new(""));
+		assert!(path_length(&limited) <= MAX_PATH_LENGTH);
+		assert_eq!(limited.components().count(), path.components().count());
+	}
+
+	#[test]
+	fn limit_path_counts_base() {
+		let base = PathBuf::from("b".repeat(MAX_PATH_LENGTH - 100));
+		let limited = limit_path(Path::new(&"a".repeat(150)), &base);
+		assert!(base.as_os_str().len() + 1 + path_length(&limited) <= MAX_PATH_LENGTH);
+	}
+
+	#[test]
+	fn truncate_trims_end() {
+		let mut name = "Vol. 2 ... end".to_string();
+		truncate(&mut name, 10);
+		assert_eq!(name, "Vol. 2");
+	}
+
+	#[test]
+	fn sanitize_replaces_invalid_characters() {
+		assert_eq!(sanitize("AC/DC"), "AC_DC");
+		assert_eq!(sanitize("Song: Reprise?"), "Song_ Reprise_");
+	}
+
+	#[test]
+	fn sanitize_trims_whitespace_and_dots() {
+		assert_eq!(sanitize("  Vol. 2...  "), "Vol. 2");
+	}
+}
@@ -0,0 +1,110 @@
+use std::os::raw::{c_int, c_uchar};
+
+/// Opaque OpusEncoder
+#[repr(C)]
+struct OpusEncoderState {
+	_private: [u8; 0],
+}
+
+/// Best for most non-voice signals
+const OPUS_APPLICATION_AUDIO: c_int = 2049;
+const OPUS_SET_BITRATE_REQUEST: c_int = 4002;
+const OPUS_GET_LOOKAHEAD_REQUEST: c_int = 4027;
+
+#[link(name = "opus")]
+extern "C" {
+	fn opus_encoder_create(
+		sample_rate: i32,
+		channels: c_int,
+		application: c_int,
+		error: *mut c_int,
+	) -> *mut OpusEncoderState;
+	fn opus_encoder_destroy(st: *mut OpusEncoderState);
+	fn opus_encoder_ctl(st: *mut OpusEncoderState, request: c_int, ...) -> c_int;
+	fn opus_encode(
+		st: *mut OpusEncoderState,
+		pcm: *const i16,
+		frame_size: c_int,
+		data: *mut c_uchar,
+		max_data_bytes: i32,
+	) -> i32;
+}
+
+/// Safe wrapper around a libopus encoder
+pub(crate) struct Opus {
+	ptr: *mut OpusEncoderState,
+	channels: usize,
+}
+
+impl Opus {
+	/// Create new encoder, the sample rate has to be 8, 12, 16, 24 or 48 kHz
+	pub fn new(sample_rate: u32, channels: u8) -> Result<Opus, i32> {
+		let mut error = 0;
+		let ptr = unsafe {
+			opus_encoder_create(
+				sample_rate as i32,
+				channels as c_int,
+				OPUS_APPLICATION_AUDIO,
+				&mut error,
+			)
+		};
+		match ptr.is_null() || error != 0 {
+			true => Err(error),
+			false => Ok(Opus {
+				ptr,
+				channels: channels as usize,
+			}),
+		}
+	}
+
+	pub fn set_kilobitrate(&mut self, bitrate: i32) -> Result<(), i32> {
+		check(unsafe { opus_encoder_ctl(self.ptr, OPUS_SET_BITRATE_REQUEST, bitrate * 1000) })
+	}
+
+	/// Samples per channel the encoder delays the audio by, to skip when decoding
+	pub fn lookahead(&mut self) -> Result<u16, i32> {
+		let mut lookahead: i32 = 0;
+		check(unsafe {
+			opus_encoder_ctl(
+				self.ptr,
+				OPUS_GET_LOOKAHEAD_REQUEST,
+				&mut lookahead as *mut i32,
+			)
+		})?;
+		Ok(lookahead as u16)
+	}
+
+	/// Encode one frame of interleaved PCM, returns the amount of bytes written to the buffer
+	///
+	/// Frames have to be 2.5, 5, 10, 20, 40 or 60 ms long
+	pub fn encode(&mut self, pcm: &[i16], packet: &mut [u8]) -> Result<usize, i32> {
+		let size = unsafe {
+			opus_encode(
+				self.ptr,
+				pcm.as_ptr(),
+				(pcm.len() / self.channels) as c_int,
+				packet.as_mut_ptr(),
+				packet.len() as i32,
+			)
+		};
+		match size < 0 {
+			true => Err(size),
+			false => Ok(size as usize),
+		}
+	}
+}
+
+impl Drop for Opus {
+	fn drop(&mut self) {
+		unsafe {
+			opus_encoder_destroy(self.ptr);
+		}
+	}
+}
+
+fn check(code: c_int) -> Result<(), i32> {
+	match code {
+		0 => Ok(()),
+		e => Err(e),
+	}
+}
@@ -1,6 +1,9 @@
-use crate::downloader::DownloaderConfig;
+use crate::downloader::{Bitrate, DownloaderConfig, OutputFormat, Quality};
 use crate::error::SpotifyError;
 use aspotify::CountryCode;
+use clap::ValueEnum;
+use librespot::core::authentication::Credentials;
+use librespot::protocol::authentication::AuthenticationType;
 use serde::{Deserialize, Serialize};
 
 use tokio::{
@@ -19,11 +22,57 @@ use std::{
 pub struct Settings {
 	pub username: String,
 	pub password: String,
+	#[serde(default)]
+	pub auth_method: AuthMethod,
+	/// Access token used by the token authentication method
+	pub access_token: Option<String>,
+	/// Web API application, metadata comes from librespot if these are empty
+	#[serde(default)]
 	pub client_id: String,
+	#[serde(default)]
 	pub client_secret: String,
 	pub refresh_ui_seconds: u64,
 	pub downloader: DownloaderConfig,
 	pub market_country_code: Option<CountryCode>,
+	/// HTTP proxy for all connections, falls back to the HTTPS_PROXY / HTTP_PROXY variables
+	pub proxy: Option<String>,
+	/// Device ID the session registers with, random if not set
+	pub device_id: Option<String>,
+	/// Folder of the cached credentials, see get_cache_path for the default
+	pub cache_dir: Option<String>,
+	/// Folder of downloaded audio files to reuse instead of fetching them again, off if not set
+	pub audio_cache_dir: Option<String>,
+	/// Bytes after which the least recently used files of the audio cache are removed
+	pub audio_cache_limit: Option<u64>,
+}
+
+/// Defaults from a `down_on_spot.toml`, for the settings most often changed
+///
+/// Precedence: command line options > `down_on_spot.toml` > environment variables > `settings.json`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+	pub username: Option<String>,
+	pub password: Option<String>,
+	pub client_id: Option<String>,
+	pub client_secret: Option<String>,
+	/// Output folder, may contain template variables like the setting path
+	pub path: Option<String>,
+	pub filename_template: Option<String>,
+	pub quality: Option<Quality>,
+	pub skip_existing: Option<bool>,
+	pub bitrate: Option<Bitrate>,
+	pub concurrent_downloads: Option<usize>,
+}
+
+/// How to authenticate with Spotify
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum AuthMethod {
+	/// Username and password
+	#[default]
+	Password,
+	/// Access token, for accounts without a password such as federated logins
+	Token,
 }
 
 // On UNIX systems (eg. Linux, *BSD, even macOS), follow the
@@ -32,14 +81,54 @@ pub struct Settings {
 fn get_config_folder_path() -> PathBuf {
 	match env::var("XDG_CONFIG_HOME") {
 		Ok(v) => Path::new(&v).join("down_on_spot").to_path_buf(),
-		Err(_) => Path::new(&env::var("HOME").unwrap()).join(".config/down_on_spot"),
+		// Relative to the working directory if there is no home either
+		Err(_) => dirs::home_dir()
+			.unwrap_or_default()
+			.join(".config/down_on_spot"),
 	}
 }
 
 // On Windows, follow whatever windows does for AppData
 #[cfg(target_family = "windows")]
 fn get_config_folder_path() -> PathBuf {
-	Path::new(&env::var("APPDATA").unwrap()).join("down_on_spot")
+	match env::var("APPDATA") {
+		Ok(v) => Path::new(&v).join("down_on_spot"),
+		Err(_) => dirs::config_dir().unwrap_or_default().join("down_on_spot"),
+	}
+}
+
+// Cache files follow the XDG Base Directory Specification as well
+#[cfg(target_family = "unix")]
+fn get_cache_folder_path() -> PathBuf {
+	match env::var("XDG_CACHE_HOME") {
+		Ok(v) => Path::new(&v).join("down_on_spot"),
+		Err(_) => dirs::home_dir()
+			.unwrap_or_default()
+			.join(".cache/down_on_spot"),
+	}
+}
+
+// On Windows, local AppData is meant for caches
+#[cfg(target_family = "windows")]
+fn get_cache_folder_path() -> PathBuf {
+	match env::var("LOCALAPPDATA") {
+		Ok(v) => Path::new(&v).join("down_on_spot"),
+		Err(_) => dirs::cache_dir().unwrap_or_default().join("down_on_spot"),
+	}
+}
+
+/// Returns the path of the cache folder if none is configured
+///
+/// A `credentials_cache` folder in the working directory, as used by earlier
+/// versions, takes precedence over `%LOCALAPPDATA%\down_on_spot` on Windows and
+/// `~/.cache/down_on_spot` on Unix-like systems
+pub fn get_cache_path() -> PathBuf {
+	let local = Path::new("credentials_cache");
+	if local.is_dir() {
+		local.to_path_buf()
+	} else {
+		get_cache_folder_path()
+	}
 }
 
 /// Returns the full path to the Settings json
@@ -51,17 +140,116 @@ pub fn get_config_settings_path() -> PathBuf {
 	get_config_folder_path().join("settings.json")
 }
 
+/// Returns the path of the Settings json to load
+///
+/// A `settings.json` in the working directory takes precedence over the one
+/// in the config folder
+pub fn get_settings_path() -> PathBuf {
+	let local = Path::new("settings.json");
+	if local.is_file() {
+		local.to_path_buf()
+	} else {
+		get_config_settings_path()
+	}
+}
+
+/// Returns the path of the `down_on_spot.toml` to load, if there is one
+///
+/// A `down_on_spot.toml` in the working directory takes precedence over the one
+/// in the config folder
+pub fn get_config_file_path() -> Option<PathBuf> {
+	[
+		PathBuf::from("down_on_spot.toml"),
+		get_config_folder_path().join("down_on_spot.toml"),
+	]
+	.into_iter()
+	.find(|path| path.is_file())
+}
+
+impl ConfigFile {
+	/// Load the `down_on_spot.toml`, none if there is no such file
+	pub async fn load() -> Result<Option<ConfigFile>, SpotifyError> {
+		let Some(path) = get_config_file_path() else {
+			return Ok(None);
+		};
+		let mut buf = String::new();
+		File::open(&path).await?.read_to_string(&mut buf).await?;
+		ConfigFile::parse(&buf)
+			.map(Some)
+			.map_err(|e| SpotifyError::Error(format!("Invalid {}: {}", path.display(), e)))
+	}
+
+	fn parse(data: &str) -> Result<ConfigFile, toml::de::Error> {
+		toml::from_str(data)
+	}
+
+	/// Override the settings with the values set in the file
+	pub fn apply(&self, settings: &mut Settings) {
+		let set = |field: &mut String, value: &Option<String>| {
+			if let Some(value) = value {
+				*field = value.clone();
+			}
+		};
+		set(&mut settings.username, &self.username);
+		set(&mut settings.password, &self.password);
+		set(&mut settings.client_id, &self.client_id);
+		set(&mut settings.client_secret, &self.client_secret);
+		set(&mut settings.downloader.path, &self.path);
+		set(
+			&mut settings.downloader.filename_template,
+			&self.filename_template,
+		);
+		if let Some(quality) = self.quality {
+			settings.downloader.quality = quality;
+		}
+		if let Some(skip_existing) = self.skip_existing {
+			settings.downloader.skip_existing = skip_existing;
+		}
+		if let Some(bitrate) = self.bitrate {
+			settings.downloader.bitrate = Some(bitrate);
+		}
+		if let Some(concurrent_downloads) = self.concurrent_downloads {
+			settings.downloader.concurrent_downloads = concurrent_downloads;
+		}
+	}
+}
+
 impl Settings {
 	// Create new instance
 	pub fn new(username: &str, password: &str, client_id: &str, client_secret: &str) -> Settings {
 		Settings {
 			username: username.to_string(),
 			password: password.to_string(),
+			auth_method: AuthMethod::Password,
+			access_token: None,
 			client_id: client_id.to_string(),
 			client_secret: client_secret.to_string(),
 			refresh_ui_seconds: 1,
 			downloader: DownloaderConfig::new(),
 			market_country_code: None,
+			proxy: None,
+			device_id: None,
+			cache_dir: None,
+			audio_cache_dir: None,
+			audio_cache_limit: None,
+		}
+	}
+
+	/// Credentials for the chosen authentication method
+	///
+	/// Falls back to the password if no access token is set
+	pub fn credentials(&self) -> Credentials {
+		match (self.auth_method, &self.access_token) {
+			(AuthMethod::Token, Some(token)) => Credentials {
+				username: self.username.to_string(),
+				auth_type: AuthenticationType::AUTHENTICATION_SPOTIFY_TOKEN,
+				auth_data: token.as_bytes().to_vec(),
+			},
+			(AuthMethod::Token, None) => {
+				warn!("No access token set, falling back to password authentication");
+				Credentials::with_password(&self.username, &self.password)
+			}
+			(AuthMethod::Password, _) => Credentials::with_password(&self.username, &self.password),
 		}
 	}
 
@@ -85,13 +273,105 @@ impl Settings {
 
 	// Load config
 	pub async fn load() -> Result<Settings, SpotifyError> {
-		// Get config folder path, generate config file path
-		let config_file_path = get_config_settings_path();
+		// Get config file path, preferring the working directory
+		let config_file_path = get_settings_path();
 
 		// Deserialize the settings from a json file
 		let mut file = File::open(config_file_path).await?;
 		let mut buf = String::new();
 		file.read_to_string(&mut buf).await?;
-		Ok(serde_json::from_str(&buf)?)
+		Ok(Settings::parse(&buf)?)
+	}
+
+	fn parse(data: &str) -> Result<Settings, serde_json::Error> {
+		let mut settings: Settings = serde_json::from_str(data)?;
+		if settings.downloader.convert_to_mp3.take() == Some(true) {
+			settings.downloader.format = OutputFormat::Mp3;
+		}
+		Ok(settings)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn config_file_overrides_settings() {
+		let config = ConfigFile::parse(
+			r#"
+			client_id = "id"
+			client_secret = "secret"
+			path = "music/%artist%"
+			quality = "Q320"
+			skip_existing = false
+			bitrate = "B256"
+			concurrent_downloads = 2
+			"#,
+		)
+		.unwrap();
+		let mut settings = Settings::new("user", "password", "", "");
+		config.apply(&mut settings);
+
+		// Values not in the file are kept
+		assert_eq!(settings.username, "user");
+		assert_eq!(settings.client_id, "id");
+		assert_eq!(settings.client_secret, "secret");
+		assert_eq!(settings.downloader.path, "music/%artist%");
+		assert_eq!(
+			settings.downloader.filename_template,
+			DownloaderConfig::new().filename_template
+		);
+		assert_eq!(settings.downloader.quality, Quality::Q320);
+		assert!(!settings.downloader.skip_existing);
+		assert_eq!(settings.downloader.bitrate, Some(Bitrate::B256));
+		assert_eq!(settings.downloader.concurrent_downloads, 2);
+	}
+
+	#[test]
+	fn legacy_convert_to_mp3_sets_format() {
+		let settings = Settings::parse(
+			r#"{
+				"username": "user",
+				"password": "password",
+				"client_id": "",
+				"client_secret": "",
+				"refresh_ui_seconds": 1,
+				"downloader": {
+					"concurrent_downloads": 4,
+					"quality": "Q320",
+					"path": "downloads",
+					"filename_template": "%artist% - %title%",
+					"id3v24": true,
+					"convert_to_mp3": true,
+					"separator": ", ",
+					"skip_existing": true
+				}
+			}"#,
+		)
+		.unwrap();
+		assert_eq!(settings.downloader.format, OutputFormat::Mp3);
+
+		// Saving writes the format instead of the old switch
+		let saved = serde_json::to_string(&settings).unwrap();
+		assert!(!saved.contains("convert_to_mp3"));
+		assert!(saved.contains(r#""format":"Mp3""#));
+
+		let settings = Settings::parse(
+			r#"{
+				"username": "",
+				"password": "",
+				"refresh_ui_seconds": 1,
+				"downloader": {"convert_to_mp3": false}
+			}"#,
+		)
+		.unwrap();
+		assert_eq!(settings.downloader.format, OutputFormat::Original);
+	}
+
+	#[test]
+	fn config_file_rejects_unknown_keys() {
+		assert!(ConfigFile::parse("concurrency = 2").is_err());
+		assert_eq!(ConfigFile::parse("").unwrap(), ConfigFile::default());
 	}
 }
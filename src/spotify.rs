@@ -1,43 +1,186 @@
 use aspotify::{
-	Album, Artist, Client, ClientCredentials, CountryCode, ItemType, Market, Playlist,
-	PlaylistItemType, Track, TrackSimplified,
+	Album, Artist, Client, ClientCredentials, CountryCode, ItemType, Market, Page, Playlist,
+	PlaylistItem, PlaylistItemType, SearchResults, Track, TrackSimplified,
 };
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use futures::stream::{self, StreamExt};
+use librespot::core::audio_key::{AudioKey, AudioKeyError};
 use librespot::core::authentication::Credentials;
 use librespot::core::cache::Cache;
 use librespot::core::config::SessionConfig;
-use librespot::core::session::Session;
+use librespot::core::keymaster;
+use librespot::core::session::{Session, SessionError};
+use librespot::core::spotify_id::{FileId, SpotifyId};
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use url::Url;
 
 use crate::error::SpotifyError;
+use crate::metadata;
+
+/// Client ID of the desktop app, which is allowed user scoped tokens from the keymaster
+///
+/// Lyrics, playlists fetched as user and liked songs depend on it, see the README
+pub(crate) const KEYMASTER_CLIENT_ID: &str = "65b708073fc0480ea92a077233ca87bd";
+
+/// Scopes of user tokens for playlists the app credentials can not see
+const PLAYLIST_SCOPES: &str = "playlist-read-private,playlist-read-collaborative";
+
+type SharedRequest<T, E> = Shared<BoxFuture<'static, Result<T, E>>>;
+
+/// Requests by key, shared by clones to make each request only once
+///
+/// Failed requests are forgotten, to be made again next time
+struct SharedRequests<K, T, E> {
+	requests: Arc<Mutex<HashMap<K, SharedRequest<T, E>>>>,
+}
+
+impl<K: Eq + Hash + Clone, T: Clone, E: Clone> SharedRequests<K, T, E> {
+	fn new() -> SharedRequests<K, T, E> {
+		SharedRequests {
+			requests: Arc::new(Mutex::new(HashMap::new())),
+		}
+	}
+
+	/// Result of the request for key, joining a pending or finished one
+	async fn get(
+		&self,
+		key: K,
+		request: impl FnOnce() -> BoxFuture<'static, Result<T, E>>,
+	) -> Result<T, E> {
+		let shared = self
+			.requests
+			.lock()
+			.unwrap()
+			.entry(key.clone())
+			.or_insert_with(|| request().shared())
+			.clone();
+
+		let result = shared.clone().await;
+		if result.is_err() {
+			let mut requests = self.requests.lock().unwrap();
+			// Another caller may have replaced the failed request already
+			if requests.get(&key).is_some_and(|r| r.ptr_eq(&shared)) {
+				requests.remove(&key);
+			}
+		}
+		result
+	}
+}
+
+impl<K, T, E> Clone for SharedRequests<K, T, E> {
+	fn clone(&self) -> Self {
+		SharedRequests {
+			requests: self.requests.clone(),
+		}
+	}
+}
 
 pub struct Spotify {
 	// librespotify sessopm
 	pub session: Session,
 	pub spotify: Client,
+	/// Client of the other HTTP requests, like lyrics and the library, through the proxy
+	pub(crate) http: reqwest::Client,
 	pub market: Option<Market>,
+	/// Requested audio keys, shared by clones to request each key only once
+	audio_keys: SharedRequests<(SpotifyId, FileId), AudioKey, AudioKeyError>,
+	/// Cache and configuration to connect a new session with, if this one logged in
+	connection: Option<(Cache, SessionConfig)>,
 }
 
 impl Spotify {
-	/// Create new instance
+	/// Create new instance, storing credentials and audio files in the cache
 	pub async fn new(
-		username: &str,
-		password: &str,
+		credentials: Credentials,
 		client_id: &str,
 		client_secret: &str,
 		market_country_code: Option<CountryCode>,
+		proxy: Option<Url>,
+		device_id: Option<String>,
+		cache: Cache,
 	) -> Result<Spotify, SpotifyError> {
+		let http = Spotify::http_client(proxy.as_ref())?;
+		let mut config = SessionConfig {
+			proxy,
+			..Default::default()
+		};
+		if let Some(device_id) = device_id {
+			config.device_id = device_id;
+		}
+
 		// librespot
-		let credentials = Credentials::with_password(username, password);
-		let (session, _) = Session::connect(
-			SessionConfig::default(),
-			credentials,
-			Some(Cache::new(Some(Path::new("credentials_cache")), None, None, None).unwrap()),
-			true,
-		)
-		.await?;
+		// Prefer the reusable credentials stored by a previous login of the same user
+		let cached = cache.credentials().filter(|cached| {
+			credentials.username.is_empty() || cached.username == credentials.username
+		});
+		let session = match cached {
+			Some(cached) => match Spotify::connect(cached, cache.clone(), config.clone()).await {
+				Ok(session) => session,
+				Err(SessionError::AuthenticationError(_)) => {
+					warn!("Cached credentials are stale, logging in with the settings");
+					Spotify::connect(credentials, cache.clone(), config.clone()).await?
+				}
+				Err(e) => return Err(e.into()),
+			},
+			None => Spotify::connect(credentials, cache.clone(), config.clone()).await?,
+		};
+
+		let mut spotify =
+			Spotify::with_session(session, client_id, client_secret, market_country_code);
+		spotify.http = http;
+		spotify.connection = Some((cache, config));
+		Ok(spotify)
+	}
 
+	/// HTTP client connecting through the proxy if there is one
+	fn http_client(proxy: Option<&Url>) -> Result<reqwest::Client, SpotifyError> {
+		let mut builder = reqwest::Client::builder();
+		if let Some(proxy) = proxy {
+			builder = builder.proxy(reqwest::Proxy::all(proxy.as_str())?);
+		}
+		Ok(builder.build()?)
+	}
+
+	/// Connect a new session with the cached credentials, when this one expired
+	///
+	/// Only possible for instances which logged in themselves with Spotify::new
+	pub async fn reconnect(&self) -> Result<Spotify, SpotifyError> {
+		let (cache, config) = self
+			.connection
+			.clone()
+			.ok_or(SpotifyError::AuthenticationError)?;
+		let credentials = cache
+			.credentials()
+			.ok_or(SpotifyError::AuthenticationError)?;
+		let session = Spotify::connect(credentials, cache.clone(), config.clone()).await?;
+		Ok(Spotify {
+			session,
+			spotify: Client::new(self.spotify.credentials.clone()),
+			http: self.http.clone(),
+			market: self.market,
+			audio_keys: self.audio_keys.clone(),
+			connection: Some((cache, config)),
+		})
+	}
+
+	/// Create new instance from an already connected librespot session
+	pub fn with_session(
+		session: Session,
+		client_id: &str,
+		client_secret: &str,
+		market_country_code: Option<CountryCode>,
+	) -> Spotify {
 		//aspotify
 		let credentials = ClientCredentials {
 			id: client_id.to_string(),
@@ -45,18 +188,69 @@ impl Spotify {
 		};
 		let spotify = Client::new(credentials);
 
-		Ok(Spotify {
+		Spotify {
 			session,
 			spotify,
+			http: reqwest::Client::new(),
 			market: market_country_code.map(Market::Country),
-		})
+			audio_keys: SharedRequests::new(),
+			connection: None,
+		}
+	}
+
+	/// Get the key to decrypt a file, joining a pending request for the same file
+	pub async fn audio_key(
+		&self,
+		track: SpotifyId,
+		file: FileId,
+	) -> Result<AudioKey, SpotifyError> {
+		let session = self.session.clone();
+		let key = self
+			.audio_keys
+			.get((track, file), || {
+				async move { session.audio_key().request(track, file).await }.boxed()
+			})
+			.await?;
+		Ok(key)
+	}
+
+	/// librespot cache of credentials and optionally of audio files
+	///
+	/// Files of the audio cache are kept until they exceed the size limit in bytes
+	pub fn cache(
+		cache_dir: &Path,
+		audio_cache_dir: Option<&Path>,
+		audio_cache_limit: Option<u64>,
+	) -> Result<Cache, SpotifyError> {
+		Ok(Cache::new(
+			Some(cache_dir),
+			None,
+			audio_cache_dir,
+			audio_cache_limit,
+		)?)
+	}
+
+	/// Connect librespot session, storing reusable credentials in the cache
+	async fn connect(
+		credentials: Credentials,
+		cache: Cache,
+		config: SessionConfig,
+	) -> Result<Session, SessionError> {
+		let (session, _) = Session::connect(config, credentials, Some(cache), true).await?;
+		Ok(session)
 	}
 
 	/// Parse URI or URL into URI
 	pub fn parse_uri(uri: &str) -> Result<String, SpotifyError> {
+		// Liked songs of the logged in user
+		if matches!(uri, "liked" | "spotify:liked" | "spotify:collection:tracks") {
+			return Ok("spotify:collection:tracks".to_string());
+		}
+
 		// Already URI
 		if uri.starts_with("spotify:") {
-			if uri.split(':').count() < 3 {
+			let parts = uri.split(':').collect::<Vec<&str>>();
+			if parts.len() < 3 || (parts.len() == 3 && !Spotify::is_id(parts[2])) {
 				return Err(SpotifyError::InvalidUri);
 			}
 			return Ok(uri.to_string());
@@ -69,8 +263,14 @@ impl Spotify {
 			let path = url
 				.path_segments()
 				.ok_or_else(|| SpotifyError::Error("Missing URL path".into()))?
+				// Localized URLs start with the locale, e.g. /intl-de/track/<id>
+				.skip_while(|s| s.starts_with("intl-"))
 				.collect::<Vec<&str>>();
-			if path.len() < 2 {
+			if path.len() == 2 && path[0] == "collection" && path[1] == "tracks" {
+				return Ok("spotify:collection:tracks".to_string());
+			}
+			// Query parameters like ?si= are not part of the path
+			if path.len() < 2 || !Spotify::is_id(path[1]) {
 				return Err(SpotifyError::InvalidUri);
 			}
 			return Ok(format!("spotify:{}:{}", path[0], path[1]));
@@ -78,67 +278,290 @@ impl Spotify {
 		Err(SpotifyError::InvalidUri)
 	}
 
+	/// Query of a search URI like spotify:search:ariana+grande or of a search URL
+	pub fn parse_search(input: &str) -> Option<String> {
+		if let Some(query) = input.strip_prefix("spotify:search:") {
+			return Some(Spotify::decode_query(query));
+		}
+		let url = Url::parse(input).ok()?;
+		if url.host_str() != Some("open.spotify.com") {
+			return None;
+		}
+		let mut path = url.path_segments()?.skip_while(|s| s.starts_with("intl-"));
+		match (path.next(), path.next()) {
+			(Some("search"), Some(query)) if !query.is_empty() => {
+				Some(Spotify::decode_query(query))
+			}
+			_ => None,
+		}
+	}
+
+	/// ISRC of an input like isrc:USRC17607839, in upper case and without hyphens
+	pub fn parse_isrc(input: &str) -> Option<String> {
+		let prefix = input.get(..5)?;
+		if !prefix.eq_ignore_ascii_case("isrc:") {
+			return None;
+		}
+		let isrc: String = input[5..]
+			.trim()
+			.chars()
+			.filter(|c| *c != '-')
+			.collect::<String>()
+			.to_uppercase();
+		// Country, registrant, year and designation code
+		let valid = isrc.len() == 12
+			&& isrc.is_ascii()
+			&& isrc[..2].chars().all(|c| c.is_ascii_alphabetic())
+			&& isrc[2..5].chars().all(|c| c.is_ascii_alphanumeric())
+			&& isrc[5..].chars().all(|c| c.is_ascii_digit());
+		valid.then_some(isrc)
+	}
+
+	/// Tracks with the ISRC, the most popular first as re-releases share it
+	pub async fn search_isrc(&self, isrc: &str) -> Result<Vec<Track>, SpotifyError> {
+		let results = self
+			.search(&format!("isrc:{}", isrc), SearchType::Track)
+			.await?;
+		let tracks = results.tracks.map(|t| t.items).unwrap_or_default();
+		Ok(Spotify::filter_isrc(tracks, isrc))
+	}
+
+	/// Search results which really have the ISRC, the most popular first
+	fn filter_isrc(tracks: Vec<Track>, isrc: &str) -> Vec<Track> {
+		let mut tracks: Vec<Track> = tracks
+			.into_iter()
+			.filter(|t| {
+				t.id.is_some()
+					&& t.external_ids
+						.get("isrc")
+						.is_some_and(|i| i.eq_ignore_ascii_case(isrc))
+			})
+			.collect();
+		tracks.sort_by_key(|t| std::cmp::Reverse(t.popularity));
+		tracks
+	}
+
+	/// Decode a percent-encoded search query, with + for spaces
+	fn decode_query(query: &str) -> String {
+		let bytes = query.as_bytes();
+		let mut decoded = Vec::with_capacity(bytes.len());
+		let mut i = 0;
+		while i < bytes.len() {
+			let hex = query.get(i + 1..i + 3);
+			match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+				(b'+', _) => decoded.push(b' '),
+				(b'%', Some(byte)) => {
+					decoded.push(byte);
+					i += 2;
+				}
+				(byte, _) => decoded.push(byte),
+			}
+			i += 1;
+		}
+		String::from_utf8_lossy(&decoded).into_owned()
+	}
+
+	/// Whether the string is a base62 Spotify ID
+	fn is_id(id: &str) -> bool {
+		id.len() == 22 && id.chars().all(|c| c.is_ascii_alphanumeric())
+	}
+
+	/// Parse URI or URL into URI, following the redirect of shortened URLs
+	pub async fn parse_input(&self, input: &str) -> Result<String, SpotifyError> {
+		if !Spotify::is_short_url(input) {
+			return Spotify::parse_uri(input);
+		}
+
+		let res = self.http.get(input).send().await?;
+		Spotify::parse_redirect(res.url())
+	}
+
+	/// Whether the input is a shortened URL, which redirects to the Spotify URL
+	fn is_short_url(input: &str) -> bool {
+		Url::parse(input)
+			.map(|url| matches!(url.host_str(), Some("spotify.link") | Some("spoti.fi")))
+			.unwrap_or(false)
+	}
+
+	/// URI of the URL a shortened URL redirected to, without its query and fragment
+	fn parse_redirect(target: &Url) -> Result<String, SpotifyError> {
+		let mut target = target.clone();
+		target.set_query(None);
+		target.set_fragment(None);
+		Spotify::parse_uri(target.as_str())
+	}
+
 	/// Fetch data for URI
 	pub async fn resolve_uri(&self, uri: &str) -> Result<SpotifyItem, SpotifyError> {
 		let parts = uri.split(':').skip(1).collect::<Vec<&str>>();
 		let id = parts[1];
 		match parts[0] {
-			"track" => {
-				let track = self.spotify.tracks().get_track(id, self.market).await?;
-				Ok(SpotifyItem::Track(track.data))
-			}
+			"track" => Ok(SpotifyItem::Track(self.track(id).await?)),
 			"playlist" => {
-				let playlist = self
-					.spotify
-					.playlists()
-					.get_playlist(id, self.market)
-					.await?;
-				Ok(SpotifyItem::Playlist(playlist.data))
-			}
-			"album" => {
-				let album = self.spotify.albums().get_album(id, self.market).await?;
-				Ok(SpotifyItem::Album(album.data))
+				// Without app credentials the Web API only takes tokens of the user session
+				if !self.has_web_api() {
+					let playlist = self
+						.user_playlist_request(&format!("playlists/{}", id), &[])
+						.await?;
+					return Ok(SpotifyItem::Playlist(playlist));
+				}
+				let playlist = match self.spotify.playlists().get_playlist(id, self.market).await {
+					Ok(playlist) => playlist.data,
+					// Private and collaborative playlists are only visible to users with access
+					Err(e) => {
+						debug!("Fetching playlist {} failed: {}, retrying as user", id, e);
+						self.user_playlist_request(&format!("playlists/{}", id), &[])
+							.await?
+					}
+				};
+				Ok(SpotifyItem::Playlist(playlist))
 			}
+			"album" => Ok(SpotifyItem::Album(self.album(id).await?)),
 			"artist" => {
+				self.require_web_api("Downloading artists")?;
 				let artist = self.spotify.artists().get_artist(id).await?;
 				Ok(SpotifyItem::Artist(artist.data))
 			}
+			"collection" if id == "tracks" => Ok(SpotifyItem::Liked),
 			// Unsupported / Unimplemented
 			_ => Ok(SpotifyItem::Other(uri.to_string())),
 		}
 	}
 
+	/// Whether the client_id and client_secret settings are set, without them metadata of
+	/// tracks and albums comes from librespot
+	pub fn has_web_api(&self) -> bool {
+		!self.spotify.credentials.id.is_empty() && !self.spotify.credentials.secret.is_empty()
+	}
+
+	/// Fail clearly instead of with an authentication error of the Web API
+	fn require_web_api(&self, action: &str) -> Result<(), SpotifyError> {
+		match self.has_web_api() {
+			true => Ok(()),
+			false => Err(SpotifyError::Error(format!(
+				"{} needs the client_id and client_secret settings",
+				action
+			))),
+		}
+	}
+
+	/// Get track, from librespot without Web API credentials
+	pub async fn track(&self, id: &str) -> Result<Track, SpotifyError> {
+		match self.has_web_api() {
+			true => Ok(self.spotify.tracks().get_track(id, self.market).await?.data),
+			false => metadata::track(&self.session, id).await,
+		}
+	}
+
+	/// Get album, from librespot without Web API credentials
+	pub async fn album(&self, id: &str) -> Result<Album, SpotifyError> {
+		match self.has_web_api() {
+			true => Ok(self.spotify.albums().get_album(id, self.market).await?.data),
+			false => metadata::album(&self.session, id).await,
+		}
+	}
+
 	/// Get search results for query
-	pub async fn search(&self, query: &str) -> Result<Vec<Track>, SpotifyError> {
+	pub async fn search(
+		&self,
+		query: &str,
+		search_type: SearchType,
+	) -> Result<SearchResults, SpotifyError> {
+		self.require_web_api("Searching")?;
 		Ok(self
 			.spotify
 			.search()
-			.search(query, [ItemType::Track], true, 50, 0, None)
+			.search(query, [search_type.into()], true, 50, 0, self.market)
 			.await?
-			.data
-			.tracks
-			.unwrap()
-			.items)
+			.data)
 	}
 
 	/// Get all tracks from playlist
 	pub async fn full_playlist(&self, id: &str) -> Result<Vec<Track>, SpotifyError> {
-		let mut items = vec![];
-		let mut offset = 0;
-		loop {
-			let page = self
+		self.playlist_tracks(id, 0, None).await
+	}
+
+	/// Get tracks from playlist, starting at offset and at most limit
+	pub async fn playlist_tracks(
+		&self,
+		id: &str,
+		offset: usize,
+		limit: Option<usize>,
+	) -> Result<Vec<Track>, SpotifyError> {
+		let items = self.playlist_items(id, offset, limit).await?;
+		Ok(items.into_iter().map(|(_, _, t)| t).collect())
+	}
+
+	/// Get tracks from playlist with their position in it from 0 and when they were added,
+	/// starting at offset and at most limit
+	///
+	/// Positions of episodes and removed tracks are left out, very old playlists have no added time
+	pub async fn playlist_items(
+		&self,
+		id: &str,
+		offset: usize,
+		limit: Option<usize>,
+	) -> Result<Vec<(usize, Option<DateTime<Utc>>, Track)>, SpotifyError> {
+		// Without app credentials the Web API only takes tokens of the user session
+		let as_user = AtomicBool::new(!self.has_web_api());
+		let as_user = &as_user;
+		Spotify::playlist_pages(offset, limit, |limit, offset| {
+			self.playlist_page(id, limit, offset, as_user)
+		})
+		.await
+	}
+
+	/// Get a page of playlist items, as user once the app credentials failed
+	async fn playlist_page(
+		&self,
+		id: &str,
+		limit: usize,
+		offset: usize,
+		as_user: &AtomicBool,
+	) -> Result<Page<PlaylistItem>, SpotifyError> {
+		if !as_user.load(Ordering::Relaxed) {
+			match self
 				.spotify
 				.playlists()
-				.get_playlists_items(id, 100, offset, self.market)
-				.await?;
+				.get_playlists_items(id, limit, offset, self.market)
+				.await
+			{
+				Ok(page) => return Ok(page.data),
+				// Private and collaborative playlists are only visible to users with access
+				Err(e) => {
+					debug!("Fetching playlist {} failed: {}, retrying as user", id, e);
+					as_user.store(true, Ordering::Relaxed);
+				}
+			}
+		}
+		let query = [("limit", limit.to_string()), ("offset", offset.to_string())];
+		self.user_playlist_request(&format!("playlists/{}/tracks", id), &query)
+			.await
+	}
+
+	/// Tracks of the pages fetch_page(limit, offset) returns, starting at offset and at most limit
+	async fn playlist_pages<F, Fut>(
+		offset: usize,
+		limit: Option<usize>,
+		mut fetch_page: F,
+	) -> Result<Vec<(usize, Option<DateTime<Utc>>, Track)>, SpotifyError>
+	where
+		F: FnMut(usize, usize) -> Fut,
+		Fut: Future<Output = Result<Page<PlaylistItem>, SpotifyError>>,
+	{
+		let mut items = vec![];
+		let mut offset = offset;
+		let mut remaining = limit.unwrap_or(usize::MAX);
+		while remaining > 0 {
+			let page = fetch_page(remaining.min(100), offset).await?;
 			items.append(
 				&mut page
-					.data
 					.items
 					.iter()
-					.filter_map(|i| -> Option<Track> {
+					.enumerate()
+					.filter_map(|(position, i)| {
 						if let Some(PlaylistItemType::Track(t)) = &i.item {
-							Some(t.to_owned())
+							Some((offset + position, i.added_at, t.to_owned()))
 						} else {
 							None
 						}
@@ -147,54 +570,171 @@ impl Spotify {
 			);
 
 			// End
-			offset += page.data.items.len();
-			if page.data.total == offset {
-				return Ok(items);
+			offset += page.items.len();
+			remaining = remaining.saturating_sub(page.items.len());
+			if page.items.is_empty() || offset >= page.total {
+				break;
 			}
 		}
+		Ok(items)
 	}
 
-	/// Get all tracks from album
-	pub async fn full_album(&self, id: &str) -> Result<Vec<TrackSimplified>, SpotifyError> {
+	/// Get a playlist endpoint of the Web API with a token of the user session
+	///
+	/// Fails with PlaylistNotAccessible if the user can not see the playlist either
+	async fn user_playlist_request<T: DeserializeOwned>(
+		&self,
+		path: &str,
+		query: &[(&str, String)],
+	) -> Result<T, SpotifyError> {
+		let token = keymaster::get_token(&self.session, KEYMASTER_CLIENT_ID, PLAYLIST_SCOPES)
+			.await
+			.map_err(|_| SpotifyError::PlaylistNotAccessible)?;
+		let market = match self.market {
+			Some(Market::Country(country)) => country.alpha2().to_string(),
+			_ => "from_token".to_string(),
+		};
+		let res = self
+			.http
+			.get(format!("https://api.spotify.com/v1/{}", path))
+			.query(query)
+			.query(&[("market", market)])
+			.bearer_auth(&token.access_token)
+			.send()
+			.await?;
+		if matches!(
+			res.status(),
+			StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND
+		) {
+			return Err(SpotifyError::PlaylistNotAccessible);
+		}
+		Ok(serde_json::from_str(
+			&res.error_for_status()?.text().await?,
+		)?)
+	}
+
+	/// Get liked tracks of the logged in user, starting at offset and at most limit
+	pub async fn liked_tracks(
+		&self,
+		offset: usize,
+		limit: Option<usize>,
+	) -> Result<Vec<Track>, SpotifyError> {
+		// The web API client only has app credentials, so use a token of the user session
+		let token =
+			keymaster::get_token(&self.session, KEYMASTER_CLIENT_ID, "user-library-read").await?;
+		let client = &self.http;
 		let mut items = vec![];
-		let mut offset = 0;
-		loop {
+		let mut offset = offset;
+		let mut remaining = limit.unwrap_or(usize::MAX);
+		while remaining > 0 {
+			let res = client
+				.get("https://api.spotify.com/v1/me/tracks")
+				.query(&[
+					("limit", remaining.min(50).to_string()),
+					("offset", offset.to_string()),
+					("market", "from_token".to_string()),
+				])
+				.bearer_auth(&token.access_token)
+				.send()
+				.await?
+				.error_for_status()?;
+			let page: SavedTracks = serde_json::from_str(&res.text().await?)?;
+			items.extend(page.items.iter().map(|i| i.track.clone()));
+
+			// End
+			offset += page.items.len();
+			remaining = remaining.saturating_sub(page.items.len());
+			if page.items.is_empty() || offset >= page.total {
+				break;
+			}
+		}
+		Ok(items)
+	}
+
+	/// Get tracks from album, starting at offset and at most limit
+	pub async fn album_tracks(
+		&self,
+		id: &str,
+		offset: usize,
+		limit: Option<usize>,
+	) -> Result<Vec<TrackSimplified>, SpotifyError> {
+		if !self.has_web_api() {
+			return metadata::album_tracks(&self.session, id, offset, limit).await;
+		}
+		let mut items = vec![];
+		let mut offset = offset;
+		let mut remaining = limit.unwrap_or(usize::MAX);
+		while remaining > 0 {
 			let page = self
 				.spotify
 				.albums()
-				.get_album_tracks(id, 50, offset, self.market)
+				.get_album_tracks(id, remaining.min(50), offset, self.market)
 				.await?;
 			items.append(&mut page.data.items.to_vec());
 
 			// End
 			offset += page.data.items.len();
-			if page.data.total == offset {
-				return Ok(items);
+			remaining = remaining.saturating_sub(page.data.items.len());
+			if page.data.items.is_empty() || offset >= page.data.total {
+				break;
 			}
 		}
+		Ok(items)
 	}
 
-	/// Get all tracks from artist
-	pub async fn full_artist(&self, id: &str) -> Result<Vec<TrackSimplified>, SpotifyError> {
-		let mut items = vec![];
+	/// Get all tracks from album
+	pub async fn full_album(&self, id: &str) -> Result<Vec<TrackSimplified>, SpotifyError> {
+		self.album_tracks(id, 0, None).await
+	}
+
+	/// Get all tracks from artist, fetching at most `concurrency` albums at once
+	pub async fn full_artist(
+		&self,
+		id: &str,
+		groups: &[AlbumGroup],
+		concurrency: usize,
+	) -> Result<Vec<TrackSimplified>, SpotifyError> {
+		let groups: Vec<aspotify::AlbumGroup> = groups.iter().map(|g| (*g).into()).collect();
+		let mut albums = vec![];
 		let mut offset = 0;
 		loop {
 			let page = self
 				.spotify
 				.artists()
-				.get_artist_albums(id, None, 50, offset, self.market)
+				.get_artist_albums(id, Some(&groups), 50, offset, self.market)
 				.await?;
-
-			for album in &mut page.data.items.iter() {
-				items.append(&mut self.full_album(&album.id).await?)
-			}
+			albums.extend(page.data.items.iter().map(|a| a.id.clone()));
 
 			// End
 			offset += page.data.items.len();
-			if page.data.total == offset {
-				return Ok(items);
+			if page.data.items.is_empty() || page.data.total == offset {
+				break;
 			}
 		}
+
+		// Buffered keeps the order of the albums, whichever finishes first
+		let results: Vec<Result<Vec<TrackSimplified>, SpotifyError>> = stream::iter(albums.iter())
+			.map(|album| self.full_album(album))
+			.buffered(concurrency.max(1))
+			.collect()
+			.await;
+
+		// Skip albums which failed, unless all of them did
+		let mut items = vec![];
+		let mut error = None;
+		for (album, result) in albums.iter().zip(results) {
+			match result {
+				Ok(mut tracks) => items.append(&mut tracks),
+				Err(e) => {
+					warn!("Failed fetching album {}: {}", album, e);
+					error = Some(e);
+				}
+			}
+		}
+		match error {
+			Some(e) if items.is_empty() => Err(e),
+			_ => Ok(items),
+		}
 	}
 }
 
@@ -203,7 +743,10 @@ impl Clone for Spotify {
 		Self {
 			session: self.session.clone(),
 			spotify: Client::new(self.spotify.credentials.clone()),
+			http: self.http.clone(),
 			market: self.market,
+			audio_keys: self.audio_keys.clone(),
+			connection: self.connection.clone(),
 		}
 	}
 }
@@ -221,6 +764,387 @@ pub enum SpotifyItem {
 	Album(Album),
 	Playlist(Playlist),
 	Artist(Artist),
+	/// Liked songs of the logged in user
+	Liked,
 	/// Unimplemented
 	Other(String),
 }
+
+impl SpotifyItem {
+	/// Kind of the item, as in its URI
+	pub fn kind(&self) -> &'static str {
+		match self {
+			SpotifyItem::Track(_) => "track",
+			SpotifyItem::Album(_) => "album",
+			SpotifyItem::Playlist(_) => "playlist",
+			SpotifyItem::Artist(_) => "artist",
+			SpotifyItem::Liked => "liked songs",
+			SpotifyItem::Other(_) => "other",
+		}
+	}
+
+	/// Amount of tracks if known without further requests
+	pub fn track_count(&self) -> Option<usize> {
+		match self {
+			SpotifyItem::Track(_) => Some(1),
+			SpotifyItem::Album(a) => Some(a.tracks.total),
+			SpotifyItem::Playlist(p) => Some(p.tracks.total),
+			SpotifyItem::Artist(_) | SpotifyItem::Liked | SpotifyItem::Other(_) => None,
+		}
+	}
+}
+
+/// Summary like `album Sweetener by Ariana Grande (15 tracks)`
+impl fmt::Display for SpotifyItem {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let names = |artists: &[aspotify::ArtistSimplified]| {
+			artists
+				.iter()
+				.map(|a| a.name.as_str())
+				.collect::<Vec<&str>>()
+				.join(", ")
+		};
+		match self {
+			SpotifyItem::Track(t) => write!(f, "track {} by {}", t.name, names(&t.artists))?,
+			SpotifyItem::Album(a) => write!(f, "album {} by {}", a.name, names(&a.artists))?,
+			SpotifyItem::Playlist(p) => write!(f, "playlist {}", p.name)?,
+			SpotifyItem::Artist(a) => write!(f, "artist {}", a.name)?,
+			SpotifyItem::Liked => write!(f, "liked songs")?,
+			SpotifyItem::Other(uri) => write!(f, "unsupported {}", uri)?,
+		}
+		match self.track_count() {
+			Some(count) if !matches!(self, SpotifyItem::Track(_)) => {
+				write!(f, " ({} tracks)", count)
+			}
+			_ => Ok(()),
+		}
+	}
+}
+
+/// Page of the user's saved tracks
+#[derive(Debug, Clone, Deserialize)]
+struct SavedTracks {
+	items: Vec<SavedTrack>,
+	total: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SavedTrack {
+	track: Track,
+}
+
+/// Kind of item to search for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SearchType {
+	Track,
+	Album,
+	Playlist,
+}
+
+/// Kind of release of an artist
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum AlbumGroup {
+	Album,
+	Single,
+	AppearsOn,
+	Compilation,
+}
+
+impl From<AlbumGroup> for aspotify::AlbumGroup {
+	fn from(group: AlbumGroup) -> Self {
+		match group {
+			AlbumGroup::Album => aspotify::AlbumGroup::Album,
+			AlbumGroup::Single => aspotify::AlbumGroup::Single,
+			AlbumGroup::AppearsOn => aspotify::AlbumGroup::AppearsOn,
+			AlbumGroup::Compilation => aspotify::AlbumGroup::Compilation,
+		}
+	}
+}
+
+impl From<SearchType> for ItemType {
+	fn from(search_type: SearchType) -> Self {
+		match search_type {
+			SearchType::Track => ItemType::Track,
+			SearchType::Album => ItemType::Album,
+			SearchType::Playlist => ItemType::Playlist,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	/// Request counting its calls, failing the first fail times
+	fn request(
+		calls: &Arc<AtomicUsize>,
+		fail: usize,
+	) -> impl FnOnce() -> BoxFuture<'static, Result<usize, ()>> {
+		let calls = calls.clone();
+		move || {
+			async move {
+				let call = calls.fetch_add(1, Ordering::SeqCst);
+				tokio::task::yield_now().await;
+				if call < fail {
+					Err(())
+				} else {
+					Ok(call)
+				}
+			}
+			.boxed()
+		}
+	}
+
+	#[tokio::test]
+	async fn shared_requests_join_pending_request() {
+		let requests = SharedRequests::new();
+		let calls = Arc::new(AtomicUsize::new(0));
+		let clone = requests.clone();
+		let (first, second) = futures::join!(
+			requests.get("key", request(&calls, 0)),
+			clone.get("key", request(&calls, 0)),
+		);
+		assert_eq!((first, second), (Ok(0), Ok(0)));
+		assert_eq!(requests.get("key", request(&calls, 0)).await, Ok(0));
+		assert_eq!(calls.load(Ordering::SeqCst), 1);
+	}
+
+	#[tokio::test]
+	async fn shared_requests_forget_failed_request() {
+		let requests = SharedRequests::new();
+		let calls = Arc::new(AtomicUsize::new(0));
+		assert_eq!(requests.get("key", request(&calls, 1)).await, Err(()));
+		assert_eq!(requests.get("key", request(&calls, 1)).await, Ok(1));
+		assert_eq!(calls.load(Ordering::SeqCst), 2);
+	}
+
+	/// Web API track with the fields the tests look at
+	fn web_track(id: &str, isrc: &str, popularity: u32) -> Track {
+		Track {
+			album: aspotify::AlbumSimplified {
+				album_type: None,
+				artists: vec![],
+				available_markets: None,
+				external_urls: HashMap::new(),
+				id: None,
+				images: vec![],
+				name: "Album".to_string(),
+				release_date: None,
+				release_date_precision: None,
+				restrictions: None,
+				item_type: aspotify::TypeAlbum,
+			},
+			artists: vec![],
+			available_markets: None,
+			disc_number: 1,
+			duration: std::time::Duration::from_secs(180),
+			explicit: false,
+			external_ids: HashMap::from([("isrc".to_string(), isrc.to_string())]),
+			external_urls: HashMap::new(),
+			id: (!id.is_empty()).then(|| id.to_string()),
+			is_playable: None,
+			linked_from: None,
+			restrictions: None,
+			name: format!("Track {}", id),
+			popularity,
+			preview_url: None,
+			track_number: 1,
+			item_type: aspotify::TypeTrack,
+			is_local: false,
+		}
+	}
+
+	/// Page of a playlist with total tracks, of which limit are returned from offset
+	fn playlist_page(total: usize, limit: usize, offset: usize) -> Page<PlaylistItem> {
+		let end = total.min(offset + limit);
+		Page {
+			items: (offset..end)
+				.map(|i| PlaylistItem {
+					added_at: None,
+					added_by: None,
+					is_local: false,
+					item: Some(PlaylistItemType::Track(web_track(&i.to_string(), "", 0))),
+				})
+				.collect(),
+			limit,
+			offset,
+			total,
+		}
+	}
+
+	/// Collect playlist pages from a playlist of total tracks, with the requested limits and offsets
+	async fn collect_pages(
+		total: usize,
+		offset: usize,
+		limit: Option<usize>,
+	) -> (Vec<usize>, Vec<(usize, usize)>) {
+		let requests = Mutex::new(vec![]);
+		let items = Spotify::playlist_pages(offset, limit, |limit, offset| {
+			requests.lock().unwrap().push((limit, offset));
+			async move { Ok(playlist_page(total, limit, offset)) }
+		})
+		.await
+		.unwrap();
+		for (position, _, track) in &items {
+			assert_eq!(track.id, Some(position.to_string()));
+		}
+		let positions = items.iter().map(|(position, _, _)| *position).collect();
+		(positions, requests.into_inner().unwrap())
+	}
+
+	#[tokio::test]
+	async fn playlist_pages_requests_every_page() {
+		let (positions, requests) = collect_pages(250, 0, None).await;
+		assert_eq!(positions, (0..250).collect::<Vec<_>>());
+		assert_eq!(requests, vec![(100, 0), (100, 100), (100, 200)]);
+
+		// The limit shrinks the last page, the offset moves all of them
+		let (positions, requests) = collect_pages(250, 20, Some(150)).await;
+		assert_eq!(positions, (20..170).collect::<Vec<_>>());
+		assert_eq!(requests, vec![(100, 20), (50, 120)]);
+
+		let (positions, requests) = collect_pages(250, 240, None).await;
+		assert_eq!(positions, (240..250).collect::<Vec<_>>());
+		assert_eq!(requests, vec![(100, 240)]);
+	}
+
+	#[test]
+	fn decode_query_cases() {
+		assert_eq!(Spotify::decode_query("ariana+grande"), "ariana grande");
+		assert_eq!(Spotify::decode_query("ariana%20grande"), "ariana grande");
+		assert_eq!(Spotify::decode_query("beyonc%C3%A9"), "beyoncé");
+		assert_eq!(Spotify::decode_query("ac%2Bdc"), "ac+dc");
+		// Invalid escapes are kept as they are
+		assert_eq!(Spotify::decode_query("100%"), "100%");
+		assert_eq!(Spotify::decode_query("50%zz"), "50%zz");
+	}
+
+	#[test]
+	fn parse_search_cases() {
+		let cases = [
+			("spotify:search:ariana+grande", Some("ariana grande")),
+			(
+				"https://open.spotify.com/search/ariana%20grande",
+				Some("ariana grande"),
+			),
+			(
+				"https://open.spotify.com/intl-de/search/7%20rings",
+				Some("7 rings"),
+			),
+			("https://open.spotify.com/search/", None),
+			("https://example.com/search/ariana", None),
+			(
+				"https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC",
+				None,
+			),
+			("ariana grande", None),
+		];
+		for (input, query) in cases {
+			assert_eq!(Spotify::parse_search(input).as_deref(), query, "{}", input);
+		}
+	}
+
+	#[test]
+	fn parse_isrc_cases() {
+		let cases = [
+			("isrc:USRC17607839", Some("USRC17607839")),
+			("ISRC:us-rc1-76-07839", Some("USRC17607839")),
+			("isrc: GBAYE0601498 ", Some("GBAYE0601498")),
+			// Too short, digits as country and letters in the designation code
+			("isrc:USRC1760783", None),
+			("isrc:12RC17607839", None),
+			("isrc:USRC1760783X", None),
+			("USRC17607839", None),
+			("isrc", None),
+		];
+		for (input, isrc) in cases {
+			assert_eq!(Spotify::parse_isrc(input).as_deref(), isrc, "{}", input);
+		}
+	}
+
+	#[test]
+	fn filter_isrc_keeps_matches_by_popularity() {
+		let tracks = vec![
+			web_track("original", "USRC17607839", 40),
+			web_track("other", "GBAYE0601498", 90),
+			web_track("remaster", "usrc17607839", 70),
+			// Unavailable tracks have no id
+			web_track("", "USRC17607839", 100),
+		];
+		let ids: Vec<Option<String>> = Spotify::filter_isrc(tracks, "USRC17607839")
+			.into_iter()
+			.map(|t| t.id)
+			.collect();
+		assert_eq!(
+			ids,
+			vec![Some("remaster".to_string()), Some("original".to_string())]
+		);
+	}
+
+	#[test]
+	fn parse_uri_cases() {
+		let cases = [
+			(
+				"https://open.spotify.com/intl-pt/track/4uLU6hMCjMI75M1A2tKUQC",
+				Some("spotify:track:4uLU6hMCjMI75M1A2tKUQC"),
+			),
+			(
+				"https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC?si=1a2b3c4d5e6f7a8b",
+				Some("spotify:track:4uLU6hMCjMI75M1A2tKUQC"),
+			),
+			(
+				"https://open.spotify.com/album/4aawyAB9vmqN3uQ7FjRGTy",
+				Some("spotify:album:4aawyAB9vmqN3uQ7FjRGTy"),
+			),
+			(
+				"spotify:playlist:37i9dQZF1DXcBWIGoYBM5M",
+				Some("spotify:playlist:37i9dQZF1DXcBWIGoYBM5M"),
+			),
+			("liked", Some("spotify:collection:tracks")),
+			(
+				"https://open.spotify.com/intl-de/collection/tracks",
+				Some("spotify:collection:tracks"),
+			),
+			// One character short and one too long
+			("https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQ", None),
+			(
+				"https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQCX",
+				None,
+			),
+			("spotify:track:4uLU6hMCjMI75M1A2tKUQ", None),
+			("https://example.com/track/4uLU6hMCjMI75M1A2tKUQC", None),
+		];
+		for (input, uri) in cases {
+			assert_eq!(Spotify::parse_uri(input).ok().as_deref(), uri, "{}", input);
+		}
+	}
+
+	#[test]
+	fn short_urls() {
+		assert!(Spotify::is_short_url("https://spotify.link/ZAbcD2eFgHb"));
+		assert!(Spotify::is_short_url("https://spoti.fi/3xYz"));
+		assert!(!Spotify::is_short_url(
+			"https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC"
+		));
+		assert!(!Spotify::is_short_url(
+			"spotify:track:4uLU6hMCjMI75M1A2tKUQC"
+		));
+	}
+
+	#[test]
+	fn parse_redirect_of_shared_link() {
+		let target = Url::parse(
+			"https://open.spotify.com/intl-fr/track/4uLU6hMCjMI75M1A2tKUQC\
+			?si=8f1c2d3e4b5a6978&nd=1&dlsi=0a1b2c3d4e5f4a6b#details",
+		)
+		.unwrap();
+		assert_eq!(
+			Spotify::parse_redirect(&target).unwrap(),
+			"spotify:track:4uLU6hMCjMI75M1A2tKUQC"
+		);
+
+		let target = Url::parse("https://www.spotify.com/us/").unwrap();
+		assert!(Spotify::parse_redirect(&target).is_err());
+	}
+}
@@ -0,0 +1,67 @@
+use metaflac::block::PictureType;
+use std::path::{Path, PathBuf};
+
+use super::{Field, ReleaseDate};
+use crate::error::SpotifyError;
+
+pub struct FlacTag {
+	path: PathBuf,
+	tag: metaflac::Tag,
+}
+
+impl FlacTag {
+	/// Load tag from file
+	pub fn open(path: impl AsRef<Path>) -> Result<FlacTag, SpotifyError> {
+		let tag = metaflac::Tag::read_from_path(&path)?;
+		Ok(FlacTag {
+			path: path.as_ref().to_owned(),
+			tag,
+		})
+	}
+}
+
+impl super::Tag for FlacTag {
+	fn set_separator(&mut self, _separator: &str) {}
+
+	fn set_field(&mut self, field: Field, value: Vec<String>) {
+		let tag = match field {
+			Field::Title => "TITLE",
+			Field::Artist => "ARTIST",
+			Field::Album => "ALBUM",
+			Field::TrackNumber => "TRACKNUMBER",
+			Field::DiscNumber => "DISCNUMBER",
+			Field::Genre => "GENRE",
+			Field::Label => "LABEL",
+			Field::AlbumArtist => "ALBUMARTIST",
+			Field::Isrc => "ISRC",
+		};
+		self.set_raw(tag, value);
+	}
+
+	fn add_cover(&mut self, mime: &str, data: Vec<u8>) {
+		self.tag.add_picture(mime, PictureType::CoverFront, data);
+	}
+
+	fn set_raw(&mut self, tag: &str, value: Vec<String>) {
+		self.tag.set_vorbis(tag, value);
+	}
+
+	fn save(&mut self) -> Result<(), SpotifyError> {
+		Ok(self.tag.write_to_path(&self.path)?)
+	}
+
+	fn set_release_date(&mut self, date: ReleaseDate) {
+		self.tag.set_vorbis("DATE", vec![date.to_iso()])
+	}
+
+	fn add_unique_file_identifier(&mut self, track_id: &str) {
+		self.tag.set_vorbis("SPOTIFY_TRACKID", vec![track_id]);
+	}
+
+	fn get_unique_file_identifier(&self) -> Option<String> {
+		self.tag
+			.get_vorbis("SPOTIFY_TRACKID")
+			.and_then(|mut values| values.next())
+			.map(String::from)
+	}
+}
@@ -1,11 +1,10 @@
-use chrono::{Datelike, NaiveDate};
-use id3::frame::{Picture, PictureType, Timestamp, UniqueFileIdentifier};
+use id3::frame::{Content, ExtendedText, Picture, PictureType, Timestamp, UniqueFileIdentifier};
 use id3::{Tag, TagLike, Version};
 use std::path::{Path, PathBuf};
 
 use crate::error::SpotifyError;
 
-use super::Field;
+use super::{Field, ReleaseDate};
 
 pub struct ID3Tag {
 	path: PathBuf,
@@ -55,6 +54,7 @@ impl super::Tag for ID3Tag {
 			Field::Genre => "TCON",
 			Field::Label => "TPUB",
 			Field::AlbumArtist => "TPE2",
+			Field::Isrc => "TSRC",
 		};
 		self.set_raw(tag, value);
 	}
@@ -72,11 +72,12 @@ impl super::Tag for ID3Tag {
 		});
 	}
 
-	fn set_release_date(&mut self, date: NaiveDate) {
+	fn set_release_date(&mut self, date: ReleaseDate) {
+		let (year, month, day) = date.parts();
 		self.tag.set_date_released(Timestamp {
-			year: date.year(),
-			month: Some(date.month() as u8),
-			day: Some(date.day() as u8),
+			year,
+			month: month.map(|m| m as u8),
+			day: day.map(|d| d as u8),
 			hour: None,
 			minute: None,
 			second: None,
@@ -88,5 +89,19 @@ impl super::Tag for ID3Tag {
 			owner_identifier: "spotify.com".to_string(),
 			identifier: track_id.into(),
 		});
+		// Same name as the Vorbis comment, as few players show the UFID
+		self.tag.add_frame(ExtendedText {
+			description: "SPOTIFY_TRACKID".to_string(),
+			value: track_id.to_string(),
+		});
+	}
+
+	fn get_unique_file_identifier(&self) -> Option<String> {
+		self.tag.frames().find_map(|frame| match frame.content() {
+			Content::UniqueFileIdentifier(ufid) if ufid.owner_identifier == "spotify.com" => {
+				Some(String::from_utf8_lossy(&ufid.identifier).into_owned())
+			}
+			_ => None,
+		})
 	}
 }
@@ -1,4 +1,5 @@
-use chrono::NaiveDate;
+use aspotify::DatePrecision;
+use chrono::{Datelike, NaiveDate};
 
 use std::path::Path;
 
@@ -6,14 +7,20 @@ use crate::downloader::AudioFormat;
 use crate::error::SpotifyError;
 
 use self::id3::ID3Tag;
+use flac::FlacTag;
 use ogg::OggTag;
+use opus::OpusTag;
 
+mod flac;
 mod id3;
 mod ogg;
+mod opus;
 
-pub enum TagWrap {
+pub(crate) enum TagWrap {
 	Ogg(OggTag),
 	Id3(ID3Tag),
+	Flac(FlacTag),
+	Opus(OpusTag),
 }
 
 impl TagWrap {
@@ -22,6 +29,8 @@ impl TagWrap {
 		match format {
 			AudioFormat::Ogg => Ok(TagWrap::Ogg(OggTag::open(path)?)),
 			AudioFormat::Mp3 => Ok(TagWrap::Id3(ID3Tag::open(path)?)),
+			AudioFormat::Flac => Ok(TagWrap::Flac(FlacTag::open(path)?)),
+			AudioFormat::Opus => Ok(TagWrap::Opus(OpusTag::open(path)?)),
 			_ => Err(SpotifyError::Error("Invalid format!".into())),
 		}
 	}
@@ -31,24 +40,64 @@ impl TagWrap {
 		match self {
 			TagWrap::Ogg(tag) => tag,
 			TagWrap::Id3(tag) => tag,
+			TagWrap::Flac(tag) => tag,
+			TagWrap::Opus(tag) => tag,
 		}
 	}
 }
 
-pub trait Tag {
+pub(crate) trait Tag {
 	/// Set tag values separator
 	fn set_separator(&mut self, separator: &str);
 	fn set_raw(&mut self, tag: &str, value: Vec<String>);
 	fn set_field(&mut self, field: Field, value: Vec<String>);
-	fn set_release_date(&mut self, date: NaiveDate);
+	fn set_release_date(&mut self, date: ReleaseDate);
 	fn add_cover(&mut self, mime: &str, data: Vec<u8>);
 	/// Adds the file identifier of the track
 	fn add_unique_file_identifier(&mut self, track_id: &str);
+	/// Spotify track id written by add_unique_file_identifier
+	fn get_unique_file_identifier(&self) -> Option<String>;
 	fn save(&mut self) -> Result<(), SpotifyError>;
 }
 
+/// Release date, only as precise as Spotify knows it
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ReleaseDate {
+	Year(i32),
+	Month(i32, u32),
+	Day(NaiveDate),
+}
+
+impl ReleaseDate {
+	pub fn new(date: NaiveDate, precision: &DatePrecision) -> ReleaseDate {
+		match precision {
+			DatePrecision::Year => ReleaseDate::Year(date.year()),
+			DatePrecision::Month => ReleaseDate::Month(date.year(), date.month()),
+			DatePrecision::Day => ReleaseDate::Day(date),
+		}
+	}
+
+	/// Year, month and day if known
+	pub fn parts(&self) -> (i32, Option<u32>, Option<u32>) {
+		match self {
+			ReleaseDate::Year(year) => (*year, None, None),
+			ReleaseDate::Month(year, month) => (*year, Some(*month), None),
+			ReleaseDate::Day(date) => (date.year(), Some(date.month()), Some(date.day())),
+		}
+	}
+
+	/// ISO 8601 date like 2016, 2016-05 or 2016-05-20, as used by Vorbis comments
+	pub fn to_iso(self) -> String {
+		match self.parts() {
+			(year, None, _) => year.to_string(),
+			(year, Some(month), None) => format!("{}-{:02}", year, month),
+			(year, Some(month), Some(day)) => format!("{}-{:02}-{:02}", year, month, day),
+		}
+	}
+}
+
 #[derive(Debug, Clone)]
-pub enum Field {
+pub(crate) enum Field {
 	Title,
 	Artist,
 	Album,
@@ -57,4 +106,5 @@ pub enum Field {
 	AlbumArtist,
 	Genre,
 	Label,
+	Isrc,
 }
@@ -1,9 +1,8 @@
-use chrono::{Datelike, NaiveDate};
 use oggvorbismeta::{read_comment_header, replace_comment_header, CommentHeader, VorbisComments};
 use std::fs::File;
 use std::path::{Path, PathBuf};
 
-use super::Field;
+use super::{Field, ReleaseDate};
 use crate::error::SpotifyError;
 
 pub struct OggTag {
@@ -36,6 +35,7 @@ impl super::Tag for OggTag {
 			Field::Genre => "GENRE",
 			Field::Label => "LABEL",
 			Field::AlbumArtist => "ALBUMARTIST",
+			Field::Isrc => "ISRC",
 		};
 		self.set_raw(tag, value);
 	}
@@ -45,7 +45,7 @@ impl super::Tag for OggTag {
 
 		// MIME type
 		picture.extend(3u32.to_be_bytes().iter());
-		picture.extend((mime.as_bytes().len() as u32).to_be_bytes().iter());
+		picture.extend((mime.len() as u32).to_be_bytes().iter());
 		picture.extend(mime.as_bytes());
 
 		// Description
@@ -80,14 +80,15 @@ impl super::Tag for OggTag {
 		Ok(())
 	}
 
-	fn set_release_date(&mut self, date: NaiveDate) {
-		self.tag.add_tag_single(
-			"DATE",
-			&format!("{}-{:02}-{:02}", date.year(), date.month(), date.day()),
-		)
+	fn set_release_date(&mut self, date: ReleaseDate) {
+		self.tag.add_tag_single("DATE", &date.to_iso())
 	}
 
 	fn add_unique_file_identifier(&mut self, track_id: &str) {
 		self.tag.add_tag_single("SPOTIFY_TRACKID", track_id);
 	}
+
+	fn get_unique_file_identifier(&self) -> Option<String> {
+		self.tag.get_tag_single("SPOTIFY_TRACKID")
+	}
 }
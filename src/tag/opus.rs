@@ -0,0 +1,315 @@
+use std::path::{Path, PathBuf};
+
+use super::{Field, ReleaseDate};
+use crate::converter::{self, OGG_CONTINUED};
+use crate::error::SpotifyError;
+
+/// Vorbis comments in the OpusTags header of an Ogg Opus file
+///
+/// The file is rewritten on save, as the header can change in size
+pub struct OpusTag {
+	path: PathBuf,
+	vendor: String,
+	comments: Vec<(String, String)>,
+}
+
+/// Ogg page read from a file
+struct Page {
+	flags: u8,
+	granule: u64,
+	serial: u32,
+	lacing: Vec<u8>,
+	data: Vec<u8>,
+}
+
+impl OpusTag {
+	/// Load tag from file
+	pub fn open(path: impl AsRef<Path>) -> Result<OpusTag, SpotifyError> {
+		let pages = read_pages(&std::fs::read(&path)?)?;
+		let (tags, _) = tags_packet(&pages)?;
+		let (vendor, comments) = parse_tags(&tags).ok_or(SpotifyError::InvalidFormat)?;
+		Ok(OpusTag {
+			path: path.as_ref().to_owned(),
+			vendor,
+			comments,
+		})
+	}
+
+	fn add(&mut self, tag: &str, value: &str) {
+		self.comments.push((tag.to_string(), value.to_string()));
+	}
+
+	fn get(&self, tag: &str) -> Option<String> {
+		self.comments
+			.iter()
+			.find(|(t, _)| t.eq_ignore_ascii_case(tag))
+			.map(|(_, v)| v.to_owned())
+	}
+}
+
+impl super::Tag for OpusTag {
+	fn set_separator(&mut self, _separator: &str) {}
+
+	fn set_field(&mut self, field: Field, value: Vec<String>) {
+		let tag = match field {
+			Field::Title => "TITLE",
+			Field::Artist => "ARTIST",
+			Field::Album => "ALBUM",
+			Field::TrackNumber => "TRACKNUMBER",
+			Field::DiscNumber => "DISCNUMBER",
+			Field::Genre => "GENRE",
+			Field::Label => "LABEL",
+			Field::AlbumArtist => "ALBUMARTIST",
+			Field::Isrc => "ISRC",
+		};
+		self.set_raw(tag, value);
+	}
+
+	fn add_cover(&mut self, mime: &str, data: Vec<u8>) {
+		let mut picture: Vec<u8> = Vec::new();
+
+		// Front cover and MIME type
+		picture.extend(3u32.to_be_bytes().iter());
+		picture.extend((mime.len() as u32).to_be_bytes().iter());
+		picture.extend(mime.as_bytes());
+
+		// Description
+		picture.extend(0u32.to_be_bytes().iter());
+
+		// Width, height, depth, and number of colors
+		picture.extend([0u8; 16].iter());
+
+		// Image data
+		picture.extend((data.len() as u32).to_be_bytes().iter());
+		picture.extend(data);
+
+		self.add("METADATA_BLOCK_PICTURE", &base64::encode(picture));
+	}
+
+	fn set_raw(&mut self, tag: &str, value: Vec<String>) {
+		for v in value {
+			self.add(tag, &v);
+		}
+	}
+
+	fn save(&mut self) -> Result<(), SpotifyError> {
+		let pages = read_pages(&std::fs::read(&self.path)?)?;
+		let (_, audio) = tags_packet(&pages)?;
+		let comments: Vec<String> = self
+			.comments
+			.iter()
+			.map(|(t, v)| format!("{}={}", t, v))
+			.collect();
+
+		// Identification header, the new comment header and the renumbered audio pages
+		let head = &pages[0];
+		let mut out = converter::ogg_page(
+			head.flags,
+			head.granule,
+			head.serial,
+			0,
+			&head.lacing,
+			&head.data,
+		);
+		let mut sequence = 1;
+		out.extend(converter::ogg_packet_pages(
+			0,
+			0,
+			head.serial,
+			&mut sequence,
+			&converter::opus_tags(&self.vendor, &comments),
+		));
+		for page in &pages[audio..] {
+			out.extend(converter::ogg_page(
+				page.flags,
+				page.granule,
+				page.serial,
+				sequence,
+				&page.lacing,
+				&page.data,
+			));
+			sequence += 1;
+		}
+		std::fs::write(&self.path, out)?;
+		Ok(())
+	}
+
+	fn set_release_date(&mut self, date: ReleaseDate) {
+		self.add("DATE", &date.to_iso())
+	}
+
+	fn add_unique_file_identifier(&mut self, track_id: &str) {
+		self.add("SPOTIFY_TRACKID", track_id);
+	}
+
+	fn get_unique_file_identifier(&self) -> Option<String> {
+		self.get("SPOTIFY_TRACKID")
+	}
+}
+
+/// Split an Ogg file into its pages
+fn read_pages(data: &[u8]) -> Result<Vec<Page>, SpotifyError> {
+	let mut pages = vec![];
+	let mut position = 0;
+	while position < data.len() {
+		let header = data
+			.get(position..position + 27)
+			.filter(|h| h.starts_with(b"OggS"))
+			.ok_or(SpotifyError::InvalidFormat)?;
+		let lacing_start = position + 27;
+		let data_start = lacing_start + header[26] as usize;
+		let lacing = data
+			.get(lacing_start..data_start)
+			.ok_or(SpotifyError::InvalidFormat)?;
+		let size: usize = lacing.iter().map(|l| *l as usize).sum();
+		let page_data = data
+			.get(data_start..data_start + size)
+			.ok_or(SpotifyError::InvalidFormat)?;
+		pages.push(Page {
+			flags: header[5],
+			granule: u64::from_le_bytes(header[6..14].try_into().unwrap()),
+			serial: u32::from_le_bytes(header[14..18].try_into().unwrap()),
+			lacing: lacing.to_vec(),
+			data: page_data.to_vec(),
+		});
+		position = data_start + size;
+	}
+	Ok(pages)
+}
+
+/// OpusTags packet following the identification header, with the index of the first audio page
+///
+/// The comment header is alone on its pages and the audio starts on a new page
+fn tags_packet(pages: &[Page]) -> Result<(Vec<u8>, usize), SpotifyError> {
+	if !pages
+		.first()
+		.is_some_and(|p| p.data.starts_with(b"OpusHead"))
+	{
+		return Err(SpotifyError::InvalidFormat);
+	}
+	let mut packet = vec![];
+	for (i, page) in pages.iter().enumerate().skip(1) {
+		if i > 1 && page.flags & OGG_CONTINUED == 0 {
+			break;
+		}
+		packet.extend_from_slice(&page.data);
+		// Packet ends on this page
+		if page.lacing.last().is_some_and(|l| *l < 255) {
+			return Ok((packet, i + 1));
+		}
+	}
+	Err(SpotifyError::InvalidFormat)
+}
+
+/// Vendor and comments of an OpusTags packet
+fn parse_tags(packet: &[u8]) -> Option<(String, Vec<(String, String)>)> {
+	fn read_u32(data: &mut &[u8]) -> Option<usize> {
+		let value = u32::from_le_bytes(data.get(..4)?.try_into().ok()?);
+		*data = &data[4..];
+		Some(value as usize)
+	}
+	fn read_string(data: &mut &[u8]) -> Option<String> {
+		let size = read_u32(data)?;
+		let value = String::from_utf8_lossy(data.get(..size)?).into_owned();
+		*data = &data[size..];
+		Some(value)
+	}
+
+	let mut data = packet.strip_prefix(b"OpusTags")?;
+	let vendor = read_string(&mut data)?;
+	let count = read_u32(&mut data)?;
+	let mut comments = vec![];
+	for _ in 0..count {
+		let comment = read_string(&mut data)?;
+		if let Some((tag, value)) = comment.split_once('=') {
+			comments.push((tag.to_string(), value.to_string()));
+		}
+	}
+	Some((vendor, comments))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tag::Tag;
+
+	/// Half a second of a stereo tone encoded by the converter, with its Opus pages
+	const STEREO_OPUS: &[u8] = include_bytes!("../../tests/fixtures/stereo.opus");
+
+	/// Pages of an Ogg file as they are, with their sequence number and whether their CRC is valid
+	fn raw_pages(data: &[u8]) -> Vec<(u32, bool)> {
+		let mut pages = vec![];
+		let mut position = 0;
+		while position < data.len() {
+			let segments = data[position + 26] as usize;
+			let size: usize = data[position + 27..position + 27 + segments]
+				.iter()
+				.map(|l| *l as usize)
+				.sum();
+			let mut page = data[position..position + 27 + segments + size].to_vec();
+			let crc = u32::from_le_bytes(page[22..26].try_into().unwrap());
+			page[22..26].fill(0);
+			let sequence = u32::from_le_bytes(page[18..22].try_into().unwrap());
+			pages.push((sequence, converter::ogg_crc(&page) == crc));
+			position += page.len();
+		}
+		pages
+	}
+
+	#[test]
+	fn save_rewrites_comment_header() {
+		let path = std::env::temp_dir().join(format!("down_on_spot_{}.opus", std::process::id()));
+		std::fs::write(&path, STEREO_OPUS).unwrap();
+		let original = read_pages(STEREO_OPUS).unwrap();
+		let (_, audio) = tags_packet(&original).unwrap();
+
+		let mut tag = OpusTag::open(&path).unwrap();
+		tag.set_field(Field::Title, vec!["7 rings".to_string()]);
+		tag.set_field(
+			Field::Artist,
+			vec!["Ariana Grande".to_string(), "Guest".to_string()],
+		);
+		tag.add_unique_file_identifier("6ocbgoVGwYJhOv1GgI9NsF");
+		// Large enough for the comment header to span several pages
+		let cover = vec![0xab; 100_000];
+		tag.add_cover("image/jpeg", cover.clone());
+		let vendor = tag.vendor.clone();
+		tag.save().unwrap();
+
+		let tag = OpusTag::open(&path).unwrap();
+		assert_eq!(tag.vendor, vendor);
+		assert_eq!(tag.get("title").as_deref(), Some("7 rings"));
+		let artists: Vec<&str> = tag
+			.comments
+			.iter()
+			.filter(|(t, _)| t == "ARTIST")
+			.map(|(_, v)| v.as_str())
+			.collect();
+		assert_eq!(artists, vec!["Ariana Grande", "Guest"]);
+		assert_eq!(
+			tag.get_unique_file_identifier().as_deref(),
+			Some("6ocbgoVGwYJhOv1GgI9NsF")
+		);
+		let picture = base64::decode(tag.get("METADATA_BLOCK_PICTURE").unwrap()).unwrap();
+		assert!(picture.ends_with(&cover));
+
+		// The audio pages follow unchanged, and every page is numbered in order with a valid CRC
+		let data = std::fs::read(&path).unwrap();
+		let pages = read_pages(&data).unwrap();
+		let (_, rewritten_audio) = tags_packet(&pages).unwrap();
+		assert!(rewritten_audio > 2);
+		assert_eq!(pages.len() - rewritten_audio, original.len() - audio);
+		for (page, original) in pages[rewritten_audio..].iter().zip(&original[audio..]) {
+			assert_eq!(page.data, original.data);
+			assert_eq!(page.granule, original.granule);
+		}
+		let raw = raw_pages(&data);
+		assert_eq!(
+			raw,
+			(0..pages.len() as u32)
+				.map(|i| (i, true))
+				.collect::<Vec<_>>()
+		);
+		std::fs::remove_file(&path).unwrap();
+	}
+}